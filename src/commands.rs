@@ -59,7 +59,7 @@ pub const COMMANDS: &[(&str, CmdType)] = &[
   ("compile_generated_files", Outer),
   ("consider", Inner),
   ("consts", OuterNamed),
-  ("context", Outer),
+  ("context", OuterNamed),
   ("copy_bnf", Outer),
   ("corollary", OuterNamed),
   ("datatype", OuterNamed),
@@ -269,3 +269,47 @@ pub fn get_cmd_type(key: &str) -> Option<CmdType> {
     .map(|x| COMMANDS[x].1)
     .ok()
 }
+
+pub fn parse_cmd_type(s: &str) -> Option<CmdType> {
+  match s {
+    "Inner" => Some(Inner),
+    "Outer" => Some(Outer),
+    "OuterNamed" => Some(OuterNamed),
+    _ => None,
+  }
+}
+
+// Parses a table of lines like `my_lemma = "OuterNamed"`, a small subset of TOML.
+// Empty lines, comments starting with # and [section] headers are skipped.
+pub fn parse_commands(s: &str) -> Result<Vec<(String, CmdType)>, String> {
+  let mut commands = vec![];
+
+  for (lineno, line) in s.lines().enumerate() {
+    let line = line.split('#').next().unwrap_or("").trim();
+    if line.is_empty() || line.starts_with('[') {
+      continue;
+    }
+
+    let error = |msg: &str| format!("line {}: {}", lineno + 1, msg);
+
+    let (key, value) = line
+      .split_once('=')
+      .ok_or_else(|| error("expected command = \"Type\""))?;
+    let key = key.trim().trim_matches('"');
+    let value = value.trim().trim_matches('"');
+
+    if key.is_empty() {
+      return Err(error("missing command name"));
+    }
+    let typ = parse_cmd_type(value).ok_or_else(|| {
+      error(&format!(
+        "unknown command type {}, expected Inner, Outer or OuterNamed",
+        value
+      ))
+    })?;
+
+    commands.push((key.to_string(), typ));
+  }
+
+  Ok(commands)
+}
@@ -0,0 +1,104 @@
+//! Encodings for the snippets file.
+//!
+//! Snippets are UTF-8 by default, but some LaTeX toolchains expect Latin-1,
+//! or plain ASCII with the other characters escaped.
+
+/// An encoding of the snippets file.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Latin1,
+    /// ASCII, with every other character replaced by an escape.
+    AsciiWithEscapes,
+}
+
+impl Encoding {
+    /// Parses the name of an encoding, as given on the command line.
+    pub fn parse(name: &str) -> Option<Encoding> {
+        match name.to_lowercase().replace('_', "-").as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "latin-1" | "latin1" | "iso-8859-1" => Some(Encoding::Latin1),
+            "ascii-with-escapes" | "ascii" => Some(Encoding::AsciiWithEscapes),
+            _ => None,
+        }
+    }
+
+    /// The canonical name of the encoding.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Latin1 => "latin-1",
+            Encoding::AsciiWithEscapes => "ascii-with-escapes",
+        }
+    }
+
+    /// Encodes text, replacing characters outside of ASCII by escape with
+    /// [`Encoding::AsciiWithEscapes`].
+    /// Fails on the first character that Latin-1 cannot represent.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use isasnips::encoding::Encoding;
+    ///
+    /// let snippet = "lemma \"x ∈ A\" — see §2";
+    /// let bytes = Encoding::Latin1.encode("lemma §2", |_| String::new()).unwrap();
+    /// assert_eq!(bytes, b"lemma \xa72");
+    /// assert_eq!(Encoding::Latin1.decode(&bytes), "lemma §2");
+    ///
+    /// let error = Encoding::Latin1.encode(snippet, |_| String::new()).unwrap_err();
+    /// assert_eq!(error, "line 1: ∈ (U+2208) cannot be encoded as latin-1");
+    ///
+    /// let escape = |c: char| format!("&#x{:X};", c as u32);
+    /// let bytes = Encoding::AsciiWithEscapes.encode(snippet, escape).unwrap();
+    /// assert_eq!(bytes, b"lemma \"x &#x2208; A\" &#x2014; see &#xA7;2".to_vec());
+    /// ```
+    pub fn encode(self, text: &str, escape: impl Fn(char) -> String) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Latin1 => {
+                let mut bytes = Vec::with_capacity(text.len());
+                for (lineno, line) in text.split('\n').enumerate() {
+                    if lineno > 0 {
+                        bytes.push(b'\n');
+                    }
+                    for c in line.chars() {
+                        if (c as u32) > 0xff {
+                            return Err(format!(
+                                "line {}: {} (U+{:04X}) cannot be encoded as {}",
+                                lineno + 1,
+                                c,
+                                c as u32,
+                                self.name()
+                            ));
+                        }
+                        bytes.push(c as u8);
+                    }
+                }
+                Ok(bytes)
+            }
+            Encoding::AsciiWithEscapes => Ok(text
+                .chars()
+                .map(|c| {
+                    if c.is_ascii() {
+                        c.to_string()
+                    } else {
+                        escape(c)
+                    }
+                })
+                .collect::<String>()
+                .into_bytes()),
+        }
+    }
+
+    /// Decodes bytes in the encoding. Escapes are kept as they are.
+    pub fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 | Encoding::AsciiWithEscapes => {
+                String::from_utf8_lossy(bytes).into_owned()
+            }
+            Encoding::Latin1 => bytes.iter().map(|b| *b as char).collect(),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Reading and writing files.
+//!
+//! Copying the session, extracting the snippets and writing the output go
+//! through [`Fs`], so they can be exercised on [`MemFs`] instead of the disk.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use walkdir::WalkDir;
+
+/// What an entry of a walk is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Dir,
+    /// A symlink to a directory that is not followed.
+    DirLink,
+    /// A symlink to one of its parent directories, which would be walked forever.
+    Loop,
+}
+
+/// An entry of a walk, at a depth below its root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub depth: usize,
+    pub kind: Kind,
+}
+
+/// Files and directories, on the disk or elsewhere.
+pub trait Fs: Sync {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// The root and everything below it, parents before their children.
+    /// Symlinks to directories other than the root are only followed with
+    /// `follow_links`.
+    fn walk(&self, root: &Path, follow_links: bool) -> Vec<io::Result<Entry>>;
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// The files on the disk.
+pub struct StdFs;
+
+impl Fs for StdFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn walk(&self, root: &Path, follow_links: bool) -> Vec<io::Result<Entry>> {
+        let entry = |path: &Path, depth, kind| {
+            Ok(Entry {
+                path: path.to_path_buf(),
+                depth,
+                kind,
+            })
+        };
+        (WalkDir::new(root).follow_links(follow_links).into_iter())
+            .map(|e| match e {
+                Ok(e) => {
+                    let typ = e.file_type();
+                    if typ.is_symlink() && e.path().is_dir() && e.depth() > 0 {
+                        entry(e.path(), e.depth(), Kind::DirLink)
+                    } else if typ.is_dir() || e.path().is_dir() {
+                        entry(e.path(), e.depth(), Kind::Dir)
+                    } else {
+                        entry(e.path(), e.depth(), Kind::File)
+                    }
+                }
+                Err(e) if e.loop_ancestor().is_some() => {
+                    entry(e.path().unwrap_or(root), e.depth(), Kind::Loop)
+                }
+                Err(e) => Err(e.into()),
+            })
+            .collect()
+    }
+}
+
+/// Files kept in memory. Clones share the same files, so they can be looked
+/// at after handing a clone over.
+///
+/// As on the disk, a file can only be written to an existing directory.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::files::{Fs, Kind, MemFs};
+/// use std::path::Path;
+///
+/// let fs = MemFs::default();
+/// assert!(fs.write(Path::new("/out/a.tex"), b"a").is_err());
+///
+/// fs.create_dir_all(Path::new("/out")).unwrap();
+/// fs.write(Path::new("/out/a.tex"), b"a").unwrap();
+/// assert_eq!(fs.read(Path::new("/out/a.tex")).unwrap(), b"a");
+///
+/// let walked: Vec<(String, Kind)> = (fs.walk(Path::new("/out"), false).into_iter())
+///     .map(|e| e.unwrap())
+///     .map(|e| (e.path.display().to_string(), e.kind))
+///     .collect();
+/// assert_eq!(walked, [("/out".into(), Kind::Dir), ("/out/a.tex".into(), Kind::File)]);
+/// ```
+#[derive(Clone, Default)]
+pub struct MemFs {
+    files: Arc<Mutex<BTreeMap<PathBuf, Vec<u8>>>>,
+    dirs: Arc<Mutex<BTreeSet<PathBuf>>>,
+}
+
+impl MemFs {
+    /// The files below a directory, with their contents.
+    pub fn files(&self, dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        (self.files.lock().unwrap().iter())
+            .filter(|(path, _)| path.starts_with(dir))
+            .map(|(path, contents)| (path.clone(), contents.clone()))
+            .collect()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.parent().is_none_or(|p| p.as_os_str().is_empty())
+            || self.dirs.lock().unwrap().contains(path)
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{}: not found", path.display()),
+    )
+}
+
+impl Fs for MemFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let files = self.files.lock().unwrap();
+        files.get(path).cloned().ok_or_else(|| not_found(path))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if self.is_dir(parent) && !self.is_dir(path) => {
+                let mut files = self.files.lock().unwrap();
+                files.insert(path.to_path_buf(), contents.to_vec());
+                Ok(())
+            }
+            _ => Err(not_found(path)),
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        if self.files.lock().unwrap().contains_key(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{}: is a file", path.display()),
+            ));
+        }
+        let mut dirs = self.dirs.lock().unwrap();
+        dirs.extend(path.ancestors().map(Path::to_path_buf));
+        Ok(())
+    }
+
+    fn walk(&self, root: &Path, _follow_links: bool) -> Vec<io::Result<Entry>> {
+        let depth = |path: &Path| path.components().count() - root.components().count();
+        let dirs = self.dirs.lock().unwrap();
+        let files = self.files.lock().unwrap();
+
+        let mut entries: Vec<(&PathBuf, Kind)> = (dirs.iter())
+            .map(|d| (d, Kind::Dir))
+            .chain(files.keys().map(|f| (f, Kind::File)))
+            .filter(|(path, _)| path.starts_with(root))
+            .collect();
+        if entries.is_empty() {
+            return vec![Err(not_found(root))];
+        }
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        (entries.into_iter())
+            .map(|(path, kind)| {
+                Ok(Entry {
+                    path: path.clone(),
+                    depth: depth(path),
+                    kind,
+                })
+            })
+            .collect()
+    }
+}
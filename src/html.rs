@@ -0,0 +1,206 @@
+//! Snippets from Isabelle's HTML presentation of a theory.
+//!
+//! The HTML shows the annotated source, so the markers appear as `text_raw`
+//! commands on lines of their own, wrapped in the spans of the markup.
+//! Every source line between them becomes a part.
+
+use crate::theory::{is_empty_part, is_raw_marker, Snippet, BEGIN, END};
+
+/// The text of a line of HTML, without tags and with entities decoded.
+/// Cartouches are written back as `\<open>` and `\<close>`, as in the theory source.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::html::html_text;
+///
+/// let line = "<span class=\"command\">text_raw</span> <span class=\"cartouche\">‹A &amp; B›</span>";
+/// assert_eq!(html_text(line), "text_raw \\<open>A & B\\<close>");
+/// ```
+pub fn html_text(line: &str) -> String {
+    let mut text = String::new();
+    let mut rest = line;
+    while let Some(i) = rest.find('<') {
+        text.push_str(&rest[..i]);
+        rest = rest[i..].find('>').map_or("", |j| &rest[i + j + 1..]);
+    }
+    text.push_str(rest);
+
+    decode_entities(&text)
+        .replace('‹', "\\<open>")
+        .replace('›', "\\<close>")
+}
+
+fn decode_entities(s: &str) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        let entity = rest[i + 1..].find(';').map(|j| &rest[i + 1..i + 1 + j]);
+        let c = entity.and_then(|e| match e {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => {
+                let code = match e.strip_prefix("#x").or_else(|| e.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => e.strip_prefix('#').and_then(|dec| dec.parse().ok()),
+                };
+                code.and_then(char::from_u32)
+            }
+        });
+        match (c, entity) {
+            (Some(c), Some(entity)) => {
+                out.push(c);
+                rest = &rest[i + entity.len() + 2..];
+            }
+            _ => {
+                out.push('&');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Escapes text for HTML.
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Makes every line a fragment of its own: the elements still open at the end of
+/// a line are closed there and opened again at the start of the next, since e.g.
+/// a cartouche over several lines is a single span.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::html::balance_lines;
+///
+/// let lines = vec!["a <span class=\"x\">b".to_string(), "c</span> d".to_string()];
+/// assert_eq!(
+///     balance_lines(&lines),
+///     ["a <span class=\"x\">b</span>", "<span class=\"x\">c</span> d"]
+/// );
+/// ```
+pub fn balance_lines(lines: &[String]) -> Vec<String> {
+    // The start tags of the open elements, by name.
+    let mut open: Vec<(String, String)> = vec![];
+    let mut balanced = vec![];
+
+    for line in lines {
+        let mut out: String = open.iter().map(|(_, tag)| tag.as_str()).collect();
+        out.push_str(line);
+
+        let mut rest = line.as_str();
+        while let Some(i) = rest.find('<') {
+            let end = match rest[i..].find('>') {
+                Some(j) => i + j + 1,
+                None => break,
+            };
+            let tag = &rest[i..end];
+            rest = &rest[end..];
+
+            if let Some(name) = tag.strip_prefix("</") {
+                let name = name.trim_end_matches('>').trim();
+                if let Some(k) = open.iter().rposition(|(n, _)| n == name) {
+                    open.truncate(k);
+                }
+            } else if !tag.starts_with("<!") && !tag.ends_with("/>") {
+                let name = tag[1..tag.len() - 1]
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default();
+                if !["br", "hr", "img", "meta", "link", "input"].contains(&name) {
+                    open.push((name.to_string(), tag.to_string()));
+                }
+            }
+        }
+
+        for (name, _) in open.iter().rev() {
+            out.push_str(&format!("</{}>", name));
+        }
+        balanced.push(out);
+    }
+
+    balanced
+}
+
+// The content of the <pre class="source"> element, or all of the HTML if there is none.
+fn source(html: &str) -> &str {
+    let start = match html.find("<pre class=\"source\"") {
+        Some(i) => i + html[i..].find('>').map_or(0, |j| j + 1),
+        None => return html,
+    };
+    let end = html[start..]
+        .find("</pre>")
+        .map_or(html.len(), |j| start + j);
+    &html[start..end]
+}
+
+/// Reads the snippets of a theory from its HTML presentation, where every source
+/// line becomes a part, kept as a balanced fragment of HTML.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::html::extract_html;
+///
+/// let html = r#"<pre class="source"><span class="command">theory</span> Foo <span class="keyword2">imports</span> Main <span class="keyword2">begin</span>
+///
+/// <span class="command">text_raw</span> <span class="cartouche">‹DefineSnippet lemma:foo lemma 3 4›</span>
+/// <span class="command">lemma</span> foo<span class="delimiter">:</span> <span class="cartouche">‹x &lt; y
+///   ⟹ True›</span>
+/// <span class="command">text_raw</span> <span class="cartouche">‹EndSnippet›</span>
+///
+/// <span class="command">end</span></pre>"#;
+///
+/// let snippets = extract_html("Foo", html, &None);
+/// assert_eq!(snippets.len(), 1);
+/// assert_eq!(snippets[0].name, "lemma:foo");
+/// assert_eq!(snippets[0].source_lines, Some((3, 4)));
+/// assert_eq!(
+///     snippets[0].parts,
+///     [
+///         [r#"<span class="command">lemma</span> foo<span class="delimiter">:</span> <span class="cartouche">‹x &lt; y</span>"#],
+///         [r#"<span class="cartouche">  ⟹ True›</span>"#],
+///     ]
+/// );
+/// ```
+pub fn extract_html(theory: &str, html: &str, prefix: &Option<String>) -> Vec<Snippet> {
+    let lines: Vec<String> = source(html).lines().map(String::from).collect();
+    let mut snippets = vec![];
+    let mut current: Option<Snippet> = None;
+
+    // The markers are found in the text, and the parts are taken from the HTML.
+    for (line, fragment) in lines.iter().zip(balance_lines(&lines)) {
+        let text = html_text(line);
+        if is_raw_marker(&text, BEGIN) {
+            current = Some(Snippet::from_marker(&text, theory, prefix.clone()));
+            continue;
+        }
+
+        let snippet = match current.as_mut() {
+            Some(snippet) => snippet,
+            None => continue,
+        };
+
+        if is_raw_marker(&text, END) {
+            while snippet.parts.len() > 1 && is_empty_part(snippet.parts.last().unwrap()) {
+                snippet.parts.pop();
+            }
+            snippets.extend(current.take());
+            continue;
+        }
+
+        snippet.parts.push(vec![fragment]);
+    }
+
+    snippets
+}
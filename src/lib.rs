@@ -0,0 +1,16 @@
+//! Isabelle-aware helpers behind the isasnips tool.
+
+pub mod commands;
+pub mod encoding;
+pub mod files;
+pub mod html;
+pub mod markup;
+pub mod pattern;
+pub mod root;
+pub mod runner;
+pub mod sha256;
+pub mod symbols;
+pub mod theory;
+mod words;
+
+pub use words::{make_words, make_words_with, wrap_line, CLOSE, MIN_LINE_LENGTH, OPEN};
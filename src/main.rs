@@ -1,622 +1,6015 @@
-mod commands;
-use commands::*;
+use isasnips::commands::*;
+use isasnips::encoding::Encoding;
+use isasnips::files::{Fs, Kind, StdFs};
+use isasnips::html::{escape_html, extract_html, html_text};
+use isasnips::markup::strip_markup;
+use isasnips::pattern::Pattern;
+use isasnips::root::Root;
+use isasnips::runner::{sessions, IsabelleRunner, SystemIsabelle};
+use isasnips::sha256::sha256_hex;
+use isasnips::symbols::*;
+use isasnips::theory::*;
+use isasnips::{make_words, wrap_line, CLOSE, MIN_LINE_LENGTH, OPEN};
 
+use std::cell::RefCell;
 use std::env;
 use std::ffi::OsString;
 use std::fs;
-use std::io::{self, BufRead};
-use std::path::Path;
-use std::process::{exit, Command, Stdio};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
 
-use tempfile::tempdir;
+use similar::TextDiff;
+use tempfile::{tempdir, tempdir_in, TempDir};
 use walkdir::WalkDir;
 
-// NOTE: For simplicity I assume that every outer command starts on a new line.
+// Warnings are counted, so -fail-on-warning can fail the run at the end.
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
 
-const BEGIN: &str = "DefineSnippet";
-const END: &str = "EndSnippet";
-
-const OPEN: &str = "\\<open>";
-const CLOSE: &str = "\\<close>";
+// The warnings of the current thread, so tests can check their text.
+#[cfg(test)]
+thread_local! {
+    static WARNED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
 
-const ISA_NEWLINE: &str = "\\isanewline";
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        let msg = format!($($arg)*);
+        WARNINGS.fetch_add(1, Ordering::Relaxed);
+        #[cfg(test)]
+        WARNED.with(|w| w.borrow_mut().push(msg.clone()));
+        eprintln!("{}", paint(YELLOW, &format!("WARNING: {}", msg)));
+    }};
+}
 
-/*
- * Isabelle
- */
+// Set from -quiet before anything is printed. Warnings and errors are always printed.
+static QUIET: AtomicBool = AtomicBool::new(false);
 
-fn make_root(theory: &str, library: bool) -> String {
-    format!(
-        "session isasnips = {} +
-  theories
-    {}
-  document_files
-    \"root.tex\"",
-        if library { "\"HOL-Library\"" } else { "HOL" },
-        theory
-    )
+macro_rules! info {
+    ($($arg:tt)*) => {{
+        if !QUIET.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        }
+    }};
 }
 
-fn call_isabelle(path: &Path, cmds: &[&str]) -> io::Result<()> {
-    println!("Running isabelle {} >>>", cmds.join(" "));
-
-    let stdout = Command::new("isabelle")
-        .current_dir(path)
-        .stdout(Stdio::piped())
-        .args(cmds)
-        .spawn()
-        .expect("Failed to call isabelle command")
-        .stdout
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Could not capture stdout."))?;
+thread_local! {
+    // Set for every build with -parallel-builds, so the interleaved output says where it is from.
+    static BUILD_LABEL: RefCell<String> = const { RefCell::new(String::new()) };
+}
 
-    let reader = io::BufReader::new(stdout);
+// Counted for the summary at the end of a run.
+static BUILT_THEORIES: AtomicUsize = AtomicUsize::new(0);
+static BUILD_MILLIS: AtomicU64 = AtomicU64::new(0);
+static SNIPPETS: AtomicUsize = AtomicUsize::new(0);
 
-    reader
-        .lines()
-        .filter_map(|line| line.ok())
-        .for_each(|line| println!("  {}", line));
+// Set from -color before anything is printed.
+static COLOR: AtomicBool = AtomicBool::new(false);
 
-    println!("<<<");
+const RED: &str = "31";
+const YELLOW: &str = "33";
+const DIM: &str = "2";
 
-    Ok(())
+// Wraps a message in an ANSI color code, if colors are enabled.
+fn paint(code: &str, s: &str) -> String {
+    if COLOR.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{}\x1b[0m", code, s)
+    } else {
+        s.to_string()
+    }
 }
 
-fn mkroot(
-    isa_path: &Path,
-    temp_dir: &Path,
-    library: bool,
-    skip_text: bool,
-) -> io::Result<OsString> {
-    let theory_stem = isa_path.file_stem().expect("No theory file.");
+const INLINE: &str = "InlineSnippet";
 
-    let theory = theory_stem
-        .to_str()
-        .expect("Could not convert theory name to str");
+// Starts the snippets of a theory with -toc.
+const THEORY_HEADING: &str = "SnippetTheory";
 
-    let new_theory = process_theory(isa_path, skip_text)?;
-    let new_path = temp_dir.join(Path::new(theory).with_extension("thy"));
-    fs::write(new_path, new_theory)?;
+const ISA_NEWLINE: &str = "\\isanewline";
 
-    call_isabelle(temp_dir, &["mkroot", "-n", "isasnips"])?;
+/*
+ * Isabelle
+ */
 
-    let root_path = temp_dir.join(Path::new("ROOT"));
-    let root = make_root(theory, library);
-    fs::write(root_path, root)?;
+// A base session must not break the ROOT file: at most one pair of quotes around it.
+fn check_session_name(name: &str) -> Result<(), String> {
+    let inner = match name.strip_prefix('"') {
+        Some(rest) => rest
+            .strip_suffix('"')
+            .ok_or_else(|| format!("Unterminated quote in session name: {}", name))?,
+        None => name,
+    };
+    if inner.trim().is_empty() {
+        return Err("Empty session name".to_string());
+    }
+    if inner.chars().any(|c| c == '"' || c.is_control()) {
+        return Err(format!("Invalid session name: {}", name));
+    }
+    Ok(())
+}
 
-    Ok(theory_stem.to_os_string())
+fn make_root(theories: &[String], base: &str) -> String {
+    let root = (theories.iter()).fold(Root::new("isasnips", base), |root, thy| root.theory(thy));
+    root.document_file("root.tex").render()
 }
 
-/*
- * Generate Snippets
- */
+// Every line of Isabelle's output is printed with the given prefix and returned.
+fn run_isabelle(
+    runner: &dyn IsabelleRunner,
+    path: &Path,
+    cmds: &[&str],
+    prefix: &str,
+) -> io::Result<(ExitStatus, Lines)> {
+    let label = BUILD_LABEL.with(|label| label.borrow().clone());
+    info!(
+        "{}",
+        paint(
+            DIM,
+            &format!("{}Running isabelle {} >>>", label, cmds.join(" "))
+        )
+    );
+
+    let mut output = vec![];
+    let status = runner.run(path, cmds, &mut |line| {
+        // Isabelle marks errors with ***, which are printed even with -quiet.
+        if line.starts_with("***") {
+            eprintln!("{}", output_line(prefix, &label, &paint(RED, &line)));
+        } else {
+            info!("{}", output_line(prefix, &label, &line));
+        }
+        output.push(line);
+    })?;
 
-fn escape_underscores(s: &str) -> String {
-    s.replace("_", "-")
+    info!("{}", paint(DIM, &format!("{}<<<", label)));
+
+    Ok((status, output))
 }
 
-fn strip_superscripts(s: &str) -> String {
-    s.replace("^", "")
+// A line of Isabelle's output as it is shown, after the prefix and the label of the build.
+fn output_line(prefix: &str, label: &str, line: &str) -> String {
+    [prefix, label, line].concat()
 }
 
-fn snippet_name(key: &str, name: &str) -> String {
-    format!(
-        "{}:{}",
-        strip_superscripts(&escape_underscores(key)),
-        strip_superscripts(&escape_underscores(name))
-    )
+fn call_isabelle(
+    runner: &dyn IsabelleRunner,
+    path: &Path,
+    cmds: &[&str],
+    prefix: &str,
+) -> io::Result<()> {
+    let (status, _) = run_isabelle(runner, path, cmds, prefix)?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "isabelle {} failed with {}",
+            cmds[0], status
+        )));
+    }
+    Ok(())
 }
 
-fn text_raw(s: &str) -> String {
-    vec!["text_raw", " ", OPEN, s, CLOSE].join("")
+// Output of failures that are worth retrying, like a heap image locked by another build.
+const TRANSIENT: &[&str] = &[
+    "database is locked",
+    "could not lock",
+    "unable to lock",
+    "resource temporarily unavailable",
+];
+
+#[cfg(not(test))]
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+#[cfg(test)]
+const RETRY_DELAY: Duration = Duration::ZERO;
+
+fn is_transient(output: &[String]) -> bool {
+    output.iter().any(|line| {
+        let line = line.to_lowercase();
+        TRANSIENT.iter().any(|pattern| line.contains(pattern))
+    })
 }
 
-fn begin_marker(name: &str) -> String {
-    let cmd = vec![BEGIN, name].join(" ");
-    text_raw(&cmd)
+// Like call_isabelle, but retries up to the given number of times on transient failures.
+fn call_isabelle_retrying(
+    runner: &dyn IsabelleRunner,
+    path: &Path,
+    cmds: &[&str],
+    prefix: &str,
+    retries: usize,
+) -> io::Result<()> {
+    let mut attempt = 0;
+    loop {
+        let (status, output) = run_isabelle(runner, path, cmds, prefix)?;
+        if status.success() {
+            return Ok(());
+        }
+
+        if attempt == retries || !is_transient(&output) {
+            return Err(io::Error::other(format!(
+                "isabelle {} failed with {}",
+                cmds[0], status
+            )));
+        }
+
+        attempt += 1;
+        warn!(
+            "isabelle {} failed with a transient error, retrying ({} of {}).",
+            cmds[0], attempt, retries
+        );
+        thread::sleep(RETRY_DELAY);
+    }
 }
 
-fn end_marker() -> String {
-    text_raw(END)
+// A missing base session is only found after a long build, so it is checked up front.
+fn check_base_session(runner: &dyn IsabelleRunner, base: &str, dirs: &[String]) -> io::Result<()> {
+    info!("Checking that the base session {} is installed", base);
+    let name = base.trim_matches('"');
+    match sessions(runner, dirs) {
+        Some(sessions) if !sessions.iter().any(|s| s == name) => Err(io::Error::other(format!(
+            "base session {} is not installed, use -no-preflight to build anyway",
+            base
+        ))),
+        Some(_) => Ok(()),
+        None => {
+            warn!(
+                "could not list the Isabelle sessions, the base session {} is not checked.",
+                base
+            );
+            Ok(())
+        }
+    }
 }
 
-fn make_words(s: &str) -> Vec<String> {
-    let mut words: Vec<String> = vec![];
-    let mut current_word = String::new();
+fn theory_stem(isa_path: &Path, config: &Config) -> OsString {
+    theory_name(isa_path, config.theory_ext()).unwrap_or_else(|| {
+        isa_path
+            .file_stem()
+            .expect("No theory file.")
+            .to_os_string()
+    })
+}
 
-    let mut pushing_symbol = false;
-    let mut current_symbol = String::new();
+// The directory that stands for the session directory, and the path of the theory below it.
+// With -base-dir, the theory keeps its place below the base directory,
+// so imports relative to it resolve as in the original layout.
+fn session_path(isa_path: &Path, config: &Config) -> io::Result<(PathBuf, PathBuf)> {
+    let thy_path = fs::canonicalize(isa_path)?;
+    let dir = match &config.base_dir {
+        Some(base_dir) => with_context(fs::canonicalize(base_dir), base_dir)?,
+        None => thy_path.parent().map(Path::to_path_buf).unwrap_or_default(),
+    };
+    let relative = thy_path.strip_prefix(&dir).map_err(|_| {
+        io::Error::other(format!(
+            "{} is not inside the base directory {}",
+            isa_path.display(),
+            dir.display()
+        ))
+    })?;
+    let relative = relative.with_file_name(theory_stem(isa_path, config));
+    Ok((dir, relative))
+}
 
-    let mut inside_dquote = false;
+// The theories of the generated ROOT: the extra imports, then the theory itself.
+fn root_theories(isa_path: &Path, config: &Config) -> io::Result<Vec<String>> {
+    let mut theories = vec![];
+    for extra in &config.extra_imports {
+        let stem = Path::new(extra)
+            .file_stem()
+            .ok_or_else(|| io::Error::other(format!("Not a theory file: {}", extra)))?;
+        theories.push(stem.to_string_lossy().into_owned());
+    }
 
-    fn breaker(c: char) -> bool {
-        vec!['[', ']', '(', ')', ':', '=', '\\'].contains(&c)
+    // A theory below the session directory is given by its quoted path.
+    let (_, relative) = session_path(isa_path, config)?;
+    if relative.parent().is_some_and(|p| p != Path::new("")) {
+        let path: Vec<String> = (relative.components())
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        theories.push(format!("\"{}\"", path.join("/")));
+    } else {
+        theories.push(relative.to_string_lossy().into_owned());
     }
+    Ok(theories)
+}
 
-    for c in s.chars() {
-        if c == '"' {
-            if inside_dquote {
-                words.push(CLOSE.to_owned());
-            } else {
-                words.push(OPEN.to_owned());
-            }
+// Returns the processed theories, the given one first.
+fn mkroot(
+    isa_path: &Path,
+    temp_dir: &Path,
+    base: &str,
+    config: &Config,
+) -> io::Result<Vec<OsString>> {
+    let theory_stem = theory_stem(isa_path, config);
+    let (dir, relative) = session_path(isa_path, config)?;
 
-            inside_dquote = !inside_dquote;
-            continue;
-        }
+    let new_path = temp_dir.join(&relative).with_extension("thy");
+    if let Some(parent) = new_path.parent() {
+        config.fs().create_dir_all(parent)?;
+    }
+    write_annotated(
+        &new_path,
+        temp_dir,
+        &process_theory(isa_path, config)?,
+        config,
+    )?;
 
-        if pushing_symbol {
-            if c == '>' {
-                if current_symbol == "open" {
-                    words.push(OPEN.to_owned());
-                } else if current_symbol == "close" {
-                    words.push(CLOSE.to_owned());
-                } else {
-                    current_word.push_str(&current_symbol);
+    // Imports are found by Isabelle itself, they only need to be at the same relative path.
+    // Without -include-imports they are only copied, which is needed with -base-dir.
+    let mut processed = vec![theory_stem.clone()];
+    if config.include_imports || config.base_dir.is_some() {
+        for import in import_closure(&[isa_path.to_path_buf()], config.theory_ext()) {
+            let (relative, name) = match (
+                import.strip_prefix(&dir),
+                theory_name(&import, config.theory_ext()),
+            ) {
+                (Ok(relative), Some(name)) => (relative.to_path_buf(), name),
+                _ => {
+                    warn!(
+                        "{}: imported from outside {}, not included.",
+                        import.display(),
+                        dir.display()
+                    );
+                    continue;
                 }
-
-                current_symbol.clear();
-                pushing_symbol = false;
-            } else if c != '<' {
-                current_symbol.push(c);
-            }
-            continue;
-        }
-
-        if c.is_whitespace() || breaker(c) {
-            if !current_word.is_empty() {
-                words.push(current_word.clone());
-                current_word.clear();
+            };
+            let new_path = temp_dir
+                .join(relative)
+                .with_file_name(&name)
+                .with_extension("thy");
+            if let Some(parent) = new_path.parent() {
+                config.fs().create_dir_all(parent)?;
             }
-        }
-
-        if breaker(c) {
-            if c == '\\' {
-                pushing_symbol = true;
+            if config.include_imports {
+                info!("Including imported theory {:?}", name);
+                write_annotated(
+                    &new_path,
+                    temp_dir,
+                    &process_theory(&import, config)?,
+                    config,
+                )?;
+                processed.push(name);
             } else {
-                words.push(c.to_string());
+                copy_file(&import, &new_path, config)?;
             }
-        } else if !c.is_whitespace() {
-            current_word.push(c);
         }
     }
 
-    if pushing_symbol && !current_symbol.is_empty() {
-        words.push(current_symbol);
+    // Extra imports are copied as is and listed before the theory itself.
+    for extra in &config.extra_imports {
+        let extra_path = Path::new(extra);
+        let extra_name = extra_path.file_name().ok_or_else(|| {
+            io::Error::other(format!("Not a theory file: {}", extra_path.display()))
+        })?;
+        copy_file(extra_path, &temp_dir.join(extra_name), config)
+            .map_err(|e| io::Error::other(format!("{}: {}", extra_path.display(), e)))?;
     }
+    let theories = root_theories(isa_path, config)?;
 
-    if !current_word.is_empty() {
-        words.push(current_word);
-    }
+    call_isabelle(
+        config.runner(),
+        temp_dir,
+        &["mkroot", "-n", "isasnips"],
+        config.isabelle_prefix(),
+    )?;
 
-    words
-}
+    let root_path = temp_dir.join(Path::new("ROOT"));
+    let root = make_root(&theories, base);
+    config.fs().write(&root_path, root.as_bytes())?;
 
-type Lines = Vec<String>;
-type Chunk = (String, CmdType, Lines);
+    Ok(processed)
+}
 
-fn chunk_theory(thy: String) -> Vec<Chunk> {
-    let mut chunks = vec![];
+// Copies a file as it is.
+fn copy_file(from: &Path, to: &Path, config: &Config) -> io::Result<()> {
+    config.fs().write(to, &config.fs().read(from)?)
+}
 
-    let mut current_cmd: Option<(String, CmdType)> = None;
-    let mut current_chunk: Vec<String> = vec![];
+/*
+ * Generate Snippets
+ */
 
-    for line in thy.lines() {
-        let tokens = make_words(line);
+type Lines = Vec<String>;
 
-        let mut first = tokens.first().map(|s| s.to_string());
-        if let Some(c) = tokens.first() {
-            if c == "(" && tokens.get(1).map_or(false, |c| c == "*") {
-                first = Some("(*".to_string());
-            }
+// Writes an annotated theory to the working directory, and with -save-annotated
+// also below the given directory, at the same relative path.
+fn write_annotated(
+    new_path: &Path,
+    temp_dir: &Path,
+    annotated: &str,
+    config: &Config,
+) -> io::Result<()> {
+    config.fs().write(new_path, annotated.as_bytes())?;
 
-            if c == "*" && tokens.get(1).map_or(false, |c| c == ")") {
-                first = Some("*)".to_string());
-            }
+    if let Some(dir) = &config.save_annotated {
+        let relative = new_path.strip_prefix(temp_dir).unwrap_or(new_path);
+        let saved = Path::new(dir).join(relative);
+        if let Some(parent) = saved.parent() {
+            config.fs().create_dir_all(parent)?;
         }
+        with_context(
+            config.fs().write(&saved, annotated.as_bytes()),
+            "Could not save annotated theory",
+        )?;
+        info!("Annotated theory saved to: {}", saved.display());
+    }
+    Ok(())
+}
 
-        let cmd_type = first.clone().and_then(|f| get_cmd_type(&f));
+// Pipes a theory through the -preprocess command, run by the shell.
+fn preprocess(thy: &str, cmd: &str) -> io::Result<String> {
+    let mut child = Command::new("sh")
+        .args(["-c", cmd])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
 
-        match cmd_type {
-            Some(CmdType::Outer) | Some(CmdType::OuterNamed) => match current_cmd {
-                None => {}
-                Some((ref cmd, ref typ)) => {
-                    chunks.push((cmd.to_owned(), typ.clone(), current_chunk.clone()));
-                    current_chunk.clear();
-                }
-            },
-            Some(CmdType::Inner) | None => {}
-        }
+    // Written on its own thread, so a filter that prints as it reads cannot block on a full pipe.
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| io::Error::other("Could not capture stdin."))?;
+    let input = thy.to_string();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
 
-        match cmd_type {
-            Some(CmdType::Outer) | Some(CmdType::OuterNamed) => {
-                current_cmd = first.map(|s| (s.clone(), cmd_type.unwrap()));
+    let output = child.wait_with_output()?;
+    // A filter may exit without reading all of its input, which is fine if it succeeds.
+    let _ = writer.join();
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "-preprocess command failed with {}",
+            output.status
+        )));
+    }
+    String::from_utf8(output.stdout)
+        .map_err(|_| io::Error::other("-preprocess command printed invalid UTF-8"))
+}
+
+fn process_theory(thy_path: &Path, config: &Config) -> io::Result<String> {
+    let thy = config.fs().read_to_string(thy_path)?;
+    let thy = match &config.preprocess {
+        None => thy,
+        Some(cmd) => match preprocess(&thy, cmd) {
+            Ok(thy) => thy,
+            Err(e) if config.strict => {
+                return Err(io::Error::other(format!("{}: {}", thy_path.display(), e)))
             }
-            Some(CmdType::Inner) | None => {}
-        }
+            // The theory is then built as it is, without snippets.
+            Err(e) => {
+                warn!(
+                    "{}: {}, the theory is not annotated.",
+                    thy_path.display(),
+                    e
+                );
+                return Ok(thy);
+            }
+        },
+    };
+    let annotated = annotate_theory_with(&thy, &config.options);
 
-        current_chunk.push(line.to_owned());
+    if config.require_names && !annotated.unnamed.is_empty() {
+        let unnamed: Vec<String> = (annotated.unnamed.iter())
+            .map(|(line, cmd)| {
+                format!(
+                    "{}:{}: could not extract name for '{}'",
+                    thy_path.display(),
+                    line,
+                    cmd
+                )
+            })
+            .collect();
+        return Err(io::Error::other(format!(
+            "Could not extract names:\n{}",
+            unnamed.join("\n")
+        )));
+    }
+    for (line, cmd) in &annotated.unnamed {
+        warn!(
+            "{}:{}: could not extract name for '{}', using a hash name",
+            thy_path.display(),
+            line,
+            cmd
+        );
     }
 
-    if !current_chunk.is_empty() {
-        match current_cmd {
-            Some((cmd, typ)) if !current_chunk.is_empty() => {
-                chunks.push((cmd, typ, current_chunk.clone()));
-            }
-            _ => {}
+    for (line, name, new) in &annotated.renamed {
+        let msg = format!(
+            "{}:{}: snippet {} is already used, renamed to {}",
+            thy_path.display(),
+            line,
+            name,
+            new
+        );
+        if config.strict {
+            return Err(io::Error::other(msg));
         }
+        warn!("{}", msg);
     }
 
-    chunks
+    Ok(annotated.text)
 }
 
-fn chunk_name(
-    cmd: &str,
-    words: &[String],
-    last_fun: &Option<String>,
-    last_instantiation: &Option<String>,
-) -> Option<String> {
-    let mut inside_parens = 0;
-    let mut inside_open = 0;
+// A prefix like Algebra-Group for Algebra/Group.thy relative to dir.
+fn path_prefix(thy_path: &Path, dir: &Path, theory: &OsString) -> Option<String> {
+    let thy_path = fs::canonicalize(thy_path).ok()?;
+    let dir = fs::canonicalize(dir).ok()?;
+    let relative = thy_path.strip_prefix(dir).ok()?.with_file_name(theory);
 
-    let mut name_parts = vec![];
-    let mut content = vec![];
-
-    // Names occur before these.
-    let markers = vec![
-        "[", ":", "=", "where", "and", "by", "imports", "begin", "fixes", "assumes", "shows",
-    ];
+    let parts: Vec<String> = relative
+        .components()
+        .map(|c| {
+            c.as_os_str()
+                .to_string_lossy()
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '.' || c == '\'' {
+                        c
+                    } else {
+                        '-'
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    Some(parts.join("-"))
+}
 
-    for i in 0..words.len() - 1 {
-        if words[i] == "(" {
-            inside_parens += 1;
-        } else if words[i] == ")" {
-            inside_parens -= 1;
-        } else if inside_parens == 0 && words[i] == OPEN {
-            inside_open += 1;
-        } else if inside_parens == 0 && words[i] == CLOSE {
-            inside_open -= 1;
-        } else if inside_open > 0 {
-            content.push(words[i].clone());
-        }
+// The path prefixes of the theories of an input, see path_prefix.
+fn path_prefixes(isa_path: &Path, dir: &Path, config: &Config) -> HashMap<OsString, String> {
+    let mut prefixes = HashMap::new();
 
-        if words[i] == CLOSE && inside_open == 0 {
-            break;
+    for entry in config.walk(isa_path).into_iter().filter_map(|e| e.ok()) {
+        let theory = match theory_name(entry.path(), config.theory_ext()) {
+            Some(theory) => theory,
+            None => continue,
+        };
+        match path_prefix(entry.path(), dir, &theory) {
+            Some(prefix) => {
+                prefixes.insert(theory, prefix);
+            }
+            None => warn!(
+                "{} is not inside {}, using the theory name as prefix.",
+                entry.path().display(),
+                dir.display()
+            ),
         }
+    }
 
-        if inside_parens == 0
-            && inside_open == 0
-            && words[i] != ")"
-            && !words[i].starts_with("'")
-            && words[i] != CLOSE
-        {
-            name_parts.push(words[i].clone());
-        }
+    prefixes
+}
 
-        if inside_parens == 0 && inside_open == 0 && markers.contains(&words[i + 1].as_str()) {
-            break;
-        }
-    }
+fn has_ext(p: &Path, ext: &str) -> bool {
+    p.extension().is_some_and(|e| e == ext)
+}
 
-    let mut name = None;
+// The theory name of a file with the given extension, which may contain dots like thy.txt.
+fn theory_name(p: &Path, ext: &str) -> Option<OsString> {
+    let file_name = p.file_name()?.to_str()?;
+    let name = file_name.strip_suffix(ext)?.strip_suffix('.')?;
+    (!name.is_empty()).then(|| OsString::from(name))
+}
 
-    if name_parts.len() > 1 {
-        name = Some(
-            name_parts[1..]
-                .into_iter()
-                .map(|s| s.to_string())
-                .collect::<String>(),
-        );
-    }
+// The theories in a session directory, without the excluded subdirectories.
+fn dir_theories(isa_path: &Path, exclude: &[PathBuf], config: &Config) -> Vec<OsString> {
+    config
+        .walk(isa_path)
+        .into_iter()
+        .filter_entry(|e| !exclude.iter().any(|x| x == e.path()))
+        .filter_map(|e| e.ok())
+        .filter_map(|e| theory_name(e.path(), config.theory_ext()))
+        .collect()
+}
 
-    if name.is_none() && !content.is_empty() && (cmd == "definition" || cmd == "abbreviation") {
-        // Make sure to include subscripts. A bit of a hack.
-        let mut parts = vec![];
-        parts.push(content[0].clone());
-        let mut i = 1;
-        while content[i].contains("^") {
-            parts.push(content[i].clone());
-            i += 1;
+// The imports in the header of a theory, as written.
+fn theory_imports(thy: &str) -> Vec<String> {
+    let mut imports = vec![];
+    let mut importing = false;
+    for word in make_words(thy) {
+        match word.as_str() {
+            "begin" => break,
+            "keywords" | "abbrevs" => importing = false,
+            "imports" => importing = true,
+            OPEN | CLOSE => (),
+            _ if importing => imports.push(word),
+            _ => (),
         }
-        name = Some(parts.join(""));
     }
+    imports
+}
 
-    if cmd == "termination" && name_parts.len() > 1 && name_parts[1] == "proof" {
-        name = None;
-    }
+// The imported theories that are files next to the theory, i.e. not from other sessions.
+fn local_imports(thy_path: &Path, ext: &str) -> Vec<PathBuf> {
+    let dir = thy_path.parent().unwrap_or(Path::new("."));
+    let thy = match fs::read_to_string(thy_path) {
+        Ok(thy) => thy,
+        Err(_) => return vec![],
+    };
+    theory_imports(&thy)
+        .iter()
+        .map(|import| dir.join(format!("{}.{}", import, ext)))
+        .filter_map(|path| fs::canonicalize(path).ok())
+        .filter(|path| path.is_file())
+        .collect()
+}
 
-    if name.is_none() && last_fun.is_some() && (cmd == "termination") {
-        name = last_fun.clone();
+fn visit_imports(thy_path: &Path, ext: &str, visiting: &mut Vec<PathBuf>, done: &mut Vec<PathBuf>) {
+    if done.iter().any(|p| p == thy_path) {
+        return;
     }
-
-    if name.is_none() && last_instantiation.is_some() && (cmd == "instance") {
-        name = last_instantiation.clone();
+    if let Some(i) = visiting.iter().position(|p| p == thy_path) {
+        let cycle: Vec<_> = (visiting[i..].iter())
+            .map(|p| p.display().to_string())
+            .collect();
+        warn!(
+            "Import cycle: {} -> {}",
+            cycle.join(" -> "),
+            thy_path.display()
+        );
+        return;
     }
 
-    name.map(|n| snippet_name(cmd, &n))
+    visiting.push(thy_path.to_path_buf());
+    for import in local_imports(thy_path, ext) {
+        visit_imports(&import, ext, visiting, done);
+    }
+    visiting.pop();
+    done.push(thy_path.to_path_buf());
 }
 
-fn process_theory(thy_path: &Path, skip_text: bool) -> io::Result<String> {
-    let thy = fs::read_to_string(thy_path)?;
-
-    let chunks = chunk_theory(thy);
-
-    let mut annotated: Vec<String> = vec![];
-    let mut last_fun = None;
-    let mut last_instantiation = None;
-    let mut hashes = HashMap::new();
-
-    for chunk in &chunks {
-        let (cmd, cmd_type, cont_lines) = chunk;
-
-        if skip_text && cmd == "text" {
-            continue;
-        }
-
-        let cont = cont_lines.join("\n");
-        let words = make_words(&cont);
+// The local theories imported by the given ones, transitively, without the given ones.
+// Cycles are reported and broken.
+fn import_closure(thy_paths: &[PathBuf], ext: &str) -> Vec<PathBuf> {
+    let roots: Vec<PathBuf> = (thy_paths.iter())
+        .filter_map(|p| fs::canonicalize(p).ok())
+        .collect();
+    let mut done = vec![];
+    for root in &roots {
+        visit_imports(root, ext, &mut vec![], &mut done);
+    }
+    done.retain(|p| !roots.contains(p));
+    done
+}
 
-        if words.is_empty() {
-            continue;
-        }
+// Adds the local imports of the listed theories of a session directory, with -include-imports.
+fn with_imports(
+    isa_path: &Path,
+    user_theories: Vec<OsString>,
+    exclude: &[PathBuf],
+    config: &Config,
+) -> Vec<OsString> {
+    let files: Vec<(OsString, PathBuf)> = config
+        .walk(isa_path)
+        .into_iter()
+        .filter_entry(|e| !exclude.iter().any(|x| x == e.path()))
+        .filter_map(|e| e.ok())
+        .filter_map(|e| Some((theory_name(e.path(), config.theory_ext())?, e.into_path())))
+        .collect();
 
-        let mut outer_name = None;
-        if *cmd_type == CmdType::OuterNamed {
-            outer_name = chunk_name(cmd, &words, &last_fun, &last_instantiation);
-        }
+    let listed: Vec<PathBuf> = (files.iter())
+        .filter(|(theory, _)| user_theories.iter().any(|t| config.same_theory(t, theory)))
+        .map(|(_, path)| path.clone())
+        .collect();
 
-        let name = match outer_name {
-            Some(n) => n,
-            None => {
-                let mut hasher = DefaultHasher::new();
-                words.hash(&mut hasher);
-                let hash = hasher.finish();
-                let suffix = hashes.entry(hash).or_insert(0);
-                let name = if *suffix > 0 {
-                    snippet_name(cmd, &format!("{:x}-{}", hash, suffix))
-                } else {
-                    snippet_name(cmd, &format!("{:x}", hash))
-                };
-                *suffix += 1;
-                name
+    let mut theories = user_theories;
+    for import in import_closure(&listed, config.theory_ext()) {
+        if let Some(theory) = theory_name(&import, config.theory_ext()) {
+            if !theories.contains(&theory) {
+                info!("Including imported theory {:?}", theory);
+                theories.push(theory);
             }
-        };
-
-        if cmd == "function" {
-            let colon = name.find(':').unwrap_or(0);
-            let last_name = name[colon + 1..].to_string();
-            last_fun = Some(last_name);
         }
+    }
+    theories
+}
 
-        if cmd == "instantiation" {
-            let colon = name.find(':').unwrap_or(0);
-            let last_name = name[colon + 1..].to_string();
-            last_instantiation = Some(last_name);
-        }
+// Whether the theory has a comment like (* paper *) for the tag of -tag, or there is no tag.
+fn has_tag(thy_path: &Path, config: &Config) -> io::Result<bool> {
+    let tag = match &config.tag {
+        Some(tag) => tag,
+        None => return Ok(true),
+    };
+    let thy = config.fs().read_to_string(thy_path)?;
+    Ok(thy
+        .split("(*")
+        .skip(1)
+        .filter_map(|rest| rest.split_once("*)"))
+        .any(|(comment, _)| comment.trim() == tag))
+}
 
-        annotated.push(begin_marker(&name));
-        annotated.extend(chunk.2.clone());
-        if annotated.last().map_or(false, |l| l.is_empty()) {
-            annotated.pop();
+// Levenshtein distance over characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
         }
-        annotated.push(end_marker());
-        annotated.push(String::new());
     }
-
-    Ok(annotated.join("\n"))
+    row[b.len()]
 }
 
-fn has_ext(p: &Path, ext: &str) -> bool {
-    p.extension().map_or(false, |e| e == ext)
+// Up to three theories with names close to a listed one, the closest first, to catch typos.
+fn similar_theories<'a>(name: &OsString, found: &'a [OsString]) -> Vec<&'a OsString> {
+    let name = name.to_string_lossy().to_lowercase();
+    let limit = (name.chars().count() / 3).max(2);
+
+    let mut candidates: Vec<(usize, &OsString)> = found
+        .iter()
+        .map(|t| (edit_distance(&name, &t.to_string_lossy().to_lowercase()), t))
+        .filter(|(distance, _)| *distance <= limit)
+        .collect();
+    candidates.sort();
+    candidates.into_iter().take(3).map(|(_, t)| t).collect()
 }
 
 fn copy_isabelle(
     isa_path: &Path,
     temp_path: &Path,
     user_theories: &[OsString],
-    skip_text: bool,
+    exclude: &[PathBuf],
+    config: &Config,
 ) -> io::Result<Vec<OsString>> {
     let mut processed = vec![];
+    // Every theory in the session, for suggestions when a listed one is not found.
+    let mut found = vec![];
+    let mut untagged = vec![];
+
+    // The ROOTS file would refer to the excluded directories.
+    let is_excluded = |p: &Path| {
+        exclude.iter().any(|e| p.starts_with(e))
+            || (!exclude.is_empty() && p == isa_path.join("ROOTS"))
+    };
 
-    for entry in WalkDir::new(isa_path) {
-        let entry = entry.expect("Could not read file.");
-        let typ = entry.file_type();
+    for entry in config.fs().walk(isa_path, config.follow_symlinks) {
+        let entry = entry?;
+        if is_excluded(&entry.path) {
+            continue;
+        }
+        match entry.kind {
+            // With -follow-symlinks, a link to a parent directory would be walked forever.
+            Kind::Loop => {
+                warn!(
+                    "{}: skipping {}, a symlink to one of its parent directories.",
+                    isa_path.display(),
+                    entry.path.display()
+                );
+                continue;
+            }
+            // The input itself is always followed, other links only with -follow-symlinks.
+            Kind::DirLink => {
+                warn!(
+                    "{}: skipping {}, a symlink to a directory. Pass -follow-symlinks to include it.",
+                    isa_path.display(),
+                    entry.path.display()
+                );
+                continue;
+            }
+            Kind::Dir | Kind::File => {}
+        }
 
         let new_entry_path = entry
-            .path()
+            .path
             .strip_prefix(isa_path)
             .expect("Could not strip prefix.");
 
         let new_path = temp_path.join(new_entry_path);
 
-        if typ.is_dir() {
-            fs::create_dir_all(new_path)?;
-        } else if has_ext(&entry.path(), "thy") {
-            let theory = entry
-                .path()
-                .file_stem()
-                .expect("Could not extract file stem.");
-
-            if user_theories.is_empty() || user_theories.contains(&theory.to_os_string()) {
-                let new_theory = process_theory(entry.path(), skip_text)?;
-                fs::write(new_path, new_theory)?;
-                processed.push(theory.to_os_string());
+        if entry.kind == Kind::Dir {
+            config.fs().create_dir_all(&new_path)?;
+        } else if let Some(theory) = theory_name(&entry.path, config.theory_ext()) {
+            // Isabelle only builds .thy files.
+            let new_path = new_path.with_file_name(&theory).with_extension("thy");
+
+            found.push(theory.clone());
+
+            let selected = user_theories.is_empty()
+                || user_theories.iter().any(|t| config.same_theory(t, &theory));
+            if selected && !has_tag(&entry.path, config)? {
+                untagged.push(theory);
+                copy_file(&entry.path, &new_path, config)?;
+            } else if selected {
+                let new_theory = process_theory(&entry.path, config)?;
+                write_annotated(&new_path, temp_path, &new_theory, config)?;
+                processed.push(theory);
             } else {
-                fs::copy(entry.path(), new_path)?;
+                copy_file(&entry.path, &new_path, config)?;
             }
         } else {
-            fs::copy(entry.path(), new_path)?;
+            copy_file(&entry.path, &new_path, config)?;
         }
     }
 
+    if found.is_empty() && fs::symlink_metadata(isa_path).is_ok_and(|m| m.is_symlink()) {
+        warn!(
+            "{}: no theories were found behind the symlink.",
+            isa_path.display()
+        );
+    }
+
     for thy in user_theories {
-        if !processed.contains(thy) {
-            println!("WARNING: Listed theory {:?} was not found.", thy);
+        if untagged.iter().any(|t| config.same_theory(t, thy)) {
+            warn!(
+                "{}: listed theory {:?} does not have the tag {}.",
+                isa_path.display(),
+                thy,
+                config.tag.as_deref().unwrap_or_default()
+            );
+        } else if !processed.iter().any(|t| config.same_theory(t, thy)) {
+            let similar = similar_theories(thy, &found);
+            match similar.split_first() {
+                Some((best, others)) => warn!(
+                    "{}: listed theory {:?} was not found, did you mean {:?}?{}",
+                    isa_path.display(),
+                    thy,
+                    best,
+                    if others.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" Other candidates: {:?}", others)
+                    }
+                ),
+                None => warn!(
+                    "{}: listed theory {:?} was not found.",
+                    isa_path.display(),
+                    thy
+                ),
+            }
         }
     }
 
     Ok(processed)
 }
 
-fn begin_snippet(name: &str) -> String {
-    vec!["\\", BEGIN, "{", name, "}{%"].join("")
+fn begin_snippet(name: &str, cmd: Option<&str>) -> String {
+    match cmd {
+        Some(cmd) => ["\\", BEGIN, "{", name, "}{", cmd, "}{%"].join(""),
+        None => ["\\", BEGIN, "{", name, "}{%"].join(""),
+    }
 }
 
 fn end_snippet() -> String {
-    vec!["}%", END].join("")
+    ["}%", END].join("")
 }
 
-fn iname(prefix: &Option<String>, n: &str, i: usize) -> String {
+// Snippets of a single line are written as one \InlineSnippet{name}{body} with -inline-short.
+fn inline_snippet(name: &str, cmd: Option<&str>, body: &[String]) -> String {
+    // Lines that are empty or only a comment would end the paragraph or hide the closing brace.
+    let body: Vec<&str> = body
+        .iter()
+        .map(|line| line.as_str())
+        .filter(|line| !line.trim().chars().all(|c| c == '%'))
+        .collect();
+    let body = body.join("\n");
+    match cmd {
+        Some(cmd) => format!("\\{}{{{}}}{{{}}}{{{}}}%", INLINE, name, cmd, body),
+        None => format!("\\{}{{{}}}{{{}}}%", INLINE, name, body),
+    }
+}
+
+fn base_name(prefix: &Option<String>, n: &str) -> String {
     match prefix {
-        Some(pre) => format!("{}:{}-{}", pre, n, i),
-        None => format!("{}-{}", n, i),
+        Some(pre) => format!("{}:{}", pre, n),
+        None => n.to_string(),
     }
 }
 
-fn extract_snippets(path: &Path, theories: &[OsString]) -> io::Result<String> {
-    let mut snippets: Vec<String> = vec![];
+fn iname(prefix: &Option<String>, n: &str, i: usize) -> String {
+    part_label(&base_name(prefix, n), i)
+}
 
-    let disambiguate = theories.len() > 1;
+// Every begin marker must be closed by an end marker before the next one,
+// otherwise snippets would silently be merged.
+fn check_markers(
+    path: &Path,
+    lines: &[String],
+    is_marker: impl Fn(&str, &str) -> bool,
+) -> io::Result<()> {
+    let mut open: Option<String> = None;
+    let mut last = None;
 
-    for entry in WalkDir::new(path)
-        .into_iter()
+    for (lineno, line) in lines.iter().enumerate() {
+        let error = |msg: String| {
+            Err(io::Error::other(format!(
+                "{}:{}: {}",
+                path.display(),
+                lineno + 1,
+                msg
+            )))
+        };
+
+        if is_marker(line, BEGIN) {
+            let name = marker_args(line).into_iter().next().unwrap_or_default();
+            if let Some(open) = &open {
+                return error(format!(
+                    "snippet {} is not closed before snippet {}",
+                    open, name
+                ));
+            }
+            open = Some(name);
+        } else if is_marker(line, END) {
+            match open.take() {
+                Some(name) => last = Some(name),
+                None => {
+                    return error(match &last {
+                        Some(name) => format!("{} without {} after snippet {}", END, BEGIN, name),
+                        None => format!("{} without {}", END, BEGIN),
+                    })
+                }
+            }
+        }
+    }
+
+    match open {
+        Some(name) => Err(io::Error::other(format!(
+            "{}: snippet {} is not closed",
+            path.display(),
+            name
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Extracts the snippets of one generated file.
+fn extract_file(
+    fs: &dyn Fs,
+    file_path: &Path,
+    prefix: &Option<String>,
+    backend: Backend,
+) -> io::Result<Vec<Snippet>> {
+    let mut snippets = vec![];
+
+    let stem = file_path.file_stem().expect("Could not get file stem.");
+
+    let file = fs.read(file_path)?;
+    let lines: Lines = file.as_slice().lines().map_while(Result::ok).collect();
+
+    let theory = stem.to_string_lossy();
+    if backend == Backend::Html {
+        let text: Lines = lines.iter().map(|line| html_text(line)).collect();
+        check_markers(file_path, &text, is_raw_marker)?;
+        return Ok(extract_html(&theory, &lines.join("\n"), prefix));
+    }
+
+    let raw = backend == Backend::Raw;
+    let is_marker = |line: &str, marker: &str| {
+        if raw {
+            is_raw_marker(line, marker)
+        } else {
+            line.contains(marker)
+        }
+    };
+
+    check_markers(file_path, &lines, is_marker)?;
+
+    if raw {
+        return Ok(extract_raw(&theory, &lines, prefix));
+    }
+
+    let mut current: Option<Snippet> = None;
+    let mut part: Lines = vec![];
+
+    for line in lines {
+        if is_marker(&line, BEGIN) {
+            current = Some(Snippet::from_marker(&line, &theory, prefix.clone()));
+            part.clear();
+            continue;
+        }
+
+        let snippet = match current.as_mut() {
+            Some(snippet) => snippet,
+            None => continue,
+        };
+
+        if is_marker(&line, END) {
+            // Drop a trailing part left by a final newline, so parts stay contiguous.
+            if snippet.parts.is_empty() || !is_empty_part(&part) {
+                snippet.parts.push(part.clone());
+            }
+            snippets.extend(current.take());
+            continue;
+        }
+
+        part.push(line.clone());
+
+        if line.contains(ISA_NEWLINE) {
+            snippet.parts.push(part.clone());
+            part.clear();
+        }
+    }
+
+    Ok(snippets)
+}
+
+// Except for the LaTeX backend, every source line becomes a part.
+// Theories in path_prefixes are prefixed by their path instead of their name.
+fn extract_snippets(
+    fs: &dyn Fs,
+    path: &Path,
+    theories: &[OsString],
+    disambiguate: bool,
+    backend: Backend,
+    path_prefixes: &HashMap<OsString, String>,
+) -> io::Result<Vec<Snippet>> {
+    let mut snippets = vec![];
+
+    let ext = backend.ext();
+
+    for entry in (fs.walk(path, false).into_iter())
         .filter_map(|e| e.ok())
-        .filter(|e| has_ext(e.path(), "tex"))
+        .filter(|e| e.kind == Kind::File && has_ext(&e.path, ext))
         .filter(|e| {
             theories.contains(
-                &e.path()
+                &e.path
                     .file_stem()
                     .expect("Could not get file stem.")
                     .to_os_string(),
             )
         })
     {
-        let prefix = if disambiguate {
-            let theory = entry
-                .path()
-                .file_stem()
-                .expect("Could not get file stem.")
-                .to_str()
-                .expect("Could not convert to str.");
-            Some(escape_underscores(theory))
-        } else {
+        let stem = entry.path.file_stem().expect("Could not get file stem.");
+        let prefix = if !disambiguate {
             None
+        } else if let Some(prefix) = path_prefixes.get(stem) {
+            Some(prefix.clone())
+        } else {
+            let theory = stem.to_str().expect("Could not convert to str.");
+            Some(escape_underscores(theory))
         };
 
-        let mut including = false;
-        let file = fs::File::open(entry.path())?;
-        let lines = io::BufReader::new(file).lines();
+        snippets.extend(extract_file(fs, &entry.path, &prefix, backend)?);
+    }
 
-        let mut name = String::new();
-        let mut i = 0;
+    Ok(snippets)
+}
 
-        for line in lines.filter_map(|l| l.ok()) {
-            if line.contains(BEGIN) {
-                including = true;
-                let words: Vec<_> = line.split_whitespace().collect();
-                name = words[1].to_string();
-                i = 0;
-                snippets.push(begin_snippet(&iname(&prefix, &name, i)));
-            } else if line.contains(END) {
-                including = false;
-                snippets.push(end_snippet());
-            } else if including {
-                snippets.push(line.clone());
-            }
+// Drops lines from the start and end of the whole snippet, with -trim-head and -trim-tail.
+// Snippets that would be left empty, like most one-line snippets, are kept as they are.
+fn trim_snippet(snippet: &mut Snippet, head: usize, tail: usize, raw: bool) {
+    if head == 0 && tail == 0 {
+        return;
+    }
+    if head + tail >= snippet.parts.len() {
+        return;
+    }
 
-            if including && line.contains(ISA_NEWLINE) {
-                snippets.push(end_snippet());
-                i += 1;
-                snippets.push(begin_snippet(&iname(&prefix, &name, i)));
+    snippet.parts.drain(..head);
+    snippet.parts.truncate(snippet.parts.len() - tail);
+    if let Some((start, end)) = snippet.source_lines {
+        snippet.source_lines = Some((start + head, end.saturating_sub(tail)));
+    }
+
+    // The new last line must not end the line, like the last line of any snippet.
+    if tail > 0 && !raw {
+        let last = snippet.parts.last_mut().and_then(|part| {
+            part.iter_mut()
+                .rev()
+                .find(|line| line.contains(ISA_NEWLINE))
+        });
+        if let Some(line) = last {
+            if let Some(i) = line.rfind(ISA_NEWLINE) {
+                line.replace_range(i..i + ISA_NEWLINE.len(), "");
             }
         }
     }
-
-    Ok(snippets.join("\n"))
 }
 
-const OPTIONS: [&str; 5] = [
-    "-quick_and_dirty",
-    "-quick-and-dirty",
-    "-library",
-    "-skip-text",
-    "-skip_text",
-];
+// With -max-parts, the parts from the last one allowed on are joined into it.
+fn cap_parts(snippet: &mut Snippet, max: usize) {
+    if snippet.parts.len() > max {
+        let rest = snippet.parts.split_off(max - 1).concat();
+        snippet.parts.push(rest);
+    }
+}
 
-fn main() {
-    let mut args: Vec<String> = env::args().collect();
+// With -split-equations, the equations of a function are also joined again into a
+// snippet of the whole function, named after it and placed before them.
+fn join_equations(snippets: Vec<Snippet>, raw: bool) -> Vec<Snippet> {
+    let mut joined: Vec<Snippet> = vec![];
+    // The index of the whole function that the following equations are added to.
+    let mut whole: Option<usize> = None;
 
-    if args.len() < 3 {
-        println!(
-            "Usage: ./{} theory/root snippets-out.tex [optional list of theories to include]",
-            args[0]
-        );
-        exit(1);
+    for snippet in snippets {
+        match split_equation_name(&snippet.name) {
+            Some((function, 0)) => {
+                let mut function_snippet = snippet.clone();
+                function_snippet.name = function.to_string();
+                joined.push(function_snippet);
+                whole = Some(joined.len() - 1);
+            }
+            Some((function, _))
+                if whole.is_some_and(|w| {
+                    joined[w].name == function && joined[w].theory == snippet.theory
+                }) =>
+            {
+                let function_snippet = &mut joined[whole.expect("Checked above.")];
+                // The last line of the previous equation no longer ends the snippet.
+                if !raw {
+                    if let Some(part) = function_snippet.parts.last_mut() {
+                        part.push(ISA_NEWLINE.to_string());
+                    }
+                }
+                function_snippet.parts.extend(snippet.parts.iter().cloned());
+                if let (Some((start, _)), Some((_, end))) =
+                    (function_snippet.source_lines, snippet.source_lines)
+                {
+                    function_snippet.source_lines = Some((start, end));
+                }
+            }
+            _ => whole = None,
+        }
+        joined.push(snippet);
     }
 
-    let quick_and_dirty = args.contains(&String::from("-quick_and_dirty"))
-        || args.contains(&String::from("-quick-and-dirty"));
+    joined
+}
 
-    let library = args.contains(&String::from("-library"));
-    let skip_text =
-        args.contains(&String::from("-skip-text")) || args.contains(&String::from("-skip_text"));
+// With -proof-steps, every step of a proof is preceded by the steps before it,
+// so the snippets grow step by step.
+fn accumulate_steps(snippets: &mut [Snippet], raw: bool) {
+    for i in 1..snippets.len() {
+        let (before, after) = snippets.split_at_mut(i);
+        let (previous, snippet) = (&before[i - 1], &mut after[0]);
 
-    args.retain(|x| !OPTIONS.contains(&x.as_str()));
+        let follows = match (
+            split_step_name(&previous.name),
+            split_step_name(&snippet.name),
+        ) {
+            (Some((a, j)), Some((b, k))) => {
+                a == b && j + 1 == k && previous.theory == snippet.theory
+            }
+            _ => false,
+        };
+        if !follows {
+            continue;
+        }
 
-    let mut user_theories = args.iter().skip(3).map(OsString::from).collect::<Vec<_>>();
+        let mut parts = previous.parts.clone();
+        // The last line of the previous step no longer ends the snippet.
+        if !raw {
+            if let Some(part) = parts.last_mut() {
+                part.push(ISA_NEWLINE.to_string());
+            }
+        }
+        parts.append(&mut snippet.parts);
+        snippet.parts = parts;
 
-    let isa_path = Path::new(&args[1]);
-    if !isa_path.exists() {
-        println!(
-            "The given Isabelle file or directory does not exist: {}",
-            isa_path.display()
-        );
-        exit(1);
+        if let (Some((start, _)), Some((_, end))) = (previous.source_lines, snippet.source_lines) {
+            snippet.source_lines = Some((start, end));
+        }
     }
+}
+
+/*
+ * Output
+ */
 
-    let temp_dir = tempdir().expect("Could not create a temporary directory.");
-    let temp_path = temp_dir.path();
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+enum Color {
+    // Only on a terminal, and unless NO_COLOR is set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    fn enabled(self) -> bool {
+        match self {
+            Color::Auto => {
+                io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+            }
+            Color::Always => true,
+            Color::Never => false,
+        }
+    }
+}
 
-    println!("Working directory: {}", temp_path.display());
+// What the snippets are read from after the build: the generated LaTeX, the annotated
+// theories with -raw-source, or the HTML presentation for -format html.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Backend {
+    Latex,
+    Raw,
+    Html,
+}
 
-    if isa_path.is_file() {
-        let theory = mkroot(isa_path, temp_path, library, skip_text)
-            .expect("Error making theory root directory.");
-        user_theories.push(theory);
+impl Backend {
+    fn ext(self) -> &'static str {
+        match self {
+            Backend::Latex => "tex",
+            Backend::Raw => "thy",
+            Backend::Html => "html",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+enum Format {
+    #[default]
+    Latex,
+    Markdown,
+    Rst,
+    Html,
+}
+
+impl Format {
+    fn ext(self) -> &'static str {
+        match self {
+            Format::Latex => "tex",
+            Format::Markdown => "md",
+            Format::Rst => "rst",
+            Format::Html => "html",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Format::Latex => "LaTeX",
+            Format::Markdown => "Markdown",
+            Format::Rst => "reStructuredText",
+            Format::Html => "HTML",
+        }
+    }
+}
+
+// How -output-encoding ascii-with-escapes writes a character outside of ASCII.
+// TeX engines with Unicode support read ^^^^2200 like the character itself.
+// HTML has character references. Markdown and reStructuredText code blocks have no escapes, so symbols go back to their Isabelle names.
+fn escape_char(format: Format, c: char) -> String {
+    match format {
+        Format::Latex if (c as u32) > 0xffff => format!("^^^^^^{:06x}", c as u32),
+        Format::Latex => format!("^^^^{:04x}", c as u32),
+        Format::Html => format!("&#x{:x};", c as u32),
+        Format::Markdown | Format::Rst => match get_name(c) {
+            Some(name) => format!("\\<{}>", name),
+            None => format!("\\u{{{:04x}}}", c as u32),
+        },
+    }
+}
+
+// An output file that is a directory, or whose extension belongs to no or another format,
+// is most likely a mistake on the command line.
+fn output_ext_warning(snips_path: &Path, format: Format) -> Option<String> {
+    if snips_path.is_dir() {
+        return Some(format!(
+            "the output {} is a directory.",
+            snips_path.display()
+        ));
+    }
+
+    let ext = snips_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("");
+    let matching = |f: Format| ext == f.ext() || (f == Format::Markdown && ext == "markdown");
+    if matching(format) {
+        return None;
+    }
+    let formats = [Format::Latex, Format::Markdown, Format::Rst, Format::Html];
+    Some(match formats.iter().copied().find(|f| matching(*f)) {
+        Some(other) => format!(
+            "the snippets are written as {} to {}, which looks like {}. Use -format to change the format.",
+            format.name(),
+            snips_path.display(),
+            other.name()
+        ),
+        None => format!(
+            "the output {} does not end in .{}, but the snippets are written as {}.",
+            snips_path.display(),
+            format.ext(),
+            format.name()
+        ),
+    })
+}
+
+// Escape a raw source line for LaTeX, keeping its spacing.
+fn latex_escape(line: &str) -> String {
+    let mut out = String::new();
+    for c in line.chars() {
+        match c {
+            '\\' => out.push_str("\\textbackslash{}"),
+            '{' | '}' | '$' | '&' | '#' | '_' | '%' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '^' => out.push_str("\\^{}"),
+            '~' => out.push_str("\\~{}"),
+            ' ' => out.push_str("\\ "),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// A comment like "% from Foo.thy:12-18" on where a snippet comes from.
+fn provenance(snippet: &Snippet, config: &Config) -> String {
+    let file = format!("{}.{}", snippet.theory, config.theory_ext());
+    match snippet.source_lines {
+        Some((start, end)) if start == end => format!("% from {}:{}", file, start),
+        Some((start, end)) => format!("% from {}:{}-{}", file, start, end),
+        None => format!("% from {}", file),
+    }
+}
+
+fn render_latex(snippets: &[Snippet], config: &Config) -> String {
+    let mut out: Vec<String> = vec![];
+
+    for (k, snippet) in snippets.iter().enumerate() {
+        // With -toc, the snippets of every theory start with a heading for it.
+        if config.toc && (k == 0 || snippets[k - 1].theory != snippet.theory) {
+            out.push(format!(
+                "\\{}{{{}}}%",
+                THEORY_HEADING,
+                latex_escape(&snippet.theory)
+            ));
+        }
+        if config.provenance_comments {
+            out.push(provenance(snippet, config));
+        }
+
+        let last = snippet.parts.len().saturating_sub(1);
+        for (i, part) in snippet.parts.iter().enumerate() {
+            let name = iname(&snippet.prefix, &snippet.name, i);
+            let cmd = config.command_arg.then_some(snippet.cmd.as_str());
+
+            let mut body = vec![];
+            if let Some(env) = &config.wrap_env {
+                body.push(format!("\\begin{{{}}}%", env));
+            }
+            // The header is source, so it is written like -raw-source in front of the first part.
+            if let (0, Some(context)) = (i, &snippet.context) {
+                body.extend(context.lines().map(|l| latex_escape(l) + ISA_NEWLINE));
+            }
+            if config.raw_source {
+                // Separate the lines like Isabelle does, so \SnippetPart works the same.
+                let lines = part.len();
+                body.extend(part.iter().enumerate().map(|(j, l)| {
+                    let newline = if i < last || j + 1 < lines {
+                        ISA_NEWLINE
+                    } else {
+                        "%"
+                    };
+                    latex_escape(l) + newline
+                }));
+            } else {
+                body.extend(part.iter().cloned());
+            }
+            if let Some(env) = &config.wrap_env {
+                body.push(format!("\\end{{{}}}%", env));
+            }
+
+            if config.inline_short && snippet.parts.len() == 1 {
+                out.push(inline_snippet(&name, cmd, &body));
+            } else {
+                out.push(begin_snippet(&name, cmd));
+                out.extend(body);
+                out.push(end_snippet());
+            }
+        }
+    }
+
+    out.join("\n")
+}
+
+// Turn Isabelle's LaTeX for a part back into plain text with Unicode symbols.
+fn plain_text(part: &[String]) -> String {
+    let mut text = String::new();
+
+    for line in part {
+        let mut chars = line.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '%' => break,
+                '{' | '}' => {}
+                '\\' => {
+                    let mut macro_name = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if !c.is_ascii_alphabetic() {
+                            break;
+                        }
+                        macro_name.push(c);
+                        chars.next();
+                    }
+
+                    if macro_name.is_empty() {
+                        // A control symbol like '\ '.
+                        if let Some(c) = chars.next() {
+                            text.push(c);
+                        }
+                    } else if let Some(sym) = macro_name.strip_prefix("isasym") {
+                        match get_symbol(sym) {
+                            Some(u) => text.push_str(u),
+                            None => text.push_str(&format!("\\<{}>", sym)),
+                        }
+                    } else if let Some(ctrl) = macro_name.strip_prefix("isactrl") {
+                        match get_symbol(&format!("^{}", ctrl)) {
+                            Some(u) => text.push_str(u),
+                            None => text.push_str(&format!("\\<^{}>", ctrl)),
+                        }
+                    } else if let Some(ch) = macro_name.strip_prefix("isachar") {
+                        text.push_str(get_char(ch).unwrap_or(""));
+                    }
+                    // Other macros like \isacommand only contribute their argument.
+                }
+                c => text.push(c),
+            }
+        }
+    }
+
+    text.trim_end().to_string()
+}
+
+// Replace symbols like \<forall> in raw source by Unicode where known.
+fn unicode_symbols(line: &str) -> String {
+    let mut out = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find("\\<") {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let symbol = rest.find('>').map(|end| (end, get_symbol(&rest[2..end])));
+        match symbol {
+            Some((end, Some(u))) => {
+                out.push_str(u);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                out.push_str("\\<");
+                rest = &rest[2..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// The lines of a snippet as plain text, for the code blocks of Markdown and reStructuredText.
+fn code_lines(snippet: &Snippet, config: &Config) -> Vec<String> {
+    let mut lines = vec![];
+    if let Some(context) = &snippet.context {
+        lines.extend(context.lines().map(unicode_symbols));
+    }
+    if config.raw_source {
+        lines.extend(snippet.parts.concat().iter().map(|l| unicode_symbols(l)));
+    } else {
+        lines.extend(snippet.parts.iter().map(|part| plain_text(part)));
+    }
+    lines
+}
+
+fn render_markdown(snippets: &[Snippet], config: &Config) -> String {
+    let mut out: Vec<String> = vec![];
+
+    for snippet in snippets {
+        out.push(format!(
+            "<a id=\"{}\"></a>",
+            base_name(&snippet.prefix, &snippet.name)
+        ));
+        out.push(String::new());
+        out.push("```isabelle".to_string());
+        out.extend(code_lines(snippet, config));
+        out.push("```".to_string());
+        out.push(String::new());
+    }
+
+    out.join("\n")
+}
+
+// A code block per snippet, after a label for :ref:. The label is quoted with backquotes,
+// since names contain colons. The content is indented by three spaces, and blank lines
+// are left empty.
+fn render_rst(snippets: &[Snippet], config: &Config) -> String {
+    let mut out: Vec<String> = vec![];
+
+    for snippet in snippets {
+        out.push(format!(
+            ".. _`{}`:",
+            base_name(&snippet.prefix, &snippet.name)
+        ));
+        out.push(String::new());
+        out.push(".. code-block:: isabelle".to_string());
+        out.push(String::new());
+        for line in code_lines(snippet, config) {
+            if line.trim().is_empty() {
+                out.push(String::new());
+            } else {
+                out.push(format!("   {}", line.trim_end()));
+            }
+        }
+        out.push(String::new());
+    }
+
+    out.join("\n")
+}
+
+// A pre element per snippet, with the name as id and the parts on lines of their own.
+// Parts from the HTML presentation are fragments of it, other lines are escaped.
+fn render_html(snippets: &[Snippet], config: &Config) -> String {
+    let mut out: Vec<String> = vec![];
+
+    for snippet in snippets {
+        out.push(format!(
+            "<pre class=\"snippet\" id=\"{}\">",
+            escape_html(&base_name(&snippet.prefix, &snippet.name))
+        ));
+        if let Some(context) = &snippet.context {
+            out.extend(context.lines().map(|l| escape_html(&unicode_symbols(l))));
+        }
+        if config.raw_source {
+            out.extend((snippet.parts.concat().iter()).map(|l| escape_html(&unicode_symbols(l))));
+        } else {
+            out.extend(snippet.parts.concat());
+        }
+        out.push("</pre>".to_string());
+        out.push(String::new());
+    }
+
+    out.join("\n")
+}
+
+/*
+ * Manifest
+ */
+
+const STRUCTURE: [&str; 7] = [
+    "chapter",
+    "section",
+    "subsection",
+    "subsubsection",
+    "paragraph",
+    "subparagraph",
+    "text",
+];
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// The argument of e.g. \isamarkupsection{...} in the generated LaTeX.
+fn markup_title(snippet: &Snippet) -> Option<String> {
+    let body = snippet.parts.concat().join("\n");
+    let start = body.find(&format!("\\isamarkup{}{{", snippet.cmd))? + snippet.cmd.len() + 11;
+
+    let mut depth = 1;
+    for (i, c) in body[start..].char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            let title: Vec<String> = body[start..start + i].lines().map(String::from).collect();
+            return Some(plain_text(&title));
+        }
+    }
+
+    None
+}
+
+// 64-bit FNV-1a, which unlike DefaultHasher is specified, so hashes stay the same
+// across Rust versions and platforms.
+fn stable_hash(s: &str) -> u64 {
+    s.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// A hash of the extracted body, to notice when a snippet changes under the same name.
+fn content_hash(snippet: &Snippet) -> String {
+    let body: Vec<&str> = (snippet.parts.iter().flatten())
+        .map(|line| line.as_str())
+        .collect();
+    format!("{:016x}", stable_hash(&body.join("\n")))
+}
+
+fn render_manifest(snippets: &[Snippet]) -> String {
+    let mut entries = vec![];
+
+    for snippet in snippets {
+        let mut fields = vec![];
+        if STRUCTURE.contains(&snippet.cmd.as_str()) {
+            fields.push(format!("\"kind\": {}", json_string("structure")));
+        } else {
+            fields.push(format!("\"kind\": {}", json_string("snippet")));
+        }
+        fields.push(format!("\"command\": {}", json_string(&snippet.cmd)));
+        fields.push(format!(
+            "\"name\": {}",
+            json_string(&base_name(&snippet.prefix, &snippet.name))
+        ));
+        fields.push(format!("\"parts\": {}", snippet.parts.len()));
+        fields.push(format!(
+            "\"content_hash\": {}",
+            json_string(&content_hash(snippet))
+        ));
+        if let Some((start, end)) = snippet.source_lines {
+            fields.push(format!("\"start_line\": {}, \"end_line\": {}", start, end));
+        }
+        if let Some(title) = markup_title(snippet) {
+            fields.push(format!("\"title\": {}", json_string(&title)));
+        }
+        if let Some(original) = &snippet.original_name {
+            fields.push(format!("\"original_name\": {}", json_string(original)));
+        }
+        entries.push(format!("  {{{}}}", fields.join(", ")));
+    }
+
+    format!("[\n{}\n]\n", entries.join(",\n"))
+}
+
+const HEADER: &str = "Generated by isasnips";
+
+// A comment like "% Generated by isasnips 0.1.0 from Theory.thy at 2024-01-31 12:00:00 UTC".
+fn make_header(sources: &[String], config: &Config) -> String {
+    let mut text = format!(
+        "{} {} from {}",
+        HEADER,
+        env!("CARGO_PKG_VERSION"),
+        sources.join(", ")
+    );
+    if !config.no_timestamp {
+        text.push_str(&format!(" at {}", utc_timestamp()));
+    }
+    match config.format {
+        Format::Latex => format!("% {}\n", text),
+        Format::Markdown | Format::Html => format!("<!-- {} -->\n\n", text),
+        Format::Rst => format!(".. {}\n\n", text),
+    }
+}
+
+fn strip_header(s: &str) -> &str {
+    let first = s.lines().next().unwrap_or("");
+    if first.contains(HEADER)
+        && (first.starts_with('%') || first.starts_with("<!--") || first.starts_with(".."))
+    {
+        s[first.len()..].trim_start_matches('\n')
     } else {
-        let processed = copy_isabelle(&isa_path, &temp_path, &user_theories, skip_text)
-            .expect("Could not copy Isabelle files.");
-        if user_theories.is_empty() {
-            user_theories.extend(processed);
-        }
-    }
-
-    let mut isa_args = vec![
-        "build",
-        "-c",
-        "-D",
-        ".",
-        "-o",
-        "document=pdf",
-        "-o",
-        "document_output=output",
+        s
+    }
+}
+
+// The current time in UTC, converting days to a civil date by hand to avoid a dependency.
+fn utc_timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let (days, time) = (secs / 86400, secs % 86400);
+
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+fn render_snippets(snippets: &[Snippet], config: &Config) -> String {
+    match config.format {
+        Format::Latex => render_latex(snippets, config),
+        Format::Markdown => render_markdown(snippets, config),
+        Format::Rst => render_rst(snippets, config),
+        Format::Html => render_html(snippets, config),
+    }
+}
+
+// Default definitions of the macros used by the snippets, matching the options that
+// change them. \providecommand keeps any definitions of the user's own.
+fn render_macros(config: &Config) -> String {
+    let define = |macro_name: &str| {
+        if config.command_arg {
+            format!(
+                "\\providecommand{{\\{}}}[3]{{\\expandafter\\newcommand\\csname snippet--#1\\endcsname{{#3}}}}",
+                macro_name
+            )
+        } else {
+            format!(
+                "\\providecommand{{\\{}}}[2]{{\\expandafter\\newcommand\\csname snippet--#1\\endcsname{{#2}}}}",
+                macro_name
+            )
+        }
+    };
+
+    let mut out = vec![
+        format!(
+            "% Snippet macros generated by isasnips {}",
+            env!("CARGO_PKG_VERSION")
+        ),
+        define(BEGIN),
     ];
+    if config.inline_short {
+        out.push(define(INLINE));
+    }
+    if config.toc {
+        out.push(format!(
+            "\\providecommand{{\\{}}}[1]{{\\subsection*{{#1}}\\addcontentsline{{toc}}{{subsection}}{{#1}}}}",
+            THEORY_HEADING
+        ));
+    }
+    if let Some(env) = &config.wrap_env {
+        out.push(format!(
+            "\\ifcsname {0}\\endcsname\\else\\newenvironment{{{0}}}{{}}{{}}\\fi",
+            env
+        ));
+    }
+    out.push(
+        r"\providecommand{\Snippet}[1]{{%
+  \newcount\i
+  \i=0
+  \loop
+    \csname snippet--#1/\the\i\endcsname
+    \advance \i 1
+  \ifcsname snippet--#1/\the\i\endcsname
+  \repeat
+}}
+\providecommand{\SnippetPart}[3]{{%
+  \newcount\i
+  \i=#1
+  \loop
+    \ifnum \i=#2
+      \renewcommand{\isanewline}{}%
+    \fi
+    \csname snippet--#3/\the\i\endcsname
+    \advance \i 1
+    \ifnum \i>#2 {}
+    \else \repeat
+}}"
+        .to_string(),
+    );
+
+    out.join("\n") + "\n"
+}
+
+/*
+ * Options
+ */
+
+#[derive(Default)]
+struct Config {
+    options: Options,
+    quick_and_dirty: bool,
+    require_complete: bool,
+    library: bool,
+    base: Option<String>,
+    inputs: Vec<Input>,
+    include_imports: bool,
+    tag: Option<String>,
+    preprocess: Option<String>,
+    require_names: bool,
+    strict: bool,
+    fail_on_warning: bool,
+    quiet: bool,
+    anonymize: bool,
+    labels: Vec<(String, String)>,
+    extra_imports: Vec<String>,
+    base_dir: Option<String>,
+    self_test: bool,
+    filter: Option<Pattern>,
+    save_annotated: Option<String>,
+    plan: bool,
+    list_commands: bool,
+    map_only: bool,
+    out_dir: Option<String>,
+    threads: Option<usize>,
+    provenance_comments: bool,
+    theory_ext: Option<String>,
+    theory_configs: HashMap<String, TheoryConfig>,
+    ignore_case: bool,
+    command_arg: bool,
+    header: bool,
+    no_timestamp: bool,
+    wrap_env: Option<String>,
+    inline_short: bool,
+    toc: bool,
+    raw_source: bool,
+    document_variant: Option<String>,
+    strip_markup: bool,
+    line_per_snippet: bool,
+    follow_symlinks: bool,
+    format: Format,
+    output_encoding: Encoding,
+    color: Color,
+    diff: bool,
+    manifest: Option<String>,
+    checksum: Option<String>,
+    emit_macros: Option<String>,
+    keep_temp: bool,
+    temp_parent: Option<String>,
+    no_preflight: bool,
+    retries: usize,
+    parallel_builds: usize,
+    trim_head: usize,
+    trim_tail: usize,
+    max_line_length: Option<usize>,
+    max_parts: Option<usize>,
+    only_snippet: Option<String>,
+    isabelle_prefix: Option<String>,
+    split_by_theory: bool,
+    sort: bool,
+    theory_order: Vec<String>,
+    only_ordered: bool,
+    relative_to: Option<String>,
+    since: Option<String>,
+    // The theory files changed since -since, canonicalized, if git could tell.
+    changed: Option<Vec<PathBuf>>,
+    // None runs the isabelle on the PATH.
+    isabelle: Option<Box<dyn IsabelleRunner>>,
+    // None uses the files on the disk.
+    fs: Option<Box<dyn Fs>>,
+}
+
+impl Config {
+    fn same_theory(&self, a: &OsString, b: &OsString) -> bool {
+        if self.ignore_case {
+            a.to_string_lossy().to_lowercase() == b.to_string_lossy().to_lowercase()
+        } else {
+            a == b
+        }
+    }
+
+    // The position of the snippet's theory in -theory-order, unlisted theories last.
+    fn theory_rank(&self, snippet: &Snippet) -> usize {
+        let theory = OsString::from(&snippet.theory);
+        let prefix = snippet.prefix.as_ref().map(OsString::from);
+        (self.theory_order.iter())
+            .position(|t| {
+                let t = OsString::from(t);
+                self.same_theory(&t, &theory) || prefix.as_ref().is_some_and(|p| *p == t)
+            })
+            .unwrap_or(self.theory_order.len())
+    }
+
+    // Theories missing from -theory-order are left out with -only-ordered.
+    fn excludes_theory(&self, snippet: &Snippet) -> bool {
+        self.only_ordered && self.theory_rank(snippet) == self.theory_order.len()
+    }
+
+    fn theory_ext(&self) -> &str {
+        self.theory_ext.as_deref().unwrap_or("thy")
+    }
+
+    // The output file is not needed when printing a single snippet or the map.
+    fn first_theory(&self) -> usize {
+        if self.only_snippet.is_some() || self.map_only {
+            2
+        } else {
+            3
+        }
+    }
+
+    fn runner(&self) -> &dyn IsabelleRunner {
+        self.isabelle.as_deref().unwrap_or(&SystemIsabelle)
+    }
+
+    fn fs(&self) -> &dyn Fs {
+        self.fs.as_deref().unwrap_or(&StdFs)
+    }
+
+    fn isabelle_prefix(&self) -> &str {
+        self.isabelle_prefix.as_deref().unwrap_or("  ")
+    }
+
+    // Overrides from -theory-config for a single theory input.
+    fn overrides(&self, input: &Input) -> Option<&TheoryConfig> {
+        let theory = theory_name(Path::new(&input.path), self.theory_ext())?;
+        self.theory_configs.get(theory.to_str()?)
+    }
+
+    fn base_session<'a>(&'a self, input: &'a Input) -> &'a str {
+        let overridden = self.overrides(input).and_then(|o| o.base.as_ref());
+        match (&input.base, overridden, &self.base) {
+            (Some(base), _, _) | (None, Some(base), _) | (None, None, Some(base)) => base,
+            (None, None, None) if self.library => "HOL-Library",
+            (None, None, None) => "HOL",
+        }
+    }
+
+    // Walks an input, following symlinks only with -follow-symlinks.
+    // Loops are reported by walkdir as errors instead of being walked.
+    fn walk(&self, path: &Path) -> WalkDir {
+        WalkDir::new(path).follow_links(self.follow_symlinks)
+    }
+
+    fn backend(&self) -> Backend {
+        if self.raw_source {
+            Backend::Raw
+        } else if self.format == Format::Html {
+            Backend::Html
+        } else {
+            Backend::Latex
+        }
+    }
+
+    // Isabelle writes the LaTeX of every document variant to a directory named after it.
+    // With -document-variant only that one is read, otherwise the whole working directory.
+    fn document_dir(&self, temp_path: &Path) -> PathBuf {
+        match &self.document_variant {
+            Some(variant) if self.backend() == Backend::Latex => {
+                let name = variant.split('=').next().unwrap_or_default();
+                temp_path.join("output").join(name)
+            }
+            _ => temp_path.to_path_buf(),
+        }
+    }
+
+    fn quick_and_dirty(&self, input: &Input) -> bool {
+        self.quick_and_dirty || self.overrides(input).is_some_and(|o| o.quick_and_dirty)
+    }
+}
+
+// An input is a theory file or session directory, optionally with its own base session.
+#[derive(Clone, Default)]
+struct Input {
+    path: String,
+    base: Option<String>,
+    // Further session directories for dependencies, passed to isabelle build with -d.
+    dirs: Vec<PathBuf>,
+    // Subdirectories that are not copied, since they are inputs of their own.
+    exclude: Vec<PathBuf>,
+}
+
+impl Input {
+    // Parses PATH or PATH:BASE, splitting at the last colon.
+    fn parse(s: &str) -> Input {
+        match s.rsplit_once(':') {
+            Some((path, base)) if !path.is_empty() && !base.is_empty() && !base.contains('/') => {
+                Input {
+                    path: path.to_string(),
+                    base: Some(base.to_string()),
+                    ..Default::default()
+                }
+            }
+            _ => Input {
+                path: s.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+// The directories listed in a ROOTS file, one per line.
+fn parse_roots(roots: &str) -> Vec<String> {
+    roots
+        .lines()
+        .map(|line| {
+            line.split('#')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .trim_matches('"')
+        })
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Collects the session directories below dir by following ROOTS files,
+// each with the subdirectories its ROOTS lists.
+fn session_dirs(dir: &Path, sessions: &mut Vec<(PathBuf, Vec<PathBuf>)>) {
+    let children: Vec<PathBuf> = match fs::read_to_string(dir.join("ROOTS")) {
+        Ok(roots) => parse_roots(&roots).iter().map(|d| dir.join(d)).collect(),
+        Err(_) => vec![],
+    };
+
+    if dir.join("ROOT").is_file() {
+        sessions.push((dir.to_path_buf(), children.clone()));
+    }
+
+    for child in &children {
+        if child.is_dir() {
+            session_dirs(child, sessions);
+        } else {
+            warn!("{}: listed in ROOTS, but not a directory.", child.display());
+        }
+    }
+}
+
+// A directory with a ROOTS file becomes one input per session directory.
+// The other session directories are available to each, for dependencies between them.
+fn session_inputs(input: &Input) -> Vec<Input> {
+    let dir = Path::new(&input.path);
+    if !dir.join("ROOTS").is_file() {
+        return vec![input.clone()];
+    }
+
+    let mut sessions = vec![];
+    session_dirs(dir, &mut sessions);
+
+    let absolute: Vec<PathBuf> = sessions
+        .iter()
+        .map(|(d, _)| fs::canonicalize(d).unwrap_or_else(|_| d.clone()))
+        .collect();
+
+    sessions
+        .iter()
+        .enumerate()
+        .map(|(i, (d, children))| Input {
+            path: d.display().to_string(),
+            base: input.base.clone(),
+            dirs: (absolute.iter().enumerate())
+                .filter(|(j, _)| *j != i)
+                .map(|(_, d)| d.clone())
+                .collect(),
+            exclude: children.clone(),
+        })
+        .collect()
+}
+
+// Per theory settings from -theory-config.
+#[derive(Default)]
+struct TheoryConfig {
+    base: Option<String>,
+    quick_and_dirty: bool,
+}
+
+// Parses sections like
+//
+// [Theory]
+// base = "HOL-Library"
+// quick_and_dirty = true
+//
+// which is a small subset of TOML.
+fn parse_theory_configs(s: &str) -> Result<HashMap<String, TheoryConfig>, String> {
+    let mut configs: HashMap<String, TheoryConfig> = HashMap::new();
+    let mut current = None;
 
-    if quick_and_dirty {
-        isa_args.extend(&["-o", "quick_and_dirty"]);
+    for (lineno, line) in s.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let error = |msg: &str| format!("line {}: {}", lineno + 1, msg);
+
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let theory = section.trim().trim_matches('"').to_string();
+            configs.entry(theory.clone()).or_default();
+            current = Some(theory);
+            continue;
+        }
+
+        let theory = current
+            .as_ref()
+            .ok_or_else(|| error("expected a [Theory] section first"))?;
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected key = value"))?;
+        let value = value.trim();
+        let value = match value.strip_prefix("'").and_then(|v| v.strip_suffix("'")) {
+            // A TOML literal string keeps its quotes, e.g. '"My Session"'.
+            Some(literal) => literal,
+            None => value.trim_matches('"'),
+        };
+        let config = configs.get_mut(theory).expect("Section was added.");
+
+        match option_name(key.trim()).as_str() {
+            "base" => {
+                check_session_name(value).map_err(|e| error(&e))?;
+                config.base = Some(value.to_string());
+            }
+            "quick-and-dirty" => {
+                config.quick_and_dirty =
+                    value.parse().map_err(|_| error("expected true or false"))?
+            }
+            other => return Err(error(&format!("unknown setting {}", other))),
+        }
     }
-    call_isabelle(temp_path, &isa_args).expect("Error running Isabelle build.");
 
-    println!("Extracting snippets for theories: {:?}", user_theories);
+    Ok(configs)
+}
+
+// Parses lines like `group-intro = "Group.lemma:assoc"`, mapping stable labels to the
+// snippets they stand for, given by name, optionally after their theory and a dot.
+// Empty lines and comments starting with # are skipped.
+fn parse_labels(s: &str) -> Result<Vec<(String, String)>, String> {
+    let mut labels: Vec<(String, String)> = vec![];
 
-    let snippets =
-        extract_snippets(&temp_path, &user_theories).expect("Could not extract snippets.");
+    for (lineno, line) in s.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
 
-    let snips_path = Path::new(&args[2]);
-    fs::write(snips_path, snippets).expect("Could not write to snippets file.");
+        let error = |msg: &str| format!("line {}: {}", lineno + 1, msg);
 
-    println!("Snippets written to: {}", snips_path.display());
+        let (label, locator) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected label = \"Theory.name\""))?;
+        let label = label.trim().trim_matches('"');
+        let locator = locator.trim().trim_matches('"');
+
+        if label.is_empty() || locator.is_empty() {
+            return Err(error("missing label or snippet name"));
+        }
+        if !label
+            .chars()
+            .all(|c| c.is_alphanumeric() || "-:.".contains(c))
+        {
+            return Err(error(&format!(
+                "invalid label {}, use only letters, digits and -:.",
+                label
+            )));
+        }
+        if labels.iter().any(|(l, _)| l == label) {
+            return Err(error(&format!("duplicate label {}", label)));
+        }
+
+        labels.push((label.to_string(), locator.to_string()));
+    }
+
+    Ok(labels)
+}
+
+fn comma_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+// Options may be given with one or two dashes and with either hyphens or underscores.
+fn option_name(arg: &str) -> String {
+    arg.trim_start_matches('-').replace('_', "-")
+}
+
+fn parse_args(args: &[String]) -> Result<(Config, Vec<String>), String> {
+    let mut config = Config::default();
+    let mut positional = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') || arg.len() == 1 {
+            positional.push(arg.clone());
+            continue;
+        }
+
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("Missing value for option: {}", arg))
+        };
+
+        match option_name(arg).as_str() {
+            "quick-and-dirty" => config.quick_and_dirty = true,
+            "require-complete" => config.require_complete = true,
+            "library" => config.library = true,
+            "base" => {
+                let base = value()?;
+                check_session_name(&base)?;
+                config.base = Some(base);
+            }
+            "input" => {
+                let input = Input::parse(&value()?);
+                if let Some(base) = &input.base {
+                    check_session_name(base)?;
+                }
+                config.inputs.push(input);
+            }
+            "skip-text" => config.options.skip_text = true,
+            "strip-comments" => config.options.strip_comments = true,
+            "merge-adjacent" => config.options.merge_adjacent = true,
+            "proof-steps" => config.options.proof_steps = true,
+            "split-equations" => config.options.split_equations = true,
+            "text-snippets" => config.options.text_snippets = true,
+            "whole-theory" => config.options.whole_theory = true,
+            "with-locale-header" => config.options.locale_headers = true,
+            "merge-same-command" => {
+                config.options.merge_adjacent = true;
+                config.options.merge_same_command = true;
+            }
+            "include-imports" => config.include_imports = true,
+            "tag" => config.tag = Some(value()?),
+            "require-names" => config.require_names = true,
+            "strict" => config.strict = true,
+            "fail-on-warning" => config.fail_on_warning = true,
+            "quiet" => config.quiet = true,
+            "command-arg" => config.command_arg = true,
+            "header" => config.header = true,
+            "no-timestamp" => config.no_timestamp = true,
+            "wrap-env" => config.wrap_env = Some(value()?),
+            "inline-short" => config.inline_short = true,
+            "toc" => config.toc = true,
+            "provenance-comments" => config.provenance_comments = true,
+            "raw-source" => config.raw_source = true,
+            "breakers" => config
+                .options
+                .breakers
+                .extend(value()?.chars().filter(|c| !c.is_whitespace() && *c != ',')),
+            "format" => {
+                config.format = match value()?.as_str() {
+                    "latex" | "tex" => Format::Latex,
+                    "markdown" | "md" => Format::Markdown,
+                    "rst" | "restructuredtext" => Format::Rst,
+                    "html" => Format::Html,
+                    other => return Err(format!("Unknown format: {}", other)),
+                }
+            }
+            "output-encoding" => {
+                let v = value()?;
+                config.output_encoding =
+                    Encoding::parse(&v).ok_or_else(|| format!("Unknown output encoding: {}", v))?;
+            }
+            "color" => {
+                config.color = match value()?.as_str() {
+                    "auto" => Color::Auto,
+                    "always" => Color::Always,
+                    "never" => Color::Never,
+                    other => return Err(format!("Unknown color setting: {}", other)),
+                }
+            }
+            "diff" => config.diff = true,
+            "keep-temp" => config.keep_temp = true,
+            "temp-parent" => config.temp_parent = Some(value()?),
+            "save-annotated" => config.save_annotated = Some(value()?),
+            "plan" => config.plan = true,
+            "list-commands" => config.list_commands = true,
+            "map-only" => config.map_only = true,
+            "out-dir" => config.out_dir = Some(value()?),
+            "self-test" => config.self_test = true,
+            "no-preflight" => config.no_preflight = true,
+            "threads" => {
+                let threads = value()?;
+                config.threads = Some(if threads == "auto" {
+                    0
+                } else {
+                    threads
+                        .parse()
+                        .map_err(|_| format!("Not a number of threads: {}", threads))?
+                })
+            }
+            "parallel-builds" => {
+                let builds = value()?;
+                config.parallel_builds = builds
+                    .parse()
+                    .map_err(|_| format!("Not a number of parallel builds: {}", builds))?
+            }
+            "max-line-length" => {
+                let length = value()?;
+                config.max_line_length = Some(
+                    length
+                        .parse()
+                        .map_err(|_| format!("Not a number of characters: {}", length))?,
+                );
+            }
+            "max-parts" => {
+                let parts = value()?;
+                config.max_parts = Some(
+                    (parts.parse().ok())
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| format!("Not a positive number of parts: {}", parts))?,
+                );
+            }
+            "retries" => {
+                let retries = value()?;
+                config.retries = retries
+                    .parse()
+                    .map_err(|_| format!("Not a number of retries: {}", retries))?
+            }
+            "trim-head" => {
+                let lines = value()?;
+                config.trim_head = lines
+                    .parse()
+                    .map_err(|_| format!("Not a number of lines: {}", lines))?
+            }
+            "trim-tail" => {
+                let lines = value()?;
+                config.trim_tail = lines
+                    .parse()
+                    .map_err(|_| format!("Not a number of lines: {}", lines))?
+            }
+            "isabelle-prefix" => config.isabelle_prefix = Some(value()?),
+            "split-by-theory" => config.split_by_theory = true,
+            "sort" => config.sort = true,
+            "theory-order" => config.theory_order.extend(comma_list(&value()?)),
+            "only-ordered" => config.only_ordered = true,
+            "only-commands" => config.options.only_commands.extend(comma_list(&value()?)),
+            "skip-commands" => config.options.skip_commands.extend(comma_list(&value()?)),
+            "manifest" => config.manifest = Some(value()?),
+            "checksum" => config.checksum = Some(value()?),
+            "anonymize" => config.anonymize = true,
+            "emit-macros" => config.emit_macros = Some(value()?),
+            "labels" => {
+                let path = value()?;
+                let table = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+                config
+                    .labels
+                    .extend(parse_labels(&table).map_err(|e| format!("{}: {}", path, e))?);
+            }
+            "theory-config" => {
+                let path = value()?;
+                let table = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+                config
+                    .theory_configs
+                    .extend(parse_theory_configs(&table).map_err(|e| format!("{}: {}", path, e))?);
+            }
+            "commands-file" => {
+                let path = value()?;
+                let table = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+                config
+                    .options
+                    .commands
+                    .extend(parse_commands(&table).map_err(|e| format!("{}: {}", path, e))?);
+            }
+            "only-snippet" => config.only_snippet = Some(value()?),
+            "filter" => {
+                let source = value()?;
+                let pattern = Pattern::new(&source)
+                    .map_err(|e| format!("Invalid pattern for -filter {}: {}", source, e))?;
+                config.filter = Some(pattern);
+            }
+            "relative-to" => config.relative_to = Some(value()?),
+            "since" => config.since = Some(value()?),
+            "preprocess" => config.preprocess = Some(value()?),
+            "strip-markup" => config.strip_markup = true,
+            "line-per-snippet" => config.line_per_snippet = true,
+            "follow-symlinks" => config.follow_symlinks = true,
+            "document-variant" => {
+                let variant = value()?;
+                if variant.is_empty() || variant.contains(':') {
+                    return Err(format!("Not a single document variant: {}", variant));
+                }
+                config.document_variant = Some(variant);
+            }
+            "extra-import" => config.extra_imports.push(value()?),
+            "base-dir" => config.base_dir = Some(value()?),
+            "ignore-case" => config.ignore_case = true,
+            "theory-ext" => config.theory_ext = Some(value()?.trim_start_matches('.').to_string()),
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+    }
+
+    // An output template always means one file per theory.
+    if config.only_snippet.is_none() && positional.get(2).is_some_and(|out| is_template(out)) {
+        config.split_by_theory = true;
+    }
+
+    Ok((config, positional))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let (mut config, args) = match parse_args(&args) {
+        Ok(parsed) => parsed,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            exit(1);
+        }
+    };
+
+    COLOR.store(config.color.enabled(), Ordering::Relaxed);
+    QUIET.store(config.quiet, Ordering::Relaxed);
+
+    if config.document_variant.is_some() && config.backend() != Backend::Latex {
+        warn!(
+            "-document-variant is ignored with -raw-source and -format html, no document is built."
+        );
+    }
+
+    match config.max_line_length {
+        Some(_) if !config.raw_source => {
+            warn!("-max-line-length only wraps the source lines of -raw-source.")
+        }
+        Some(max) if max < MIN_LINE_LENGTH => warn!(
+            "-max-line-length {} is below {}, the lines are not wrapped.",
+            max, MIN_LINE_LENGTH
+        ),
+        _ => {}
+    }
+
+    // Needs no input, but takes -commands-file into account.
+    if config.list_commands {
+        let table = config.options.command_table();
+        let width = table.iter().map(|(cmd, _)| cmd.len()).max().unwrap_or(0);
+        for (cmd, typ) in table {
+            println!("{:width$}  {:?}", cmd, typ, width = width);
+        }
+        exit(0);
+    }
+
+    if args.len() < config.first_theory() && !config.self_test {
+        eprintln!(
+            "Usage: ./{} theory/root snippets-out.tex [optional list of theories to include]",
+            args[0]
+        );
+        exit(1);
+    }
+
+    let isa_path = Path::new(args.get(1).map_or("", |s| s.as_str()));
+    if !config.self_test && !isa_path.exists() {
+        let msg = format!(
+            "The given Isabelle file or directory does not exist: {}",
+            isa_path.display()
+        );
+        eprintln!("{}", paint(RED, &msg));
+        exit(1);
+    }
+
+    if let Some(since) = &config.since {
+        let dir = if isa_path.is_dir() {
+            isa_path
+        } else {
+            isa_path.parent().unwrap_or(Path::new("."))
+        };
+        config.changed = changed_theories(since, dir, config.theory_ext());
+        match &config.changed {
+            Some(changed) => info!("{} theories changed since {}", changed.len(), since),
+            None => warn!(
+                "Could not ask git for the theories changed since {}, processing all of them.",
+                since
+            ),
+        }
+    }
+
+    if config.plan {
+        if let Err(e) = plan(&config, &args, &mut io::stdout()) {
+            eprintln!("{}", paint(RED, &format!("Error: {}", e)));
+            exit(1);
+        }
+        exit(0);
+    }
+
+    if config.map_only {
+        if let Err(e) = map_only(&config, &args, &mut io::stdout()) {
+            eprintln!("{}", paint(RED, &format!("Error: {}", e)));
+            exit(1);
+        }
+        exit(0);
+    }
+
+    let temp_dir = match working_dir(&config) {
+        Ok(temp_dir) => temp_dir,
+        Err(e) => {
+            eprintln!("{}", paint(RED, &format!("Error: {}", e)));
+            exit(1);
+        }
+    };
+
+    info!("Working directory: {}", temp_dir.path().display());
+
+    let result = if config.self_test {
+        self_test(&config, temp_dir.path())
+    } else {
+        run(&config, &args, temp_dir.path())
+    };
+
+    // Clean up explicitly, since exiting skips the destructor.
+    if config.keep_temp {
+        info!("Keeping working directory: {}", temp_dir.keep().display());
+    } else if let Err(e) = temp_dir.close() {
+        warn!("Could not remove working directory: {}", e);
+    }
+
+    if result.is_ok() && !config.self_test && config.only_snippet.is_none() {
+        let summary = summary(
+            BUILT_THEORIES.load(Ordering::Relaxed),
+            BUILD_MILLIS.load(Ordering::Relaxed),
+            SNIPPETS.load(Ordering::Relaxed),
+            config.diff,
+        );
+        info!("{}", summary);
+    }
+
+    let warnings = WARNINGS.load(Ordering::Relaxed);
+    let (code, error) = exit_code(result, warnings, config.fail_on_warning);
+    if let Some(error) = error {
+        eprintln!("{}", paint(RED, &format!("Error: {}", error)));
+    }
+    exit(code);
+}
+
+// The exit code of a run with the given number of warnings, and the error to report, if any.
+fn exit_code(
+    result: io::Result<i32>,
+    warnings: usize,
+    fail_on_warning: bool,
+) -> (i32, Option<String>) {
+    match result {
+        Ok(0) if fail_on_warning && warnings > 0 => (
+            1,
+            Some(format!("{} warning(s) with -fail-on-warning", warnings)),
+        ),
+        Ok(code) => (code, None),
+        Err(e) => (1, Some(e.to_string())),
+    }
+}
+
+// The working directory, below -temp-parent if given, or else the system's, which honors TMPDIR.
+fn working_dir(config: &Config) -> io::Result<TempDir> {
+    let parent = match &config.temp_parent {
+        Some(parent) => Path::new(parent),
+        None => return with_context(tempdir(), "Could not create a temporary directory"),
+    };
+    if !parent.is_dir() {
+        return Err(io::Error::other(format!(
+            "The directory for -temp-parent does not exist: {}",
+            parent.display()
+        )));
+    }
+    with_context(
+        tempdir_in(parent),
+        &format!(
+            "Could not create a temporary directory in {}",
+            parent.display()
+        ),
+    )
+}
+
+// Like 'Built 5 theories in 42.1s, wrote 87 snippets', from the counts of the run.
+fn summary(theories: usize, millis: u64, snippets: usize, diff: bool) -> String {
+    let seconds = millis as f64 / 1000.0;
+    format!(
+        "Built {} {} in {:.1}s, {} {} {}",
+        theories,
+        if theories == 1 { "theory" } else { "theories" },
+        seconds,
+        if diff { "checked" } else { "wrote" },
+        snippets,
+        if snippets == 1 { "snippet" } else { "snippets" }
+    )
+}
+
+// A small theory that any Isabelle installation can build, and the snippets it must give.
+// The section is named by a hash, so only its command is checked.
+const SELF_TEST: &str = include_str!("SelfTest.thy");
+const SELF_TEST_SNIPPETS: [&str; 4] = [
+    "theory:SelfTest",
+    "section:",
+    "definition:double",
+    "lemma:double-add",
+];
+
+// Runs the whole pipeline on the bundled theory, with -self-test. Returns the exit code.
+fn self_test(config: &Config, temp_path: &Path) -> io::Result<i32> {
+    let sample_path = temp_path.join("sample");
+    let build_path = temp_path.join("build");
+    fs::create_dir_all(&sample_path)?;
+    fs::create_dir_all(&build_path)?;
+
+    let thy_path = sample_path.join("SelfTest.thy");
+    fs::write(&thy_path, SELF_TEST)?;
+    let input = Input {
+        path: thy_path.display().to_string(),
+        ..Default::default()
+    };
+
+    let snippets = build_input(&input, vec![], false, config, &build_path)?;
+    let names: Vec<String> = (snippets.iter())
+        .map(|s| base_name(&s.prefix, &s.name))
+        .collect();
+    let missing: Vec<&str> = (SELF_TEST_SNIPPETS.iter())
+        .filter(|expected| !names.iter().any(|n| n.starts_with(*expected)))
+        .copied()
+        .collect();
+
+    if missing.is_empty() {
+        eprintln!("Self-test passed, found snippets: {}", names.join(", "));
+        Ok(0)
+    } else {
+        eprintln!(
+            "{}",
+            paint(
+                RED,
+                &format!(
+                    "Self-test failed, missing snippets: {} (found: {})",
+                    missing.join(", "),
+                    names.join(", ")
+                )
+            )
+        );
+        Ok(1)
+    }
+}
+
+fn with_context<T>(result: io::Result<T>, msg: &str) -> io::Result<T> {
+    result.map_err(|e| io::Error::other(format!("{}: {}", msg, e)))
+}
+
+// The arguments of isabelle for building an input in its working directory.
+fn build_args(input: &Input, config: &Config) -> Vec<String> {
+    let mut isa_args: Vec<String> = ["build", "-c", "-D", "."]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+
+    // Raw source is read from the theories, so no document and no LaTeX is needed.
+    let document: &[&str] = match config.backend() {
+        Backend::Raw => &["-o", "document=false"],
+        Backend::Html => &["-o", "document=false", "-P", "output"],
+        Backend::Latex => &["-o", "document=pdf", "-o", "document_output=output"],
+    };
+    isa_args.extend(document.iter().map(|a| a.to_string()));
+    let latex = config.backend() == Backend::Latex;
+    if let Some(variant) = config.document_variant.as_ref().filter(|_| latex) {
+        isa_args.extend(["-o".to_string(), format!("document_variants={}", variant)]);
+    }
+
+    if config.quick_and_dirty(input) {
+        isa_args.extend(["-o".to_string(), "quick_and_dirty".to_string()]);
+    }
+
+    for dir in &input.dirs {
+        isa_args.extend(["-d".to_string(), dir.display().to_string()]);
+    }
+
+    if let Some(threads) = config.threads {
+        let (threads, jobs) = thread_split(threads, available_cpus());
+        isa_args.extend([
+            "-o".to_string(),
+            format!("threads={}", threads),
+            "-j".to_string(),
+            jobs.to_string(),
+        ]);
+    }
+    isa_args
+}
+
+fn available_cpus() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+// With -threads N, every session is checked with N threads, and as many sessions are
+// built in parallel as fit on the CPUs. With -threads 0 (or auto), one session uses all.
+fn thread_split(threads: usize, cpus: usize) -> (usize, usize) {
+    let threads = if threads == 0 { cpus } else { threads };
+    (threads, (cpus / threads).max(1))
+}
+
+// Runs the full pipeline on a single input in its own working directory.
+fn build_input(
+    input: &Input,
+    mut user_theories: Vec<OsString>,
+    disambiguate: bool,
+    config: &Config,
+    temp_path: &Path,
+) -> io::Result<Vec<Snippet>> {
+    let isa_path = Path::new(&input.path);
+    let dirs: Vec<String> = input.dirs.iter().map(|d| d.display().to_string()).collect();
+
+    if isa_path.is_file() && !with_context(has_tag(isa_path, config), &input.path)? {
+        info!(
+            "Skipping {}, it does not have the tag {}.",
+            input.path,
+            config.tag.as_deref().unwrap_or_default()
+        );
+        return Ok(vec![]);
+    }
+
+    // Snippets of unchecked proofs must not end up in a final build by accident.
+    if config.quick_and_dirty(input) {
+        if config.require_complete {
+            return Err(io::Error::other(format!(
+                "{}: quick_and_dirty is enabled, which -require-complete does not allow",
+                input.path
+            )));
+        }
+        warn!(
+            "{}: quick_and_dirty is enabled, proofs with sorry are accepted and the snippets may show unchecked proofs.",
+            input.path
+        );
+    }
+
+    if isa_path.is_file() {
+        if !config.no_preflight {
+            check_base_session(config.runner(), config.base_session(input), &dirs)?;
+        }
+        let theories = with_context(
+            mkroot(isa_path, temp_path, config.base_session(input), config),
+            "Could not make theory root directory",
+        )?;
+        user_theories.extend(theories);
+    } else {
+        if !config.extra_imports.is_empty() {
+            warn!("-extra-import is ignored for sessions, add the theory to the ROOT instead.");
+        }
+        if input.base.is_some() {
+            warn!(
+                "{}: the base session is ignored for sessions, it is given by the ROOT.",
+                input.path
+            );
+        }
+
+        if config.include_imports && !user_theories.is_empty() {
+            user_theories = with_imports(isa_path, user_theories, &input.exclude, config);
+        }
+
+        let processed = with_context(
+            copy_isabelle(isa_path, temp_path, &user_theories, &input.exclude, config),
+            "Could not copy Isabelle files",
+        )?;
+        // The processed theories have the names on disk, also with -ignore-case.
+        user_theories = processed;
+    }
+
+    let isa_args = build_args(input, config);
+    let isa_args: Vec<&str> = isa_args.iter().map(|a| a.as_str()).collect();
+
+    let started = Instant::now();
+    with_context(
+        call_isabelle_retrying(
+            config.runner(),
+            temp_path,
+            &isa_args,
+            config.isabelle_prefix(),
+            config.retries,
+        ),
+        "Could not run Isabelle build",
+    )?;
+    BUILD_MILLIS.fetch_add(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    BUILT_THEORIES.fetch_add(user_theories.len(), Ordering::Relaxed);
+
+    info!("Extracting snippets for theories: {:?}", user_theories);
+
+    let prefixes = match &config.relative_to {
+        Some(dir) => path_prefixes(isa_path, Path::new(dir), config),
+        None => HashMap::new(),
+    };
+
+    let document_dir = config.document_dir(temp_path);
+    if !document_dir.is_dir() {
+        return Err(io::Error::other(format!(
+            "Isabelle wrote no document to {}",
+            document_dir.display()
+        )));
+    }
+
+    let mut snippets = with_context(
+        extract_snippets(
+            config.fs(),
+            &document_dir,
+            &user_theories,
+            disambiguate || user_theories.len() > 1,
+            config.backend(),
+            &prefixes,
+        ),
+        "Could not extract snippets",
+    )?;
+
+    // Lines left empty are dropped, since an empty line would end the paragraph.
+    if config.strip_markup && config.backend() == Backend::Latex {
+        for part in snippets.iter_mut().flat_map(|s| s.parts.iter_mut()) {
+            *part = (part.iter())
+                .filter_map(|line| {
+                    let stripped = strip_markup(line);
+                    let emptied = stripped.trim().is_empty() && !line.trim().is_empty();
+                    (!emptied).then_some(stripped)
+                })
+                .collect();
+        }
+    }
+
+    if config.line_per_snippet {
+        for snippet in &mut snippets {
+            snippet.split_source_lines();
+        }
+    }
+
+    if config.options.text_snippets {
+        for snippet in &mut snippets {
+            if TEXTS.contains(&snippet.cmd.as_str()) {
+                snippet.unwrap_text(config.raw_source);
+            }
+        }
+    }
+
+    // A whole theory is included at once, so it is kept as a single part.
+    // Markdown writes every part as a line, so there the parts stay.
+    if config.options.whole_theory && config.format == Format::Latex {
+        for snippet in &mut snippets {
+            snippet.parts = vec![snippet.parts.concat()];
+        }
+    }
+
+    if config.options.proof_steps {
+        accumulate_steps(&mut snippets, config.raw_source);
+    }
+
+    if config.options.split_equations {
+        snippets = join_equations(snippets, config.raw_source);
+    }
+
+    for snippet in &mut snippets {
+        trim_snippet(
+            snippet,
+            config.trim_head,
+            config.trim_tail,
+            config.raw_source,
+        );
+    }
+
+    // Every source line is a part, so a wrapped line becomes several parts.
+    if let Some(max) = config.max_line_length.filter(|_| config.raw_source) {
+        for snippet in &mut snippets {
+            snippet.parts = (snippet.parts.iter())
+                .flat_map(|part| part.iter().flat_map(|line| wrap_line(line, max)))
+                .map(|line| vec![line])
+                .collect();
+        }
+    }
+
+    if let Some(max) = config.max_parts {
+        for snippet in &mut snippets {
+            cap_parts(snippet, max);
+        }
+    }
+
+    // Usually the command classification or -only-commands and -skip-commands are to blame.
+    for theory in &user_theories {
+        if !snippets.iter().any(|s| *theory == *s.theory) {
+            let msg = format!("{}: theory {:?} produced no snippets.", input.path, theory);
+            if config.strict {
+                return Err(io::Error::other(msg));
+            }
+            warn!("{}", msg);
+        }
+    }
+
+    if let Some(filter) = &config.filter {
+        snippets.retain(|s| filter.is_match(&base_name(&s.prefix, &s.name)));
+    }
+
+    Ok(snippets)
+}
+
+// The inputs, with whether they come from the first one.
+fn run_inputs(config: &Config, args: &[String]) -> Vec<(Input, bool)> {
+    let first = Input {
+        path: args[1].clone(),
+        ..Default::default()
+    };
+
+    // The list of theories applies to the sessions of the first input only.
+    let mut inputs: Vec<(Input, bool)> = session_inputs(&first)
+        .into_iter()
+        .map(|input| (input, true))
+        .collect();
+    for input in &config.inputs {
+        inputs.extend(
+            session_inputs(input)
+                .into_iter()
+                .map(|input| (input, false)),
+        );
+    }
+    inputs
+}
+
+// The listed theories for one of several inputs, or None if it is to be skipped.
+// With -since, only the changed theories are listed.
+fn input_theories(
+    input: &Input,
+    is_first: bool,
+    user_theories: &[OsString],
+    config: &Config,
+) -> Option<Vec<OsString>> {
+    let listed = listed_theories(input, is_first, user_theories, config)?;
+    let changed = match &config.changed {
+        Some(changed) => changed,
+        None => return Some(listed),
+    };
+
+    let isa_path = Path::new(&input.path);
+    let path = fs::canonicalize(isa_path).ok()?;
+    if isa_path.is_file() {
+        return changed.contains(&path).then_some(listed);
+    }
+
+    let changed: Vec<OsString> = (changed.iter())
+        .filter(|p| p.starts_with(&path) && !input.exclude.iter().any(|x| p.starts_with(x)))
+        .filter_map(|p| theory_name(p, config.theory_ext()))
+        .filter(|thy| listed.is_empty() || listed.iter().any(|l| config.same_theory(l, thy)))
+        .collect();
+    (!changed.is_empty()).then_some(changed)
+}
+
+fn listed_theories(
+    input: &Input,
+    is_first: bool,
+    user_theories: &[OsString],
+    config: &Config,
+) -> Option<Vec<OsString>> {
+    if !is_first {
+        return Some(vec![]);
+    }
+    if input.dirs.is_empty() || user_theories.is_empty() {
+        return Some(user_theories.to_vec());
+    }
+
+    // Only the listed theories that belong to this session of a ROOTS hierarchy.
+    let present = dir_theories(Path::new(&input.path), &input.exclude, config);
+    let listed: Vec<_> = (user_theories.iter())
+        .filter(|thy| present.iter().any(|p| config.same_theory(p, thy)))
+        .cloned()
+        .collect();
+    (!listed.is_empty()).then_some(listed)
+}
+
+// The theory files changed since the given git revision, by git diff --name-only run in dir,
+// or None if git cannot tell, e.g. outside of a repository.
+fn changed_theories(since: &str, dir: &Path, ext: &str) -> Option<Vec<PathBuf>> {
+    let git = |args: &[&str]| {
+        let output = Command::new("git")
+            .current_dir(dir)
+            .args(args)
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        (output.status.success()).then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+
+    // The names are relative to the top of the repository.
+    let top = git(&["rev-parse", "--show-toplevel"])?;
+    let names = git(&["diff", "--name-only", since, "--"])?;
+    Some(
+        (names.lines())
+            .map(|name| Path::new(top.trim()).join(name))
+            .filter(|path| has_ext(path, ext))
+            .filter_map(|path| fs::canonicalize(path).ok())
+            .collect(),
+    )
+}
+
+// The theories of a session that would be processed, as copy_isabelle selects them,
+// with their paths.
+fn planned_theories(
+    input: &Input,
+    user_theories: Vec<OsString>,
+    config: &Config,
+) -> io::Result<Vec<(PathBuf, OsString)>> {
+    let isa_path = Path::new(&input.path);
+    let user_theories = if config.include_imports && !user_theories.is_empty() {
+        with_imports(isa_path, user_theories, &input.exclude, config)
+    } else {
+        user_theories
+    };
+
+    let mut theories = vec![];
+    for entry in config
+        .walk(isa_path)
+        .into_iter()
+        .filter_entry(|e| !input.exclude.iter().any(|x| x == e.path()))
+        .filter_map(|e| e.ok())
+    {
+        if let Some(theory) = theory_name(entry.path(), config.theory_ext()) {
+            let selected = user_theories.is_empty()
+                || user_theories.iter().any(|t| config.same_theory(t, &theory));
+            if selected && has_tag(entry.path(), config)? {
+                theories.push((entry.path().to_path_buf(), theory));
+            }
+        }
+    }
+    Ok(theories)
+}
+
+// Prints what would be built and written, with -plan, without running Isabelle or writing files.
+fn plan(config: &Config, args: &[String], out: &mut dyn Write) -> io::Result<()> {
+    let user_theories: Vec<OsString> = (args.iter().skip(config.first_theory()))
+        .map(OsString::from)
+        .collect();
+    let names = |theories: &[OsString]| {
+        let names: Vec<String> = (theories.iter())
+            .map(|t| t.to_string_lossy().into_owned())
+            .collect();
+        names.join(", ")
+    };
+
+    let inputs = run_inputs(config, args);
+    for (input, is_first) in &inputs {
+        writeln!(out, "Input: {}", input.path)?;
+        let theories = match input_theories(input, *is_first, &user_theories, config) {
+            Some(theories) => theories,
+            None => {
+                if config.changed.is_some() {
+                    writeln!(
+                        out,
+                        "  Skipped, none of the listed theories changed or belong to it"
+                    )?;
+                } else {
+                    writeln!(out, "  Skipped, none of the listed theories belong to it")?;
+                }
+                continue;
+            }
+        };
+
+        let isa_path = Path::new(&input.path);
+        if isa_path.is_file() {
+            if !has_tag(isa_path, config)? {
+                writeln!(
+                    out,
+                    "  Skipped, it does not have the tag {}",
+                    config.tag.as_deref().unwrap_or_default()
+                )?;
+                continue;
+            }
+            let mut theories = vec![theory_stem(isa_path, config)];
+            if config.include_imports {
+                let imports = import_closure(&[isa_path.to_path_buf()], config.theory_ext());
+                theories.extend(
+                    (imports.iter()).filter_map(|import| theory_name(import, config.theory_ext())),
+                );
+            }
+            writeln!(out, "  Theories: {}", names(&theories))?;
+            writeln!(out, "  ROOT:")?;
+            let root = make_root(
+                &root_theories(isa_path, config)?,
+                config.base_session(input),
+            );
+            for line in root.lines() {
+                writeln!(out, "{}", format!("    {}", line).trim_end())?;
+            }
+        } else {
+            writeln!(out, "  Session directory, with its own ROOT")?;
+            writeln!(
+                out,
+                "  Theories: {}",
+                names(
+                    &(planned_theories(input, theories, config)?)
+                        .into_iter()
+                        .map(|(_, theory)| theory)
+                        .collect::<Vec<_>>()
+                )
+            )?;
+        }
+        writeln!(
+            out,
+            "  Command: isabelle {}",
+            build_args(input, config).join(" ")
+        )?;
+    }
+
+    if let Some(name) = &config.only_snippet {
+        writeln!(out, "Output: the snippet {} on standard output", name)?;
+    } else if is_template(&args[2]) {
+        writeln!(out, "Output: one file per theory, named by {}", args[2])?;
+    } else if config.split_by_theory {
+        writeln!(out, "Output: one file per theory in {}", args[2])?;
+    } else {
+        writeln!(out, "Output: {}", args[2])?;
+    }
+    if let Some(manifest) = &config.manifest {
+        writeln!(out, "Manifest: {}", manifest)?;
+    }
+    if let Some(macros) = &config.emit_macros {
+        writeln!(out, "Macros: {}", macros)?;
+    }
+    Ok(())
+}
+
+// The theory files of an input that -map-only annotates, with their names.
+fn map_theories(
+    input: &Input,
+    user_theories: Vec<OsString>,
+    config: &Config,
+) -> io::Result<Vec<(PathBuf, OsString)>> {
+    let isa_path = Path::new(&input.path);
+    if !isa_path.is_file() {
+        return planned_theories(input, user_theories, config);
+    }
+    if !has_tag(isa_path, config)? {
+        return Ok(vec![]);
+    }
+
+    let mut theories = vec![(isa_path.to_path_buf(), theory_stem(isa_path, config))];
+    if config.include_imports {
+        let imports = import_closure(&[isa_path.to_path_buf()], config.theory_ext());
+        theories.extend(imports.into_iter().filter_map(|import| {
+            let theory = theory_name(&import, config.theory_ext())?;
+            Some((import, theory))
+        }));
+    }
+    Ok(theories)
+}
+
+// Like '[{"theory": "Foo", "command": "lemma", "name": "lemma:foo", "start_line": 3, "end_line": 4}]'.
+fn render_map(snippets: &[Snippet]) -> String {
+    let entries: Vec<String> = (snippets.iter())
+        .map(|snippet| {
+            let mut fields = vec![
+                format!("\"theory\": {}", json_string(&snippet.theory)),
+                format!("\"command\": {}", json_string(&snippet.cmd)),
+                format!("\"name\": {}", json_string(&snippet.name)),
+            ];
+            if let Some((start, end)) = snippet.source_lines {
+                fields.push(format!("\"start_line\": {}, \"end_line\": {}", start, end));
+            }
+            format!("  {{{}}}", fields.join(", "))
+        })
+        .collect();
+
+    if entries.is_empty() {
+        "[]\n".to_string()
+    } else {
+        format!("[\n{}\n]\n", entries.join(",\n"))
+    }
+}
+
+// With -map-only, the theories are annotated as for a build, but nothing is built or extracted.
+// The snippets they would give are printed as JSON, and with -out-dir the annotated
+// theories are written to it, at their paths below the input.
+fn map_only(config: &Config, args: &[String], out: &mut dyn Write) -> io::Result<()> {
+    let user_theories: Vec<OsString> = (args.iter().skip(config.first_theory()))
+        .map(OsString::from)
+        .collect();
+
+    let mut snippets = vec![];
+    for (input, is_first) in run_inputs(config, args) {
+        let theories = match input_theories(&input, is_first, &user_theories, config) {
+            Some(theories) => theories,
+            None => continue,
+        };
+
+        for (thy_path, theory) in map_theories(&input, theories, config)? {
+            let annotated = with_context(
+                process_theory(&thy_path, config),
+                &format!("Could not annotate {}", thy_path.display()),
+            )?;
+
+            if let Some(dir) = &config.out_dir {
+                let relative = match thy_path.strip_prefix(&input.path) {
+                    Ok(relative) if relative != Path::new("") => relative,
+                    _ => Path::new(thy_path.file_name().unwrap_or_default()),
+                };
+                let out_path = Path::new(dir).join(relative);
+                if let Some(parent) = out_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                with_context(
+                    fs::write(&out_path, &annotated),
+                    "Could not write annotated theory",
+                )?;
+                info!("Annotated theory written to: {}", out_path.display());
+            }
+
+            let lines: Lines = annotated.lines().map(String::from).collect();
+            snippets.extend(extract_raw(&theory.to_string_lossy(), &lines, &None));
+        }
+    }
+
+    write!(out, "{}", render_map(&snippets))
+}
+
+// With -labels, the snippets named in the file get their stable labels instead,
+// and keep their current name as the original name.
+fn apply_labels(snippets: &mut [Snippet], config: &Config) {
+    let mut labelled = vec![false; snippets.len()];
+
+    for (label, locator) in &config.labels {
+        // Theory names cannot contain a colon, but snippet names can contain dots.
+        let (theory, name) = match locator.split_once('.') {
+            Some((theory, name)) if !theory.contains(':') => (Some(OsString::from(theory)), name),
+            _ => (None, locator.as_str()),
+        };
+        let name = escape_underscores(name);
+
+        let found: Vec<usize> = (0..snippets.len())
+            .filter(|&i| {
+                let snippet = &snippets[i];
+                snippet.name == name
+                    && theory
+                        .as_ref()
+                        .is_none_or(|t| config.same_theory(t, &OsString::from(&snippet.theory)))
+            })
+            .collect();
+
+        match found[..] {
+            [] => warn!(
+                "label {}: no snippet {}, it may have been renamed or removed.",
+                label, locator
+            ),
+            [i] if labelled[i] => warn!(
+                "label {}: the snippet {} already has another label.",
+                label, locator
+            ),
+            [i] => {
+                let snippet = &mut snippets[i];
+                snippet.original_name = Some(base_name(&snippet.prefix, &snippet.name));
+                snippet.name = label.clone();
+                snippet.prefix = None;
+                labelled[i] = true;
+            }
+            _ => warn!(
+                "label {}: {} is in several theories, write it as Theory.{}.",
+                label, locator, locator
+            ),
+        }
+    }
+}
+
+// With -parallel-builds, up to that many inputs are built at once, each in its own
+// working directory. Isabelle's output is labelled by the input, since the lines interleave.
+// The results are in the order of the jobs.
+fn build_parallel(
+    jobs: &[(&Input, Vec<OsString>, PathBuf)],
+    config: &Config,
+) -> Vec<io::Result<Vec<Snippet>>> {
+    // The summary reports the time until all builds are done, not their sum.
+    let millis = BUILD_MILLIS.load(Ordering::Relaxed);
+    let started = Instant::now();
+
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<io::Result<Vec<Snippet>>>>> =
+        jobs.iter().map(|_| Mutex::new(None)).collect();
+
+    thread::scope(|scope| {
+        for _ in 0..config.parallel_builds.min(jobs.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let (input, theories, input_path) = match jobs.get(i) {
+                    Some(job) => job,
+                    None => break,
+                };
+
+                BUILD_LABEL.with(|label| *label.borrow_mut() = format!("[{}] ", input.path));
+                let result = build_input(input, theories.clone(), true, config, input_path);
+                *results[i].lock().expect("A build panicked.") = Some(result);
+            });
+        }
+    });
+    BUILD_MILLIS.store(
+        millis + started.elapsed().as_millis() as u64,
+        Ordering::Relaxed,
+    );
+
+    results
+        .into_iter()
+        .map(|r| {
+            r.into_inner()
+                .expect("A build panicked.")
+                .expect("Every job is built.")
+        })
+        .collect()
+}
+
+// Returns the exit code.
+fn run(config: &Config, args: &[String], temp_path: &Path) -> io::Result<i32> {
+    let user_theories = args
+        .iter()
+        .skip(config.first_theory())
+        .map(OsString::from)
+        .collect::<Vec<_>>();
+
+    let inputs = run_inputs(config, args);
+
+    // There is no output file when printing a single snippet.
+    let snips_path = Path::new(if config.only_snippet.is_some() {
+        ""
+    } else {
+        &args[2]
+    });
+    if config.only_snippet.is_none() && !config.split_by_theory {
+        if let Some(msg) = output_ext_warning(snips_path, config.format) {
+            warn!("{}", msg);
+        }
+    }
+
+    let header = config.header.then(|| {
+        let mut sources = vec![args[1].clone()];
+        sources.extend(config.inputs.iter().map(|input| input.path.clone()));
+        make_header(&sources, config)
+    });
+
+    // With several inputs, the files of each theory are written as soon as its input is built,
+    // so they survive a failure in a later input. Anonymous names are only known at the end.
+    let streaming = config.split_by_theory
+        && config.only_snippet.is_none()
+        && inputs.len() > 1
+        && !config.anonymize
+        && config.labels.is_empty();
+    if config.split_by_theory && config.only_snippet.is_none() && !is_template(&args[2]) {
+        with_context(
+            fs::create_dir_all(snips_path),
+            "Could not create snippets directory",
+        )?;
+    }
+    let mut changed = false;
+
+    let unchanged = || {
+        let since = config.since.as_deref().unwrap_or_default();
+        info!("No theories changed since {}, nothing to do.", since);
+        Ok(0)
+    };
+
+    let mut snippets = if inputs.len() == 1 {
+        let (input, is_first) = &inputs[0];
+        if config.changed.is_none() {
+            build_input(input, user_theories, false, config, temp_path)?
+        } else {
+            let theories = match input_theories(input, *is_first, &user_theories, config) {
+                Some(theories) => theories,
+                None => return unchanged(),
+            };
+            // The changed theories keep the prefixes they get when all theories are built.
+            let isa_path = Path::new(&input.path);
+            let all = if user_theories.is_empty() && isa_path.is_dir() {
+                dir_theories(isa_path, &input.exclude, config).len()
+            } else {
+                user_theories.len()
+            };
+            build_input(input, theories, all > 1, config, temp_path)?
+        }
+    } else {
+        // Every input gets its own working directory and snippets are always prefixed.
+        let mut jobs = vec![];
+        for (i, (input, is_first)) in inputs.iter().enumerate() {
+            if !Path::new(&input.path).exists() {
+                return Err(io::Error::other(format!(
+                    "The given Isabelle file or directory does not exist: {}",
+                    input.path
+                )));
+            }
+
+            let input_path = temp_path.join(i.to_string());
+            fs::create_dir(&input_path)?;
+
+            if let Some(theories) = input_theories(input, *is_first, &user_theories, config) {
+                jobs.push((input, theories, input_path));
+            }
+        }
+        if jobs.is_empty() && config.changed.is_some() {
+            return unchanged();
+        }
+
+        let parallel = config.parallel_builds > 1;
+        let mut built = if parallel {
+            build_parallel(&jobs, config)
+        } else {
+            vec![]
+        }
+        .into_iter();
+
+        let mut snippets: Vec<Snippet> = vec![];
+        let mut errors = vec![];
+        for (input, theories, input_path) in jobs {
+            let new = if parallel {
+                match built.next().expect("Every job has a result.") {
+                    Ok(new) => new,
+                    Err(e) => {
+                        errors.push(format!("{}: {}", input.path, e));
+                        continue;
+                    }
+                }
+            } else {
+                build_input(input, theories, true, config, &input_path)?
+            };
+            let count = new.len();
+
+            for snippet in &new {
+                if snippets.iter().any(|s| s.prefix == snippet.prefix) {
+                    warn!(
+                        "{}: theory {} also appears in an earlier input, snippet names may clash.",
+                        input.path,
+                        snippet.prefix.as_deref().unwrap_or_default()
+                    );
+                    break;
+                }
+            }
+            snippets.extend(new);
+
+            if streaming {
+                let files = theory_files(&snippets[snippets.len() - count..]);
+                changed |=
+                    output_theories(snips_path, &snippets, &files, header.as_deref(), config)?;
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(io::Error::other(format!(
+                "{} of {} inputs failed:\n{}",
+                errors.len(),
+                inputs.len(),
+                errors.join("\n")
+            )));
+        }
+        snippets
+    };
+
+    if !config.labels.is_empty() {
+        apply_labels(&mut snippets, config);
+    }
+
+    // Numbered in declaration order, before any sorting, so the numbers only change with the theories.
+    if config.anonymize {
+        anonymize(&mut snippets);
+    }
+
+    if config.sort {
+        // The parts of a snippet stay together, since they are sorted as one.
+        snippets.sort_by_cached_key(|s| base_name(&s.prefix, &s.name));
+    }
+
+    if !config.theory_order.is_empty() {
+        // The sort is stable, so the snippets of a theory keep their order.
+        snippets.sort_by_cached_key(|s| config.theory_rank(s));
+        snippets.retain(|s| !config.excludes_theory(s));
+        for (i, theory) in config.theory_order.iter().enumerate() {
+            if !snippets.iter().any(|s| config.theory_rank(s) == i) {
+                warn!("theory {} from -theory-order has no snippets.", theory);
+            }
+        }
+    }
+
+    // With -diff, nothing is written.
+    if let Some(manifest) = config.manifest.as_ref().filter(|_| !config.diff) {
+        with_context(
+            fs::write(manifest, render_manifest(&snippets)),
+            "Could not write manifest",
+        )?;
+        info!("Manifest written to: {}", manifest);
+    }
+
+    if let Some(macros) = config.emit_macros.as_ref().filter(|_| !config.diff) {
+        with_context(
+            fs::write(macros, render_macros(config)),
+            "Could not write macros",
+        )?;
+        info!("Macros written to: {}", macros);
+    }
+
+    SNIPPETS.store(snippets.len(), Ordering::Relaxed);
+
+    if let Some(name) = &config.only_snippet {
+        return match find_snippet(&snippets, name) {
+            Some(snippet) => {
+                println!("{}", render_snippets(std::slice::from_ref(snippet), config));
+                Ok(0)
+            }
+            None => Err(io::Error::other(format!("No snippet named {}", name))),
+        };
+    }
+
+    if !config.split_by_theory {
+        let changed = output_snippets(
+            snips_path,
+            &render_snippets(&snippets, config),
+            header.as_deref(),
+            config,
+        )?;
+        write_checksums(&[snips_path.to_path_buf()], config)?;
+        return Ok(if config.diff && changed { 1 } else { 0 });
+    }
+
+    if !streaming {
+        let files = theory_files(&snippets);
+        changed |= output_theories(snips_path, &snippets, &files, header.as_deref(), config)?;
+    }
+
+    let files = theory_files(&snippets);
+    let paths: Vec<PathBuf> = (files.iter().enumerate())
+        .filter(|(_, file)| {
+            snippets
+                .iter()
+                .any(|s| theory_file(s) == **file && !config.excludes_theory(s))
+        })
+        .map(|(i, file)| theory_path(snips_path, file, i + 1, config))
+        .collect();
+    write_checksums(&paths, config)?;
+
+    Ok(if config.diff && changed { 1 } else { 0 })
+}
+
+// The snippet of -only-snippet, by its name or the label of one of its parts.
+fn find_snippet<'a>(snippets: &'a [Snippet], name: &str) -> Option<&'a Snippet> {
+    snippets.iter().find(|s| {
+        let base = base_name(&s.prefix, &s.name);
+        base == name || split_part_label(name).is_some_and(|(n, i)| n == base && i < s.parts.len())
+    })
+}
+
+// An output path like figs/{theory}_snippets.tex, expanded for every theory file.
+fn is_template(out: &str) -> bool {
+    out.contains("{theory}") || out.contains("{index}")
+}
+
+// The path of a theory file with -split-by-theory, where index counts from 1
+// in the order the theories were extracted.
+fn theory_path(snips_path: &Path, file: &str, index: usize, config: &Config) -> PathBuf {
+    let out = snips_path.to_string_lossy();
+    if is_template(&out) {
+        PathBuf::from(
+            out.replace("{theory}", file)
+                .replace("{index}", &index.to_string()),
+        )
+    } else {
+        snips_path.join(file).with_extension(config.format.ext())
+    }
+}
+
+// With -checksum, writes the SHA-256 of every output file as read back from disk,
+// in the format of sha256sum, so 'sha256sum -c' can check them.
+fn write_checksums(paths: &[PathBuf], config: &Config) -> io::Result<()> {
+    let checksum = match &config.checksum {
+        Some(checksum) if !config.diff => checksum,
+        _ => return Ok(()),
+    };
+
+    let mut lines = String::new();
+    for path in paths {
+        let bytes = with_context(
+            config.fs().read(path),
+            "Could not read snippets for checksum",
+        )?;
+        lines.push_str(&format!("{}  {}\n", sha256_hex(&bytes), path.display()));
+    }
+    with_context(
+        config.fs().write(Path::new(checksum), lines.as_bytes()),
+        "Could not write checksum",
+    )?;
+    info!("Checksum written to: {}", checksum);
+    Ok(())
+}
+
+// The output file of a snippet with -split-by-theory, without extension.
+fn theory_file(snippet: &Snippet) -> String {
+    snippet.prefix.clone().unwrap_or(snippet.theory.clone())
+}
+
+// The distinct theory files of the snippets, in the order they were extracted.
+fn theory_files(snippets: &[Snippet]) -> Vec<String> {
+    let mut files: Vec<String> = vec![];
+    for snippet in snippets {
+        let file = theory_file(snippet);
+        if !files.contains(&file) {
+            files.push(file);
+        }
+    }
+    files
+}
+
+// Writes the given theory files from all snippets so far. Returns whether any changed.
+fn output_theories(
+    snips_path: &Path,
+    snippets: &[Snippet],
+    files: &[String],
+    header: Option<&str>,
+    config: &Config,
+) -> io::Result<bool> {
+    let all_files = theory_files(snippets);
+
+    let mut changed = false;
+    for file in files {
+        let mut group: Vec<Snippet> = (snippets.iter())
+            .filter(|s| theory_file(s) == *file && !config.excludes_theory(s))
+            .cloned()
+            .collect();
+        if group.is_empty() {
+            continue;
+        }
+        if config.sort {
+            group.sort_by_cached_key(|s| base_name(&s.prefix, &s.name));
+        }
+
+        let index = all_files.iter().position(|f| f == file).unwrap_or(0) + 1;
+        let path = theory_path(snips_path, file, index, config);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            with_context(
+                config.fs().create_dir_all(parent),
+                "Could not create snippets directory",
+            )?;
+        }
+        changed |= output_snippets(&path, &render_snippets(&group, config), header, config)?;
+    }
+
+    Ok(changed)
+}
+
+// Writes the snippets to path unless they are unchanged, or prints the diff with -diff.
+// A header is ignored when comparing, so a new timestamp alone is no change.
+// Returns whether they changed.
+fn output_snippets(
+    snips_path: &Path,
+    snippets: &str,
+    header: Option<&str>,
+    config: &Config,
+) -> io::Result<bool> {
+    let encoding = config.output_encoding;
+    let encode = |text: &str| {
+        let escape = |c| escape_char(config.format, c);
+        encoding.encode(text, escape).map_err(|e| {
+            io::Error::other(format!(
+                "Could not write snippets to {} as {}: {}",
+                snips_path.display(),
+                encoding.name(),
+                e
+            ))
+        })
+    };
+    // Escapes are compared as written, so the snippets are compared after encoding them.
+    let snippets = &encoding.decode(&encode(snippets)?);
+
+    // A missing file counts as empty, so everything shows up as added.
+    let old = config.fs().read(snips_path).ok();
+    let exists = old.is_some();
+    let old = old.map_or_else(String::new, |b| encoding.decode(&b));
+    let old_snippets = strip_header(&old);
+    let same_header = header.is_some() == (old_snippets.len() < old.len());
+    if old_snippets == snippets && (config.diff || (same_header && exists)) {
+        info!("Snippets are up to date: {}", snips_path.display());
+        return Ok(false);
+    }
+
+    if config.diff {
+        let name = snips_path.display().to_string();
+        print!(
+            "{}",
+            TextDiff::from_lines(old_snippets, snippets)
+                .unified_diff()
+                .header(&name, &name)
+        );
+        return Ok(true);
+    }
+
+    with_context(
+        config.fs().write(
+            snips_path,
+            &encode(&[header.unwrap_or(""), snippets].concat())?,
+        ),
+        "Could not write to snippets file",
+    )?;
+
+    info!("Snippets written to: {}", snips_path.display());
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use isasnips::files::MemFs;
+    use std::sync::Arc;
+
+    #[cfg(unix)]
+    fn exit_status(code: i32) -> ExitStatus {
+        std::os::unix::process::ExitStatusExt::from_raw(code << 8)
+    }
+
+    #[cfg(windows)]
+    fn exit_status(code: i32) -> ExitStatus {
+        std::os::windows::process::ExitStatusExt::from_raw(code as u32)
+    }
+
+    // Records its calls and does what isabelle would, roughly: mkroot makes the session
+    // files, and build writes every theory as LaTeX, with the markers as raw lines.
+    #[derive(Clone, Default)]
+    struct Fake {
+        calls: Arc<Mutex<Vec<String>>>,
+        // The output of the builds that fail before one succeeds.
+        failures: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Fake {
+        fn failing(failures: &[&str]) -> Fake {
+            let fake = Fake::default();
+            *fake.failures.lock().unwrap() = failures.iter().map(|f| f.to_string()).collect();
+            fake
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn builds(&self) -> usize {
+            self.calls()
+                .iter()
+                .filter(|c| c.starts_with("build"))
+                .count()
+        }
+    }
+
+    impl IsabelleRunner for Fake {
+        fn run(
+            &self,
+            dir: &Path,
+            args: &[&str],
+            line: &mut dyn FnMut(String),
+        ) -> io::Result<ExitStatus> {
+            self.calls.lock().unwrap().push(args.join(" "));
+            match args[0] {
+                "mkroot" => {
+                    fs::create_dir_all(dir.join("document"))?;
+                    fs::write(dir.join("document").join("root.tex"), "")?;
+                    fs::write(dir.join("ROOT"), "")?;
+                }
+                "sessions" => ["HOL", "HOL-Library", "Pure"]
+                    .iter()
+                    .for_each(|s| line(s.to_string())),
+                "build" => {
+                    let mut failures = self.failures.lock().unwrap();
+                    if !failures.is_empty() {
+                        line(failures.remove(0));
+                        return Ok(exit_status(1));
+                    }
+                    let variant = (args.iter())
+                        .find_map(|a| a.strip_prefix("document_variants="))
+                        .map_or("document", |v| v.split('=').next().unwrap_or_default());
+                    fake_build(dir, &dir.join("output").join(variant))?;
+                }
+                _ => {}
+            }
+            Ok(exit_status(0))
+        }
+    }
+
+    fn fake_build(dir: &Path, out: &Path) -> io::Result<()> {
+        fs::create_dir_all(out)?;
+        for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if !has_ext(path, "thy") || path.starts_with(out) {
+                continue;
+            }
+            let thy = fs::read_to_string(path)?;
+            let lines: Vec<&str> = thy.lines().collect();
+            let mut tex = vec![];
+            for (i, line) in lines.iter().enumerate() {
+                let raw = line.strip_prefix("text_raw \\<open>");
+                if let Some(raw) = raw.and_then(|r| r.strip_suffix("\\<close>")) {
+                    tex.push(format!("%\n{}", raw));
+                    continue;
+                }
+                let mut line = line.replace("\\<", "{\\isasym").replace('>', "}");
+                if lines.get(i + 1).is_some_and(|l| !l.starts_with("text_raw")) {
+                    line.push_str(ISA_NEWLINE);
+                }
+                tex.push(line);
+            }
+            let name = path.file_stem().unwrap_or_default();
+            fs::write(out.join(name).with_extension("tex"), tex.join("\n"))?;
+        }
+        Ok(())
+    }
+
+    fn parse(args: &[&str]) -> (Config, Vec<String>) {
+        let args: Vec<String> = std::iter::once("isasnips")
+            .chain(args.iter().copied())
+            .map(String::from)
+            .collect();
+        parse_args(&args).expect("The arguments parse.")
+    }
+
+    fn with_runner(mut config: Config, fake: &Fake) -> Config {
+        config.isabelle = Some(Box::new(fake.clone()));
+        config
+    }
+
+    fn with_fs(mut config: Config, fs: &MemFs) -> Config {
+        config.fs = Some(Box::new(fs.clone()));
+        config
+    }
+
+    const FOO: &str = "theory Foo imports Main begin\n\n\
+                       lemma foo: \"True\"\n  by simp\n\n\
+                       definition bar where \"bar = (1::nat)\"\n\n\
+                       end\n";
+
+    // A directory with the theory Foo.
+    fn foo_dir() -> TempDir {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("Foo.thy"), FOO).unwrap();
+        dir
+    }
+
+    // The names of the snippets, without the theory and its end.
+    fn names(snippets: &[Snippet]) -> Vec<String> {
+        (snippets.iter())
+            .filter(|s| s.cmd != "theory" && s.cmd != "end")
+            .map(|s| s.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn builds_a_theory_file_in_a_root() {
+        let dir = foo_dir();
+        let thy = dir.path().join("Foo.thy").display().to_string();
+        let fake = Fake::default();
+        let (config, _) = parse(&[&thy, "out.tex"]);
+        let config = with_runner(config, &fake);
+        let input = Input {
+            path: thy,
+            ..Default::default()
+        };
+
+        let temp = tempdir().unwrap();
+        let snippets = build_input(&input, vec![], false, &config, temp.path()).unwrap();
+        assert_eq!(names(&snippets), ["lemma:foo", "definition:bar"]);
+        assert_eq!(
+            fake.calls(),
+            [
+                "sessions -a",
+                "mkroot -n isasnips",
+                "build -c -D . -o document=pdf -o document_output=output",
+            ]
+        );
+
+        let root = fs::read_to_string(temp.path().join("ROOT")).unwrap();
+        assert!(root.contains("session isasnips"), "{}", root);
+        assert!(root.contains("Foo"), "{}", root);
+        assert!(root.contains("root.tex"), "{}", root);
+    }
+
+    #[test]
+    fn builds_a_session_without_mkroot() {
+        let dir = foo_dir();
+        fs::write(dir.path().join("ROOT"), "session Foo = HOL + theories Foo").unwrap();
+        let path = dir.path().display().to_string();
+        let fake = Fake::default();
+        let (config, _) = parse(&[&path, "out.tex", "-no-preflight"]);
+        let config = with_runner(config, &fake);
+        let input = Input {
+            path,
+            ..Default::default()
+        };
+
+        let temp = tempdir().unwrap();
+        let snippets = build_input(&input, vec![], false, &config, temp.path()).unwrap();
+        assert_eq!(names(&snippets), ["lemma:foo", "definition:bar"]);
+        assert_eq!(fake.builds(), 1);
+        assert!(!fake.calls().iter().any(|c| c.starts_with("mkroot")));
+        assert!(!fake.calls().iter().any(|c| c.starts_with("sessions")));
+    }
+
+    #[test]
+    fn missing_base_session_fails_before_the_build() {
+        let dir = foo_dir();
+        let thy = dir.path().join("Foo.thy").display().to_string();
+        let fake = Fake::default();
+        let (config, _) = parse(&[&thy, "out.tex", "-base", "HOL-Nope"]);
+        let config = with_runner(config, &fake);
+        let input = Input {
+            path: thy,
+            ..Default::default()
+        };
+
+        let temp = tempdir().unwrap();
+        let e = build_input(&input, vec![], false, &config, temp.path()).unwrap_err();
+        assert!(e.to_string().contains("HOL-Nope"), "{}", e);
+        assert_eq!(fake.calls(), ["sessions -a"]);
+    }
+
+    // Runs the tool on the theory Foo with the fake runner, returning the exit code.
+    fn run_foo(dir: &Path, options: &[&str]) -> io::Result<i32> {
+        let thy = dir.join("Foo.thy").display().to_string();
+        let out = dir.join("out").join("snippets.tex").display().to_string();
+        let mut args = vec![thy.as_str(), out.as_str()];
+        args.extend(options);
+        let (config, args) = parse(&args);
+        let config = with_runner(config, &Fake::default());
+        let temp = tempdir().unwrap();
+        run(&config, &args, temp.path())
+    }
+
+    // The files below a directory, with their contents.
+    fn files(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        (WalkDir::new(dir).sort_by_file_name().into_iter())
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .map(|e| (e.path().to_path_buf(), fs::read(e.path()).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn diff_writes_nothing() {
+        let dir = foo_dir();
+        let out = dir.path().join("out");
+        fs::create_dir(&out).unwrap();
+        let manifest = out.join("manifest.json").display().to_string();
+        let macros = out.join("macros.tex").display().to_string();
+        let options = ["-manifest", &manifest, "-emit-macros", &macros];
+
+        let mut diff = vec!["-diff"];
+        diff.extend(options);
+        assert_eq!(run_foo(dir.path(), &diff).unwrap(), 1);
+        assert!(files(&out).is_empty());
+
+        assert_eq!(run_foo(dir.path(), &options).unwrap(), 0);
+        let written = files(&out);
+        assert_eq!(written.len(), 3);
+        assert_eq!(run_foo(dir.path(), &diff).unwrap(), 0);
+        assert_eq!(files(&out), written);
+    }
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .current_dir(dir)
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?}", args);
+    }
+
+    #[test]
+    fn changed_theories_since_a_revision() {
+        let dir = tempdir().unwrap();
+        let repo = dir.path();
+        fs::create_dir(repo.join("sub")).unwrap();
+        fs::write(repo.join("A.thy"), "theory A begin end").unwrap();
+        fs::write(repo.join("sub").join("B.thy"), "theory B begin end").unwrap();
+        fs::write(repo.join("notes.txt"), "").unwrap();
+        git(repo, &["init", "-q"]);
+        git(repo, &["add", "."]);
+        git(repo, &["commit", "-q", "-m", "init"]);
+
+        fs::write(repo.join("A.thy"), "theory A imports Main begin end").unwrap();
+        fs::write(repo.join("notes.txt"), "changed").unwrap();
+        let changed = changed_theories("HEAD", &repo.join("sub"), "thy").unwrap();
+        assert_eq!(changed, [fs::canonicalize(repo.join("A.thy")).unwrap()]);
+
+        git(repo, &["commit", "-q", "-a", "-m", "change"]);
+        assert_eq!(changed_theories("HEAD", repo, "thy"), Some(vec![]));
+        assert_eq!(changed_theories("no-such-revision", repo, "thy"), None);
+    }
+
+    fn args_of(options: &[&str]) -> Vec<String> {
+        let mut args = vec!["Foo.thy", "out.tex"];
+        args.extend(options);
+        build_args(&Input::default(), &parse(&args).0)
+    }
+
+    #[test]
+    fn document_variant_is_built() {
+        let args = args_of(&["-document-variant", "outline=/proof,/ML"]);
+        assert!(args.ends_with(&[
+            "-o".to_string(),
+            "document_variants=outline=/proof,/ML".to_string()
+        ]));
+        assert!(!args_of(&[])
+            .iter()
+            .any(|a| a.starts_with("document_variants")));
+
+        // No document is built for the other backends.
+        for backend in [&["-raw-source"][..], &["-format", "html"]] {
+            let mut options = vec!["-document-variant", "outline"];
+            options.extend(backend);
+            let args = args_of(&options);
+            assert!(!args.iter().any(|a| a.starts_with("document_variants")));
+        }
+    }
+
+    #[test]
+    fn document_variant_is_read() {
+        let temp = Path::new("work");
+        let dir = |options: &[&str]| {
+            let mut args = vec!["Foo.thy", "out.tex"];
+            args.extend(options);
+            parse(&args).0.document_dir(temp)
+        };
+        assert_eq!(dir(&[]), temp);
+        assert_eq!(
+            dir(&["-document-variant", "outline=/proof,/ML"]),
+            temp.join("output").join("outline")
+        );
+        assert_eq!(dir(&["-document-variant", "outline", "-raw-source"]), temp);
+    }
+
+    #[test]
+    fn document_variant_is_single() {
+        let args = |variant: &str| -> Vec<String> {
+            [
+                "isasnips",
+                "Foo.thy",
+                "out.tex",
+                "-document-variant",
+                variant,
+            ]
+            .iter()
+            .map(|a| a.to_string())
+            .collect()
+        };
+        assert!(parse_args(&args("document:outline=/proof")).is_err());
+        assert!(parse_args(&args("")).is_err());
+        assert!(parse_args(&args("outline")).is_ok());
+    }
+
+    #[test]
+    fn builds_a_document_variant() {
+        let dir = foo_dir();
+        let thy = dir.path().join("Foo.thy").display().to_string();
+        let fake = Fake::default();
+        let (config, _) = parse(&[&thy, "out.tex", "-document-variant", "outline=/proof"]);
+        let config = with_runner(config, &fake);
+        let input = Input {
+            path: thy,
+            ..Default::default()
+        };
+
+        let temp = tempdir().unwrap();
+        let snippets = build_input(&input, vec![], false, &config, temp.path()).unwrap();
+        assert_eq!(names(&snippets), ["lemma:foo", "definition:bar"]);
+        assert!(temp
+            .path()
+            .join("output")
+            .join("outline")
+            .join("Foo.tex")
+            .is_file());
+    }
+
+    // Writes a theory to a directory and returns its path.
+    fn write_thy(dir: &Path, name: &str, thy: &str) -> PathBuf {
+        let path = dir.join(name).with_extension("thy");
+        fs::write(&path, thy).unwrap();
+        path
+    }
+
+    #[test]
+    fn require_names_rejects_unnamed_lemmas() {
+        let dir = tempdir().unwrap();
+        let thy = "theory Foo imports Main begin\nlemma \"True\"\n  by simp\nend";
+        let path = write_thy(dir.path(), "Foo", thy);
+
+        assert!(process_theory(&path, &parse(&[]).0).is_ok());
+        let (config, _) = parse(&["-require-names"]);
+        let e = process_theory(&path, &config).unwrap_err();
+        assert!(
+            e.to_string().contains("could not extract name for 'lemma'"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn require_names_accepts_unnamed_outer_commands() {
+        let dir = tempdir().unwrap();
+        let thy = "theory Foo imports Main begin\nsection \\<open>A\\<close>\nlemma a: \"True\"\n  by simp\nend";
+        let path = write_thy(dir.path(), "Foo", thy);
+        let (config, _) = parse(&["-require-names"]);
+        assert!(process_theory(&path, &config).is_ok());
+    }
+
+    #[test]
+    fn naming_failures_have_a_location() {
+        let dir = tempdir().unwrap();
+        let thy = "theory Foo imports Main begin\n\nlemma a: \"True\"\n  by simp\n\nlemma \"True\"\n  by simp\nend";
+        let path = write_thy(dir.path(), "Foo", thy);
+        let (config, _) = parse(&["-require-names"]);
+        let e = process_theory(&path, &config).unwrap_err().to_string();
+        let location = format!("{}:6: could not extract name for 'lemma'", path.display());
+        assert!(e.contains(&location), "{}", e);
+    }
+
+    #[test]
+    fn unnamed_snippets_are_warned_about() {
+        let dir = tempdir().unwrap();
+        let thy = "theory Foo imports Main begin\n\nlemma a: \"True\"\n  by simp\n\nlemma \"True\"\n  by simp\nend";
+        let path = write_thy(dir.path(), "Foo", thy);
+        let (config, _) = parse(&[]);
+        let before = WARNINGS.load(Ordering::Relaxed);
+        WARNED.with(|w| w.borrow_mut().clear());
+        process_theory(&path, &config).unwrap();
+        assert!(WARNINGS.load(Ordering::Relaxed) > before);
+        let warned = WARNED.with(|w| w.borrow().clone());
+        let location = format!("{}:6: could not extract name for 'lemma'", path.display());
+        assert!(
+            warned.iter().any(|w| w.starts_with(&location)),
+            "{:?}",
+            warned
+        );
+    }
+
+    #[test]
+    fn renamed_snippets_have_a_location() {
+        let dir = tempdir().unwrap();
+        let thy = "theory Foo imports Main begin\nlemma a_b: \"True\" by simp\nlemma a-b: \"True\" by simp\nend";
+        let path = write_thy(dir.path(), "Foo", thy);
+        let (config, _) = parse(&["-strict"]);
+        let e = process_theory(&path, &config).unwrap_err().to_string();
+        assert!(e.starts_with(&format!("{}:3: ", path.display())), "{}", e);
+    }
+
+    #[test]
+    fn extra_imports_are_theories_of_the_root() {
+        let dir = foo_dir();
+        let extra = tempdir().unwrap();
+        let bar = write_thy(extra.path(), "Bar", "theory Bar imports Main begin end");
+        let bar = bar.display().to_string();
+        let (config, _) = parse(&["-extra-import", &bar]);
+        let config = with_runner(config, &Fake::default());
+
+        let temp = tempdir().unwrap();
+        let theories = mkroot(&dir.path().join("Foo.thy"), temp.path(), "HOL", &config).unwrap();
+        assert_eq!(theories, ["Foo"]);
+        assert!(temp.path().join("Bar.thy").is_file());
+
+        let root = fs::read_to_string(temp.path().join("ROOT")).unwrap();
+        let lines: Vec<&str> = root.lines().map(str::trim).collect();
+        let theories = lines.iter().position(|l| *l == "theories").unwrap();
+        assert_eq!(lines[theories + 1..theories + 3], ["Bar", "Foo"]);
+        assert!(lines.contains(&"session isasnips = HOL +"), "{}", root);
+    }
+
+    // A generated LaTeX file with the lemma foo, which ends on a line break.
+    const FOO_TEX: &str = "%
+DefineSnippet lemma:foo lemma 2 3
+\\isacommand{lemma}\\ foo{\\isacharcolon}\\ {\\isachardoublequoteopen}True{\\isachardoublequoteclose}\\isanewline
+\\ \\ \\isacommand{by}\\ simp\\isanewline
+%
+EndSnippet
+";
+
+    fn write_tex(dir: &Path, name: &str, tex: &str) -> PathBuf {
+        let path = dir.join(name).with_extension("tex");
+        fs::write(&path, tex).unwrap();
+        path
+    }
+
+    #[test]
+    fn no_empty_part_after_a_final_line_break() {
+        let dir = tempdir().unwrap();
+        let path = write_tex(dir.path(), "Foo", FOO_TEX);
+        let snippets = extract_file(&StdFs, &path, &None, Backend::Latex).unwrap();
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(snippets[0].parts.len(), 2);
+        assert!(snippets[0].parts.iter().all(|p| !is_empty_part(p)));
+        assert_eq!(snippets[0].source_lines, Some((2, 3)));
+    }
+
+    fn snippet(name: &str, parts: &[&[&str]]) -> Snippet {
+        Snippet {
+            theory: "Foo".to_string(),
+            prefix: None,
+            name: name.to_string(),
+            cmd: name.split(':').next().unwrap_or_default().to_string(),
+            source_lines: Some((1, parts.len())),
+            parts: (parts.iter())
+                .map(|p| p.iter().map(|l| l.to_string()).collect())
+                .collect(),
+            original_name: None,
+            context: None,
+        }
+    }
+
+    fn render(snippets: &[Snippet], options: &[&str]) -> String {
+        render_snippets(snippets, &parse(options).0)
+    }
+
+    #[test]
+    fn command_arg_passes_the_command() {
+        let snippets = [
+            snippet("lemma:foo", &[&["\\isacommand{lemma}\\ foo"]]),
+            snippet("definition:bar", &[&["\\isacommand{definition}\\ bar"]]),
+        ];
+        let out = render(&snippets, &["-command-arg"]);
+        assert!(
+            out.contains("\\DefineSnippet{lemma:foo/0}{lemma}{%"),
+            "{}",
+            out
+        );
+        assert!(
+            out.contains("\\DefineSnippet{definition:bar/0}{definition}{%"),
+            "{}",
+            out
+        );
+
+        let out = render(&snippets, &[]);
+        assert!(out.contains("\\DefineSnippet{lemma:foo/0}{%"), "{}", out);
+        assert!(render_macros(&parse(&["-command-arg"]).0).contains("[3]"));
+    }
+
+    #[test]
+    fn markdown_has_unicode_symbols() {
+        let part: &[&str] = &[
+            "\\isacommand{lemma}\\ {\\isachardoublequoteopen}{\\isasymforall}x{\\isachardot}\\ {\\isasymalpha}\\ x\\ {\\isasymlongrightarrow}\\ {\\isasymbeta}\\ x{\\isachardoublequoteclose}%",
+        ];
+        let out = render(&[snippet("lemma:foo", &[part])], &["-format", "markdown"]);
+        assert!(out.contains("lemma \"∀x. α x ⟶ β x\""), "{}", out);
+        assert!(!out.contains("isasym"), "{}", out);
+    }
+
+    #[test]
+    fn unknown_symbols_are_kept() {
+        assert_eq!(plain_text(&["{\\isasymfoo}\\ x".to_string()]), "\\<foo> x");
+        assert_eq!(unicode_symbols("\\<forall>x. \\<foo> x"), "∀x. \\<foo> x");
+    }
+
+    #[test]
+    fn diff_compares_two_snippet_sets() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("out.tex");
+        let old = [
+            snippet("lemma:foo", &[&["a"]]),
+            snippet("lemma:bar", &[&["b"]]),
+        ];
+        let new = [
+            snippet("lemma:foo", &[&["a"]]),
+            snippet("lemma:bar", &[&["c"]]),
+        ];
+        let (config, _) = parse(&["-diff"]);
+        let written = render_snippets(&old, &config);
+        fs::write(&path, &written).unwrap();
+
+        let changed = output_snippets(&path, &render_snippets(&new, &config), None, &config);
+        assert!(changed.unwrap());
+        let same = output_snippets(&path, &written, None, &config);
+        assert!(!same.unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), written);
+    }
+
+    #[test]
+    fn manifest_has_the_structure_between_snippets() {
+        let section: &[&str] = &["\\isamarkupsection{Main\\ results%", "}\\isamarkuptrue%"];
+        let snippets = [
+            snippet("lemma:a", &[&["\\isacommand{lemma}\\ a"]]),
+            snippet("section:1f2e", &[section]),
+            snippet("lemma:b", &[&["\\isacommand{lemma}\\ b"]]),
+        ];
+        let manifest = render_manifest(&snippets);
+        let entries: Vec<&str> = manifest.lines().filter(|l| l.contains("kind")).collect();
+        assert_eq!(entries.len(), 3);
+        assert!(entries[0].contains("\"kind\": \"snippet\", \"command\": \"lemma\""));
+        assert!(entries[1].contains("\"kind\": \"structure\", \"command\": \"section\""));
+        assert!(
+            entries[1].contains("\"title\": \"Main results\""),
+            "{}",
+            entries[1]
+        );
+        assert!(entries[2].contains("\"name\": \"lemma:b\""));
+    }
+
+    #[test]
+    fn write_failure_is_an_error() {
+        // The output directory does not exist, so the snippets cannot be written.
+        let dir = foo_dir();
+        let e = run_foo(dir.path(), &[]).unwrap_err();
+        assert!(
+            e.to_string()
+                .starts_with("Could not write to snippets file"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn inputs_are_built_with_their_bases() {
+        let foo = foo_dir();
+        let bar = tempdir().unwrap();
+        let bar_thy = "theory Bar imports Main begin\nlemma bar: \"True\"\n  by simp\nend\n";
+        let bar_path = write_thy(bar.path(), "Bar", bar_thy).display().to_string();
+        let foo_path = foo.path().join("Foo.thy").display().to_string();
+        let out = foo.path().join("out.tex");
+        let input = format!("{}:HOL-Library", bar_path);
+        let (config, args) = parse(&[&foo_path, &out.display().to_string(), "-input", &input]);
+        let fake = Fake::default();
+        let config = with_runner(config, &fake);
+
+        let temp = tempdir().unwrap();
+        assert_eq!(run(&config, &args, temp.path()).unwrap(), 0);
+        assert_eq!(fake.builds(), 2);
+        let root = |i: &str| fs::read_to_string(temp.path().join(i).join("ROOT")).unwrap();
+        assert!(
+            root("0").contains("session isasnips = HOL +"),
+            "{}",
+            root("0")
+        );
+        assert!(root("1").contains("session isasnips = \"HOL-Library\" +"));
+
+        // The snippets of both are prefixed by their theories.
+        let snippets = fs::read_to_string(out).unwrap();
+        assert!(snippets.contains("{Foo:lemma:foo/0}"), "{}", snippets);
+        assert!(snippets.contains("{Bar:lemma:bar/0}"), "{}", snippets);
+    }
+
+    // Builds the theory Foo with the fake runner and the given options.
+    fn build_foo(options: &[&str]) -> Vec<Snippet> {
+        let dir = foo_dir();
+        let thy = dir.path().join("Foo.thy").display().to_string();
+        let mut args = vec![thy.as_str(), "out.tex"];
+        args.extend(options);
+        let config = with_runner(parse(&args).0, &Fake::default());
+        let input = Input {
+            path: thy,
+            ..Default::default()
+        };
+        let temp = tempdir().unwrap();
+        build_input(&input, vec![], false, &config, temp.path()).unwrap()
+    }
+
+    fn find<'a>(snippets: &'a [Snippet], name: &str) -> &'a Snippet {
+        (snippets.iter())
+            .find(|s| s.name == name)
+            .unwrap_or_else(|| panic!("No snippet {} in {:?}", name, names(snippets)))
+    }
+
+    #[test]
+    fn raw_source_has_the_source_lines() {
+        let raw = build_foo(&["-raw-source"]);
+        let lemma = find(&raw, "lemma:foo");
+        assert_eq!(lemma.parts, [["lemma foo: \"True\""], ["  by simp"]]);
+
+        let built = build_foo(&[]);
+        let lemma = find(&built, "lemma:foo");
+        assert_eq!(lemma.parts[0], ["lemma foo: \"True\"\\isanewline"]);
+
+        let out = render_snippets(&raw, &parse(&["-raw-source"]).0);
+        assert!(
+            out.contains("\nlemma\\ foo:\\ \"True\"\\isanewline\n}%EndSnippet"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn relative_to_prefixes_by_path() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("Algebra").join("Ring_Theory")).unwrap();
+        write_thy(root, "Top", "theory Top begin end");
+        write_thy(&root.join("Algebra"), "Group", "theory Group begin end");
+        write_thy(
+            &root.join("Algebra").join("Ring_Theory"),
+            "Ideal",
+            "theory Ideal begin end",
+        );
+
+        let prefixes = path_prefixes(root, root, &parse(&[]).0);
+        let prefix = |thy: &str| prefixes.get(&OsString::from(thy)).map(String::as_str);
+        assert_eq!(prefix("Top"), Some("Top"));
+        assert_eq!(prefix("Group"), Some("Algebra-Group"));
+        assert_eq!(prefix("Ideal"), Some("Algebra-Ring-Theory-Ideal"));
+
+        let prefixes = path_prefixes(root, &root.join("Algebra"), &parse(&[]).0);
+        let prefix = |thy: &str| prefixes.get(&OsString::from(thy)).map(String::as_str);
+        assert_eq!(prefix("Group"), Some("Group"));
+        assert_eq!(prefix("Top"), None);
+    }
+
+    #[test]
+    fn split_by_theory_rewrites_only_changed_theories() {
+        let dir = tempdir().unwrap();
+        let out = dir.path();
+        let (config, _) = parse(&["-split-by-theory"]);
+        let mut bar = snippet("lemma:bar", &[&["b"]]);
+        bar.theory = "Bar".to_string();
+        let mut snippets = vec![snippet("lemma:foo", &[&["a"]]), bar];
+        let files = theory_files(&snippets);
+        assert_eq!(files, ["Foo", "Bar"]);
+        assert!(output_theories(out, &snippets, &files, None, &config).unwrap());
+
+        // Both files are made older, so a rewrite shows in the modification time.
+        let old = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let modified = |name: &str| {
+            let path = out.join(name).with_extension("tex");
+            fs::metadata(path).unwrap().modified().unwrap()
+        };
+        for name in &files {
+            let path = out.join(name).with_extension("tex");
+            let file = fs::OpenOptions::new().write(true).open(path).unwrap();
+            file.set_modified(old).unwrap();
+        }
+
+        assert!(!output_theories(out, &snippets, &files, None, &config).unwrap());
+        snippets[1].parts = vec![vec!["c".to_string()]];
+        assert!(output_theories(out, &snippets, &files, None, &config).unwrap());
+        assert_eq!(modified("Foo"), old);
+        assert_ne!(modified("Bar"), old);
+        let bar = fs::read_to_string(out.join("Bar.tex")).unwrap();
+        assert!(bar.contains("\nc\n"), "{}", bar);
+    }
+
+    #[test]
+    fn unbalanced_markers_are_an_error() {
+        let dir = tempdir().unwrap();
+        let unclosed = FOO_TEX.replace("EndSnippet\n", "");
+        let tex = format!(
+            "{}%\nDefineSnippet lemma:bar lemma 5 5\nbar\n%\nEndSnippet\n",
+            unclosed
+        );
+        let path = write_tex(dir.path(), "Foo", &tex);
+        let e = extract_file(&StdFs, &path, &None, Backend::Latex)
+            .unwrap_err()
+            .to_string();
+        let expected = format!(
+            "{}:7: snippet lemma:foo is not closed before snippet lemma:bar",
+            path.display()
+        );
+        assert_eq!(e, expected);
+
+        let path = write_tex(dir.path(), "Bar", &format!("{}%\nEndSnippet\n", FOO_TEX));
+        let e = extract_file(&StdFs, &path, &None, Backend::Latex)
+            .unwrap_err()
+            .to_string();
+        assert!(e.ends_with("EndSnippet without DefineSnippet after snippet lemma:foo"));
+
+        let path = write_tex(dir.path(), "Baz", &unclosed);
+        let e = extract_file(&StdFs, &path, &None, Backend::Latex)
+            .unwrap_err()
+            .to_string();
+        assert!(e.ends_with("snippet lemma:foo is not closed"), "{}", e);
+    }
+
+    #[test]
+    fn isabelle_prefix_is_configurable() {
+        let shown = |options: &[&str]| {
+            let config = parse(options).0;
+            output_line(config.isabelle_prefix(), "", "Building HOL ...")
+        };
+        assert_eq!(shown(&[]), "  Building HOL ...");
+        assert_eq!(shown(&["-isabelle-prefix", ""]), "Building HOL ...");
+        assert_eq!(shown(&["-isabelle-prefix", "| "]), "| Building HOL ...");
+        assert_eq!(output_line("", "[A] ", "ok"), "[A] ok");
+    }
+
+    #[test]
+    fn only_snippet_finds_a_snippet_by_name() {
+        let mut bar = snippet("lemma:bar", &[&["b"], &["c"]]);
+        bar.prefix = Some("Foo".to_string());
+        let snippets = [snippet("lemma:foo", &[&["a"]]), bar];
+        let found = |name: &str| find_snippet(&snippets, name).map(|s| s.name.as_str());
+        assert_eq!(found("lemma:foo"), Some("lemma:foo"));
+        assert_eq!(found("Foo:lemma:bar"), Some("lemma:bar"));
+        assert_eq!(found("Foo:lemma:bar/1"), Some("lemma:bar"));
+        assert_eq!(found("Foo:lemma:bar/2"), None);
+        assert_eq!(found("lemma:bar"), None);
+    }
+
+    #[test]
+    fn only_snippet_fails_without_a_match() {
+        let dir = foo_dir();
+        let thy = dir.path().join("Foo.thy").display().to_string();
+        let (config, args) = parse(&[&thy, "-only-snippet", "lemma:nope"]);
+        let config = with_runner(config, &Fake::default());
+        let temp = tempdir().unwrap();
+        let e = run(&config, &args, temp.path()).unwrap_err();
+        assert_eq!(e.to_string(), "No snippet named lemma:nope");
+
+        let (config, args) = parse(&[&thy, "-only-snippet", "lemma:foo"]);
+        let config = with_runner(config, &Fake::default());
+        let temp = tempdir().unwrap();
+        assert_eq!(run(&config, &args, temp.path()).unwrap(), 0);
+    }
+
+    #[test]
+    fn retries_option_retries_the_build() {
+        let dir = foo_dir();
+        let thy = dir.path().join("Foo.thy").display().to_string();
+        let input = Input {
+            path: thy.clone(),
+            ..Default::default()
+        };
+        let build = |options: &[&str], fake: &Fake| {
+            let mut args = vec![thy.as_str(), "out.tex", "-no-preflight"];
+            args.extend(options);
+            let config = with_runner(parse(&args).0, fake);
+            let temp = tempdir().unwrap();
+            build_input(&input, vec![], false, &config, temp.path())
+        };
+
+        let fake = Fake::failing(&["*** Database is locked"]);
+        assert!(build(&[], &fake).is_err());
+        assert_eq!(fake.builds(), 1);
+
+        let fake = Fake::failing(&["*** Database is locked"]);
+        let snippets = build(&["-retries", "2"], &fake).unwrap();
+        assert_eq!(names(&snippets), ["lemma:foo", "definition:bar"]);
+        assert_eq!(fake.builds(), 2);
+    }
+
+    #[test]
+    fn theory_without_snippets_fails_with_strict() {
+        let dir = foo_dir();
+        let thy = dir.path().join("Foo.thy").display().to_string();
+        let input = Input {
+            path: thy.clone(),
+            ..Default::default()
+        };
+        let build = |options: &[&str]| {
+            let mut args = vec![thy.as_str(), "out.tex", "-only-commands", "theorem"];
+            args.extend(options);
+            let config = with_runner(parse(&args).0, &Fake::default());
+            let temp = tempdir().unwrap();
+            build_input(&input, vec![], false, &config, temp.path())
+        };
+
+        assert!(build(&[]).unwrap().is_empty());
+        let e = build(&["-strict"]).unwrap_err().to_string();
+        assert_eq!(e, format!("{}: theory \"Foo\" produced no snippets.", thy));
+    }
+
+    // The labels of the snippets in a LaTeX file, in order.
+    fn labels(tex: &str) -> Vec<&str> {
+        (tex.lines())
+            .filter_map(|l| l.strip_prefix("\\DefineSnippet{"))
+            .map(|l| l.split('}').next().unwrap_or_default())
+            .collect()
+    }
+
+    #[test]
+    fn sort_orders_the_snippets_by_name() {
+        let dir = foo_dir();
+        fs::create_dir(dir.path().join("out")).unwrap();
+        assert_eq!(run_foo(dir.path(), &["-sort"]).unwrap(), 0);
+        let tex = fs::read_to_string(dir.path().join("out").join("snippets.tex")).unwrap();
+        let labels = labels(&tex);
+        assert_eq!(labels.len(), 5);
+        assert_eq!(labels[0], "definition:bar/0");
+        assert!(labels[1].starts_with("end:"));
+        assert_eq!(labels[2..], ["lemma:foo/0", "lemma:foo/1", "theory:Foo/0"]);
+    }
+
+    #[test]
+    fn theory_ext_matches_other_files() {
+        assert_eq!(
+            theory_name(Path::new("a/Foo.thy.txt"), "thy.txt"),
+            Some("Foo".into())
+        );
+        assert_eq!(theory_name(Path::new("a/Foo.thy"), "thy.txt"), None);
+        assert_eq!(theory_name(Path::new(".thy"), "thy"), None);
+
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("Foo.thy.txt"), FOO).unwrap();
+        write_thy(dir.path(), "Bar", "theory Bar begin end");
+        let path = dir.path().display().to_string();
+        let (config, _) = parse(&[&path, "out.tex", "-theory-ext", ".thy.txt", "-no-preflight"]);
+        assert_eq!(dir_theories(dir.path(), &[], &config), ["Foo"]);
+
+        let fake = Fake::default();
+        let config = with_runner(config, &fake);
+        let input = Input {
+            path,
+            ..Default::default()
+        };
+        let temp = tempdir().unwrap();
+        let snippets = build_input(&input, vec![], false, &config, temp.path()).unwrap();
+        assert_eq!(names(&snippets), ["lemma:foo", "definition:bar"]);
+    }
+
+    #[test]
+    fn manifest_has_the_source_lines() {
+        let mut lemma = snippet("lemma:foo", &[&["a"], &["b"]]);
+        lemma.source_lines = Some((3, 4));
+        let manifest = render_manifest(&[lemma]);
+        assert!(
+            manifest.contains("\"start_line\": 3, \"end_line\": 4"),
+            "{}",
+            manifest
+        );
+    }
+
+    #[test]
+    fn names_with_spaces_are_read_back() {
+        let dir = tempdir().unwrap();
+        let tex = "%\nDefineSnippet text:main+20result text 2 2\nx%\n%\nEndSnippet\n";
+        let path = write_tex(dir.path(), "Foo", &format!("{}{}", tex, FOO_TEX));
+        let snippets = extract_file(&StdFs, &path, &None, Backend::Latex).unwrap();
+        assert_eq!(names(&snippets), ["text:main result", "lemma:foo"]);
+        assert_eq!(snippets[0].cmd, "text");
+        assert_eq!(snippets[0].source_lines, Some((2, 2)));
+    }
+
+    // A ROOTS file pointing to the sessions A and B, where B imports A.
+    fn roots_dir() -> TempDir {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("ROOTS"), "# The sessions\nA\n\"B\"\n").unwrap();
+        for (session, thy) in [
+            (
+                "A",
+                "theory A imports Main begin\nlemma a: \"True\" by simp\nend\n",
+            ),
+            (
+                "B",
+                "theory B imports A.A begin\nlemma b: \"True\" by simp\nend\n",
+            ),
+        ] {
+            fs::create_dir(root.join(session)).unwrap();
+            let root_file = format!("session {} = HOL + theories {}", session, session);
+            fs::write(root.join(session).join("ROOT"), root_file).unwrap();
+            write_thy(&root.join(session), session, thy);
+        }
+        dir
+    }
+
+    #[test]
+    fn roots_lists_the_sessions() {
+        assert_eq!(
+            parse_roots("A # first\n\n\"B C\"\n  # none\n"),
+            ["A", "B C"]
+        );
+
+        let dir = roots_dir();
+        let input = Input {
+            path: dir.path().display().to_string(),
+            ..Default::default()
+        };
+        let inputs = session_inputs(&input);
+        let paths: Vec<PathBuf> = inputs.iter().map(|i| PathBuf::from(&i.path)).collect();
+        assert_eq!(paths, [dir.path().join("A"), dir.path().join("B")]);
+
+        // Every session can use the others.
+        let canonical = |s: &str| fs::canonicalize(dir.path().join(s)).unwrap();
+        assert_eq!(inputs[0].dirs, [canonical("B")]);
+        assert_eq!(inputs[1].dirs, [canonical("A")]);
+    }
+
+    #[test]
+    fn roots_sessions_are_built_separately() {
+        let dir = roots_dir();
+        let out = dir.path().join("out.tex");
+        let (config, args) = parse(&[
+            &dir.path().display().to_string(),
+            &out.display().to_string(),
+        ]);
+        let fake = Fake::default();
+        let config = with_runner(config, &fake);
+        let temp = tempdir().unwrap();
+        assert_eq!(run(&config, &args, temp.path()).unwrap(), 0);
+
+        let builds: Vec<String> = (fake.calls().into_iter())
+            .filter(|c| c.starts_with("build"))
+            .collect();
+        assert_eq!(builds.len(), 2);
+        let a = fs::canonicalize(dir.path().join("A")).unwrap();
+        assert!(
+            builds[1].ends_with(&format!("-d {}", a.display())),
+            "{:?}",
+            builds
+        );
+
+        let labels: Vec<String> = labels(&fs::read_to_string(out).unwrap())
+            .into_iter()
+            .filter(|l| l.contains("lemma"))
+            .map(String::from)
+            .collect();
+        assert_eq!(labels, ["A:lemma:a/0", "B:lemma:b/0"]);
+    }
+
+    #[test]
+    fn fail_on_warning_fails_a_run_with_warnings() {
+        let dir = foo_dir();
+        let path = dir.path().display().to_string();
+        fs::write(dir.path().join("ROOT"), "session Foo = HOL + theories Foo").unwrap();
+        fs::create_dir(dir.path().join("out")).unwrap();
+        let out = dir
+            .path()
+            .join("out")
+            .join("snippets.tex")
+            .display()
+            .to_string();
+        let (config, args) = parse(&[&path, &out, "Foo", "Fooo", "-fail-on-warning"]);
+        let config = with_runner(config, &Fake::default());
+
+        // Other tests may warn at the same time, but never take warnings back.
+        let before = WARNINGS.load(Ordering::Relaxed);
+        let temp = tempdir().unwrap();
+        let result = run(&config, &args, temp.path());
+        let warnings = WARNINGS.load(Ordering::Relaxed) - before;
+        assert!(warnings > 0);
+
+        let (code, error) = exit_code(result, warnings, config.fail_on_warning);
+        assert_eq!(code, 1);
+        assert!(error.unwrap().ends_with("warning(s) with -fail-on-warning"));
+    }
+
+    #[test]
+    fn fail_on_warning_fails_a_run_with_an_unnamed_lemma() {
+        let dir = tempdir().unwrap();
+        let thy = "theory Foo imports Main begin\nlemma \"True\"\n  by simp\nend\n";
+        write_thy(dir.path(), "Foo", thy);
+        fs::create_dir(dir.path().join("out")).unwrap();
+        let (config, _) = parse(&["-fail-on-warning"]);
+        let config = with_runner(config, &Fake::default());
+
+        let before = WARNINGS.load(Ordering::Relaxed);
+        WARNED.with(|w| w.borrow_mut().clear());
+        let result = run_foo(dir.path(), &["-fail-on-warning"]);
+        let warnings = WARNINGS.load(Ordering::Relaxed) - before;
+        let warned = WARNED.with(|w| w.borrow().clone());
+        assert!(
+            warned.iter().any(|w| w.contains("could not extract name")),
+            "{:?}",
+            warned
+        );
+
+        let (code, error) = exit_code(result, warnings, config.fail_on_warning);
+        assert_eq!(code, 1);
+        let error = error.unwrap();
+        assert!(
+            error.ends_with("warning(s) with -fail-on-warning"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(exit_code(Ok(0), 2, false), (0, None));
+        assert_eq!(exit_code(Ok(0), 0, true), (0, None));
+        assert_eq!(exit_code(Ok(1), 2, true), (1, None));
+        let (code, error) = exit_code(Err(io::Error::other("broken")), 0, true);
+        assert_eq!((code, error.as_deref()), (1, Some("broken")));
+    }
+
+    #[test]
+    fn wrap_env_wraps_every_part() {
+        let snippets = [snippet("lemma:foo", &[&["a\\isanewline"], &["b"]])];
+        let out = render(&snippets, &["-wrap-env", "snippet"]);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "\\DefineSnippet{lemma:foo/0}{%",
+                "\\begin{snippet}%",
+                "a\\isanewline",
+                "\\end{snippet}%",
+                "}%EndSnippet",
+                "\\DefineSnippet{lemma:foo/1}{%",
+                "\\begin{snippet}%",
+                "b",
+                "\\end{snippet}%",
+                "}%EndSnippet",
+            ]
+        );
+        assert!(!render(&snippets, &[]).contains("\\begin"));
+    }
+
+    #[test]
+    fn header_records_the_version_and_time() {
+        // An unchanged output is not written again, so every run has a directory of its own.
+        let first_line = |options: &[&str]| {
+            let dir = foo_dir();
+            fs::create_dir(dir.path().join("out")).unwrap();
+            assert_eq!(run_foo(dir.path(), options).unwrap(), 0);
+            let tex = fs::read_to_string(dir.path().join("out").join("snippets.tex")).unwrap();
+            let first = tex.lines().next().unwrap_or_default().to_string();
+            assert_eq!(labels(strip_header(&tex)).len(), 5);
+            first
+        };
+
+        let header = first_line(&["-header"]);
+        let prefix = format!(
+            "% Generated by isasnips {} from ",
+            env!("CARGO_PKG_VERSION")
+        );
+        assert!(header.starts_with(&prefix), "{}", header);
+        assert!(header.contains("Foo.thy at "), "{}", header);
+        assert!(header.ends_with(" UTC"), "{}", header);
+
+        let header = first_line(&["-header", "-no-timestamp"]);
+        assert!(header.starts_with(&prefix), "{}", header);
+        assert!(header.ends_with("Foo.thy"), "{}", header);
+
+        assert!(!first_line(&[]).contains(HEADER));
+    }
+
+    #[test]
+    fn ignore_case_matches_the_theory_on_disk() {
+        let dir = foo_dir();
+        fs::write(dir.path().join("ROOT"), "session Foo = HOL + theories Foo").unwrap();
+        let path = dir.path().display().to_string();
+        let build = |options: &[&str]| {
+            let mut args = vec![path.as_str(), "out.tex"];
+            args.extend(options);
+            let config = with_runner(parse(&args).0, &Fake::default());
+            let input = Input {
+                path: path.clone(),
+                ..Default::default()
+            };
+            let temp = tempdir().unwrap();
+            build_input(&input, vec!["foo".into()], false, &config, temp.path()).unwrap()
+        };
+
+        assert!(build(&[]).is_empty());
+        let snippets = build(&["-ignore-case"]);
+        assert_eq!(names(&snippets), ["lemma:foo", "definition:bar"]);
+        assert!(snippets.iter().all(|s| s.theory == "Foo"));
+    }
+
+    #[test]
+    fn theory_config_sets_options_per_theory() {
+        let foo = foo_dir();
+        let bar_thy = "theory Bar imports Main begin\nlemma bar: \"True\"\n  sorry\nend\n";
+        let bar_path = write_thy(foo.path(), "Bar", bar_thy).display().to_string();
+        let foo_path = foo.path().join("Foo.thy").display().to_string();
+        let table = foo.path().join("theories.toml");
+        fs::write(
+            &table,
+            "[Bar]\nquick_and_dirty = true\n\n[Foo]\nbase = \"HOL-Library\"\n",
+        )
+        .unwrap();
+        let out = foo.path().join("out.tex").display().to_string();
+        let table = table.display().to_string();
+        let options = ["-input", &bar_path, "-theory-config", &table];
+        let (config, args) = parse(&[[foo_path.as_str(), &out].as_slice(), &options].concat());
+        let fake = Fake::default();
+        let config = with_runner(config, &fake);
+
+        let temp = tempdir().unwrap();
+        assert_eq!(run(&config, &args, temp.path()).unwrap(), 0);
+        let builds: Vec<String> = (fake.calls().into_iter())
+            .filter(|c| c.starts_with("build"))
+            .collect();
+        assert_eq!(builds.len(), 2);
+        assert!(!builds[0].contains("quick_and_dirty"), "{}", builds[0]);
+        assert!(builds[1].contains("-o quick_and_dirty"), "{}", builds[1]);
+
+        let root = |i: &str| fs::read_to_string(temp.path().join(i).join("ROOT")).unwrap();
+        assert!(root("0").contains("session isasnips = \"HOL-Library\" +"));
+        assert!(root("1").contains("session isasnips = HOL +"));
+
+        let snippets = fs::read_to_string(out).unwrap();
+        assert!(snippets.contains("{Bar:lemma:bar/0}"), "{}", snippets);
+
+        // With -require-complete, the quick_and_dirty of Bar is an error.
+        let (config, args) = parse(
+            &[
+                [foo_path.as_str(), "out.tex"].as_slice(),
+                &options,
+                &["-require-complete"],
+            ]
+            .concat(),
+        );
+        let config = with_runner(config, &Fake::default());
+        let temp = tempdir().unwrap();
+        let e = run(&config, &args, temp.path()).unwrap_err().to_string();
+        assert!(e.contains("Bar.thy: quick_and_dirty is enabled"), "{}", e);
+    }
+
+    #[test]
+    fn theory_configs_are_checked() {
+        let configs = parse_theory_configs("# Settings\n[Foo]\nbase = '\"My Session\"'\n").unwrap();
+        assert_eq!(configs["Foo"].base.as_deref(), Some("\"My Session\""));
+        assert!(!configs["Foo"].quick_and_dirty);
+
+        let error = |s: &str| parse_theory_configs(s).err().unwrap_or_default();
+        assert_eq!(
+            error("base = HOL"),
+            "line 1: expected a [Theory] section first"
+        );
+        assert_eq!(error("[Foo]\nbase"), "line 2: expected key = value");
+        assert_eq!(
+            error("[Foo]\nquick_and_dirty = yes"),
+            "line 2: expected true or false"
+        );
+        assert_eq!(
+            error("[Foo]\ntimeout = 3"),
+            "line 2: unknown setting timeout"
+        );
+    }
+
+    #[test]
+    fn base_session_is_checked_with_the_session_dirs() {
+        let fake = Fake::default();
+        assert!(check_base_session(&fake, "\"HOL-Library\"", &["lib".to_string()]).is_ok());
+        assert!(check_base_session(&fake, "HOL-Nope", &[]).is_err());
+        assert_eq!(fake.calls(), ["sessions -a -d lib", "sessions -a"]);
+
+        // Without a list of the sessions, the build goes ahead.
+        struct Broken;
+        impl IsabelleRunner for Broken {
+            fn run(
+                &self,
+                _: &Path,
+                _: &[&str],
+                _: &mut dyn FnMut(String),
+            ) -> io::Result<ExitStatus> {
+                Err(io::Error::other("no isabelle"))
+            }
+        }
+        let before = WARNINGS.load(Ordering::Relaxed);
+        WARNED.with(|w| w.borrow_mut().clear());
+        assert!(check_base_session(&Broken, "HOL-Nope", &[]).is_ok());
+        assert!(WARNINGS.load(Ordering::Relaxed) > before);
+        let warned = WARNED.with(|w| w.borrow().clone());
+        assert!(
+            warned.iter().any(|w| w.contains("HOL-Nope is not checked")),
+            "{:?}",
+            warned
+        );
+    }
+
+    #[test]
+    fn inline_short_inlines_one_part_snippets() {
+        let snippets = [
+            snippet("definition:bar", &[&["bar\\isanewline", "%", "= 1"]]),
+            snippet("lemma:foo", &[&["a"], &["b"]]),
+        ];
+        let out = render(&snippets, &["-inline-short"]);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "\\InlineSnippet{definition:bar/0}{bar\\isanewline",
+                "= 1}%",
+                "\\DefineSnippet{lemma:foo/0}{%",
+                "a",
+                "}%EndSnippet",
+                "\\DefineSnippet{lemma:foo/1}{%",
+                "b",
+                "}%EndSnippet",
+            ]
+        );
+
+        let out = render(&snippets[..1], &["-inline-short", "-command-arg"]);
+        assert_eq!(
+            out,
+            "\\InlineSnippet{definition:bar/0}{definition}{bar\\isanewline\n= 1}%"
+        );
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("", "Foo"), 3);
+        assert_eq!(edit_distance("Foo", "Foo"), 0);
+        assert_eq!(edit_distance("Fooo", "Foo"), 1);
+        assert_eq!(edit_distance("Foo", "Fum"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("⟹a", "⟹b"), 1);
+    }
+
+    #[test]
+    fn misspelled_theories_have_suggestions() {
+        let found: Vec<OsString> = ["Foo", "Food", "Bar", "Foundations", "Fo"]
+            .iter()
+            .map(OsString::from)
+            .collect();
+        let similar = |name: &str| -> Vec<String> {
+            (similar_theories(&name.into(), &found).into_iter())
+                .map(|t| t.to_string_lossy().into_owned())
+                .collect()
+        };
+        assert_eq!(similar("Fooo"), ["Foo", "Food", "Fo"]);
+        assert_eq!(similar("bar"), ["Bar"]);
+        assert_eq!(similar("Fundations"), ["Foundations"]);
+        assert!(similar("Lattices").is_empty());
+    }
+
+    #[test]
+    fn split_by_theory_writes_each_input_when_it_is_built() {
+        let dir = foo_dir();
+        let bar_thy = "theory Bar imports Main begin\nlemma bar: \"True\"\n  by simp\nend\n";
+        let bar_path = write_thy(dir.path(), "Bar", bar_thy).display().to_string();
+        let foo_path = dir.path().join("Foo.thy").display().to_string();
+        let out = dir.path().join("out");
+        let out_path = out.display().to_string();
+        let run_with = |bar: &str| {
+            let args = [
+                foo_path.as_str(),
+                &out_path,
+                "-split-by-theory",
+                "-input",
+                bar,
+            ];
+            let (config, args) = parse(&args);
+            let config = with_runner(config, &Fake::default());
+            let temp = tempdir().unwrap();
+            run(&config, &args, temp.path())
+        };
+
+        assert_eq!(run_with(&bar_path).unwrap(), 0);
+        let tex = |name: &str| fs::read_to_string(out.join(name).with_extension("tex")).unwrap();
+        assert!(tex("Foo").contains("{Foo:lemma:foo/0}"), "{}", tex("Foo"));
+        assert!(tex("Bar").contains("{Bar:lemma:bar/0}"), "{}", tex("Bar"));
+
+        // When Bar fails, the file of Foo is still written.
+        fs::remove_dir_all(&out).unwrap();
+        let e = run_with(&format!("{}:HOL-Nope", bar_path)).unwrap_err();
+        assert!(e.to_string().contains("HOL-Nope"), "{}", e);
+        assert!(tex("Foo").contains("{Foo:lemma:foo/0}"), "{}", tex("Foo"));
+        assert!(!out.join("Bar.tex").exists());
+    }
+
+    #[test]
+    fn quoted_base_is_used_as_given() {
+        let dir = foo_dir();
+        let thy = dir.path().join("Foo.thy").display().to_string();
+        let (config, _) = parse(&[&thy, "out.tex", "-base", "\"My Session\"", "-no-preflight"]);
+        let config = with_runner(config, &Fake::default());
+        let input = Input {
+            path: thy,
+            ..Default::default()
+        };
+        let temp = tempdir().unwrap();
+        build_input(&input, vec![], false, &config, temp.path()).unwrap();
+        let root = fs::read_to_string(temp.path().join("ROOT")).unwrap();
+        assert!(
+            root.contains("session isasnips = \"My Session\" +"),
+            "{}",
+            root
+        );
+
+        assert!(check_session_name("HOL-Library").is_ok());
+        assert!(check_session_name("\"My Session\"").is_ok());
+        for base in [
+            "",
+            "\"\"",
+            "\"My Session",
+            "My\"Session",
+            "\"A\"B\"",
+            "HOL\nLibrary",
+        ] {
+            assert!(check_session_name(base).is_err(), "{:?}", base);
+        }
+        let args = ["isasnips", "Foo.thy", "out.tex", "-base", "\"My Session"].map(String::from);
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn content_hash_changes_with_the_body() {
+        assert_eq!(stable_hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(stable_hash("a"), 0xaf63_dc4c_8601_ec8c);
+
+        let foo = snippet("lemma:foo", &[&["a"], &["b"]]);
+        let hash = content_hash(&foo);
+        assert_eq!(hash.len(), 16);
+
+        // The name and the place in the theory do not matter.
+        let mut moved = snippet("lemma:bar", &[&["a"], &["b"]]);
+        moved.source_lines = Some((10, 11));
+        assert_eq!(content_hash(&moved), hash);
+        assert_ne!(content_hash(&snippet("lemma:foo", &[&["a"], &["c"]])), hash);
+
+        let manifest = render_manifest(&[foo]);
+        assert!(
+            manifest.contains(&format!("\"content_hash\": \"{}\"", hash)),
+            "{}",
+            manifest
+        );
+    }
+
+    #[test]
+    fn include_imports_adds_the_imported_theories() {
+        let dir = tempdir().unwrap();
+        let thy = |name: &str, imports: &str| {
+            let thy = format!(
+                "theory {} imports {} begin\nlemma {}: \"True\"\n  by simp\nend\n",
+                name,
+                imports,
+                name.to_lowercase()
+            );
+            write_thy(dir.path(), name, &thy);
+        };
+        thy("A", "Main B");
+        thy("B", "\"C\"");
+        thy("C", "Main");
+        thy("D", "Main");
+        fs::write(dir.path().join("ROOT"), "session Foo = HOL + theories A D").unwrap();
+        let path = dir.path().display().to_string();
+
+        let (config, _) = parse(&[&path, "out.tex", "-include-imports"]);
+        let imported = with_imports(dir.path(), vec!["A".into()], &[], &config);
+        assert_eq!(imported, ["A", "C", "B"]);
+
+        let config = with_runner(config, &Fake::default());
+        let input = Input {
+            path: path.clone(),
+            ..Default::default()
+        };
+        let temp = tempdir().unwrap();
+        let snippets = build_input(&input, vec!["A".into()], false, &config, temp.path()).unwrap();
+        let mut lemmas: Vec<String> = (snippets.iter())
+            .filter(|s| s.cmd == "lemma")
+            .map(|s| base_name(&s.prefix, &s.name))
+            .collect();
+        lemmas.sort();
+        assert_eq!(lemmas, ["A:lemma:a", "B:lemma:b", "C:lemma:c"]);
+
+        // A cycle is broken where it closes.
+        thy("C", "A");
+        assert_eq!(
+            with_imports(dir.path(), vec!["A".into()], &[], &config),
+            ["A", "C", "B"]
+        );
+    }
+
+    #[test]
+    fn macros_define_the_macros_in_use() {
+        let macros = |options: &[&str]| {
+            let mut args = vec!["Foo.thy", "out.tex"];
+            args.extend(options);
+            render_macros(&parse(&args).0)
+        };
+
+        let plain = macros(&[]);
+        assert!(
+            plain.contains("\\providecommand{\\DefineSnippet}[2]"),
+            "{}",
+            plain
+        );
+        assert!(
+            plain.contains("\\providecommand{\\Snippet}[1]"),
+            "{}",
+            plain
+        );
+        assert!(!plain.contains("InlineSnippet"), "{}", plain);
+        assert!(!plain.contains("newenvironment"), "{}", plain);
+
+        let all = macros(&[
+            "-command-arg",
+            "-inline-short",
+            "-wrap-env",
+            "snippet",
+            "-toc",
+        ]);
+        for definition in [
+            "\\providecommand{\\DefineSnippet}[3]",
+            "\\providecommand{\\InlineSnippet}[3]",
+            "\\newenvironment{snippet}{}{}",
+            "\\providecommand{\\SnippetTheory}[1]",
+        ] {
+            assert!(all.contains(definition), "{}", all);
+        }
+    }
+
+    #[test]
+    fn theory_order_orders_the_output() {
+        let dir = tempdir().unwrap();
+        for name in ["A", "B", "C"] {
+            let thy = format!(
+                "theory {} imports Main begin\nlemma {}: \"True\"\n  by simp\nend\n",
+                name,
+                name.to_lowercase()
+            );
+            write_thy(dir.path(), name, &thy);
+        }
+        fs::write(
+            dir.path().join("ROOT"),
+            "session Foo = HOL + theories A B C",
+        )
+        .unwrap();
+        let path = dir.path().display().to_string();
+        let out = dir.path().join("out.tex");
+        let lemmas = |options: &[&str]| {
+            let out_path = out.display().to_string();
+            let mut args = vec![path.as_str(), &out_path];
+            args.extend(options);
+            let (config, args) = parse(&args);
+            let config = with_runner(config, &Fake::default());
+            let temp = tempdir().unwrap();
+            assert_eq!(run(&config, &args, temp.path()).unwrap(), 0);
+            let tex = fs::read_to_string(&out).unwrap();
+            (labels(&tex).into_iter())
+                .filter(|l| l.contains("lemma") && l.ends_with("/0"))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+
+        let ordered = ["C:lemma:c/0", "A:lemma:a/0", "B:lemma:b/0"];
+        assert_eq!(lemmas(&["-theory-order", "C,A"]), ordered);
+        assert_eq!(
+            lemmas(&["-theory-order", "C,A", "-only-ordered"]),
+            ordered[..2]
+        );
+    }
+
+    #[test]
+    fn trims_the_head_and_tail_of_a_snippet() {
+        let lemma = || {
+            let mut lemma = snippet(
+                "lemma:foo",
+                &[
+                    &["%\\isanewline"],
+                    &["a\\isanewline"],
+                    &["b\\isanewline"],
+                    &["c\\isanewline"],
+                    &["done"],
+                ],
+            );
+            lemma.source_lines = Some((3, 7));
+            lemma
+        };
+
+        let mut trimmed = lemma();
+        trim_snippet(&mut trimmed, 1, 2, false);
+        assert_eq!(trimmed.parts, [["a\\isanewline"], ["b"]]);
+        assert_eq!(trimmed.source_lines, Some((4, 5)));
+
+        let mut raw = lemma();
+        trim_snippet(&mut raw, 0, 1, true);
+        assert_eq!(raw.parts.last().unwrap(), &["c\\isanewline"]);
+        assert_eq!(raw.source_lines, Some((3, 6)));
+
+        // Nothing is left to show, so the snippet is kept as it is.
+        let mut kept = lemma();
+        trim_snippet(&mut kept, 3, 2, false);
+        assert_eq!(kept.parts, lemma().parts);
+
+        let snippets = build_foo(&["-trim-tail", "1"]);
+        assert_eq!(find(&snippets, "lemma:foo").parts.len(), 1);
+    }
+
+    #[test]
+    fn color_never_has_no_color_codes() {
+        let color = |value: &str| parse(&["Foo.thy", "out.tex", "-color", value]).0.color;
+        assert_eq!(color("never"), Color::Never);
+        assert_eq!(color("always"), Color::Always);
+        assert_eq!(color("auto"), Color::Auto);
+        let args = ["isasnips", "Foo.thy", "out.tex", "-color", "red"].map(String::from);
+        assert!(parse_args(&args).is_err());
+
+        assert!(Color::Always.enabled());
+        assert!(!Color::Never.enabled());
+        // Colors are off in the tests, as with -color never.
+        COLOR.store(Color::Never.enabled(), Ordering::Relaxed);
+        assert_eq!(paint(RED, "Error: x"), "Error: x");
+        assert!(!paint(DIM, ">>> isabelle build").contains('\x1b'));
+    }
+
+    #[test]
+    fn tag_selects_the_tagged_theories() {
+        let dir = foo_dir();
+        let bar_thy =
+            "theory Bar imports Main begin\n(* paper *)\nlemma bar: \"True\"\n  by simp\nend\n";
+        write_thy(dir.path(), "Bar", bar_thy);
+        fs::write(
+            dir.path().join("ROOT"),
+            "session Foo = HOL + theories Foo Bar",
+        )
+        .unwrap();
+        let path = dir.path().display().to_string();
+        let config = with_runner(
+            parse(&[&path, "out.tex", "-tag", "paper"]).0,
+            &Fake::default(),
+        );
+        let input = Input {
+            path,
+            ..Default::default()
+        };
+
+        let temp = tempdir().unwrap();
+        let snippets = build_input(&input, vec![], false, &config, temp.path()).unwrap();
+        assert_eq!(names(&snippets), ["lemma:bar"]);
+        // The untagged theory is still built, as it may be imported.
+        assert!(temp.path().join("Foo.thy").is_file());
+
+        assert!(has_tag(&dir.path().join("Bar.thy"), &config).unwrap());
+        assert!(!has_tag(&dir.path().join("Foo.thy"), &config).unwrap());
+    }
+
+    #[test]
+    fn mismatched_output_extensions_are_warned_about() {
+        let warning = |path: &str, format| output_ext_warning(Path::new(path), format);
+        assert_eq!(warning("out.tex", Format::Latex), None);
+        assert_eq!(warning("out.markdown", Format::Markdown), None);
+        assert_eq!(
+            warning("out.md", Format::Latex).as_deref(),
+            Some("the snippets are written as LaTeX to out.md, which looks like Markdown. Use -format to change the format.")
+        );
+        assert_eq!(
+            warning("out.txt", Format::Latex).as_deref(),
+            Some("the output out.txt does not end in .tex, but the snippets are written as LaTeX.")
+        );
+        let dir = tempdir().unwrap();
+        let dir = dir.path().display().to_string();
+        assert_eq!(
+            warning(&dir, Format::Latex),
+            Some(format!("the output {} is a directory.", dir))
+        );
+    }
+
+    #[test]
+    fn base_dir_keeps_the_layout_for_imports() {
+        let project = tempdir().unwrap();
+        let sub = project.path().join("Sub");
+        let common = project.path().join("Common");
+        fs::create_dir_all(&sub).unwrap();
+        fs::create_dir_all(&common).unwrap();
+        let foo = FOO.replace("imports Main", "imports \"../Common/Lists\"");
+        let thy = write_thy(&sub, "Foo", &foo).display().to_string();
+        write_thy(&common, "Lists", "theory Lists imports Main begin\nend\n");
+        let base_dir = project.path().display().to_string();
+        let (config, _) = parse(&[&thy, "out.tex", "-base-dir", &base_dir]);
+        let config = with_runner(config, &Fake::default());
+        let input = Input {
+            path: thy,
+            ..Default::default()
+        };
+
+        let temp = tempdir().unwrap();
+        let snippets = build_input(&input, vec![], false, &config, temp.path()).unwrap();
+        assert_eq!(names(&snippets), ["lemma:foo", "definition:bar"]);
+        assert!(temp.path().join("Sub").join("Foo.thy").is_file());
+        assert!(temp.path().join("Common").join("Lists.thy").is_file());
+        let root = fs::read_to_string(temp.path().join("ROOT")).unwrap();
+        assert!(root.contains("    \"Sub/Foo\"\n"), "{}", root);
+
+        // A theory outside of the base directory cannot keep its place.
+        let outside = foo_dir();
+        let thy = outside.path().join("Foo.thy").display().to_string();
+        let (config, _) = parse(&[&thy, "out.tex", "-base-dir", &base_dir]);
+        let e = session_path(Path::new(&thy), &config).unwrap_err();
+        assert!(
+            e.to_string().contains("is not inside the base directory"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn self_test_finds_the_sample_snippets() {
+        let fake = Fake::default();
+        let config = with_runner(parse(&["-self-test"]).0, &fake);
+        let temp = tempdir().unwrap();
+        assert_eq!(self_test(&config, temp.path()).unwrap(), 0);
+        assert_eq!(fake.builds(), 1);
+        let root = fs::read_to_string(temp.path().join("build").join("ROOT")).unwrap();
+        assert!(root.contains("SelfTest"), "{}", root);
+
+        // Without the definition, the self-test fails.
+        let config = with_runner(parse(&["-self-test", "-only-commands", "lemma"]).0, &fake);
+        let temp = tempdir().unwrap();
+        assert_eq!(self_test(&config, temp.path()).unwrap(), 1);
+    }
+
+    #[test]
+    fn save_annotated_writes_the_theories_with_markers() {
+        let dir = foo_dir();
+        let sub = dir.path().join("Sub");
+        fs::create_dir(&sub).unwrap();
+        write_thy(
+            &sub,
+            "Bar",
+            "theory Bar imports Main begin\nlemma bar: \"True\"\n  by simp\nend\n",
+        );
+        fs::write(
+            dir.path().join("ROOT"),
+            "session Foo = HOL + theories Foo \"Sub/Bar\"",
+        )
+        .unwrap();
+        let path = dir.path().display().to_string();
+        let saved = tempdir().unwrap();
+        let saved_path = saved.path().display().to_string();
+        let (config, _) = parse(&[&path, "out.tex", "-save-annotated", &saved_path]);
+        let config = with_runner(config, &Fake::default());
+        let input = Input {
+            path,
+            ..Default::default()
+        };
+
+        let temp = tempdir().unwrap();
+        build_input(&input, vec![], false, &config, temp.path()).unwrap();
+        for (relative, marker) in [
+            (
+                "Foo.thy",
+                "text_raw \\<open>DefineSnippet lemma:foo lemma 3 4\\<close>",
+            ),
+            (
+                "Sub/Bar.thy",
+                "text_raw \\<open>DefineSnippet lemma:bar lemma 2 3\\<close>",
+            ),
+        ] {
+            let annotated = fs::read_to_string(saved.path().join(relative)).unwrap();
+            assert!(annotated.contains(marker), "{}", annotated);
+            assert_eq!(
+                annotated,
+                fs::read_to_string(temp.path().join(relative)).unwrap()
+            );
+        }
+        assert!(!saved.path().join("ROOT").exists());
+    }
+
+    #[test]
+    fn plan_prints_what_would_be_built() {
+        let dir = foo_dir();
+        let thy = dir.path().join("Foo.thy").display().to_string();
+        let out = dir.path().join("out.tex").display().to_string();
+        let (config, args) = parse(&[&thy, &out, "-plan", "-base", "HOL-Library"]);
+        let fake = Fake::default();
+        let config = with_runner(config, &fake);
+
+        let mut printed = vec![];
+        plan(&config, &args, &mut printed).unwrap();
+        let printed = String::from_utf8(printed).unwrap();
+        let lines: Vec<&str> = printed.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                format!("Input: {}", thy).as_str(),
+                "  Theories: Foo",
+                "  ROOT:",
+                "    (* Generated by isasnips. *)",
+                "",
+                "    session isasnips = \"HOL-Library\" +",
+                "      theories",
+                "        Foo",
+                "      document_files",
+                "        \"root.tex\"",
+                "  Command: isabelle build -c -D . -o document=pdf -o document_output=output",
+                format!("Output: {}", out).as_str(),
+            ]
+        );
+        assert!(fake.calls().is_empty());
+        assert_eq!(files(dir.path()).len(), 1);
+    }
+
+    #[test]
+    fn provenance_comments_precede_the_snippets() {
+        let mut lemma = snippet("lemma:foo", &[&["a"], &["b"]]);
+        lemma.source_lines = Some((12, 18));
+        let mut bar = snippet("definition:bar", &[&["c"]]);
+        bar.source_lines = Some((20, 20));
+        let mut text = snippet("text:intro", &[&["d"]]);
+        text.source_lines = None;
+        let snippets = [lemma, bar, text];
+
+        let out = render(&snippets, &["-provenance-comments"]);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "% from Foo.thy:12-18",
+                "\\DefineSnippet{lemma:foo/0}{%",
+                "a",
+                "}%EndSnippet",
+                "\\DefineSnippet{lemma:foo/1}{%",
+                "b",
+                "}%EndSnippet",
+                "% from Foo.thy:20",
+                "\\DefineSnippet{definition:bar/0}{%",
+                "c",
+                "}%EndSnippet",
+                "% from Foo.thy",
+                "\\DefineSnippet{text:intro/0}{%",
+                "d",
+                "}%EndSnippet",
+            ]
+        );
+        assert!(!render(&snippets, &[]).contains("% from"));
+    }
+
+    #[test]
+    fn threads_are_split_over_the_cpus() {
+        assert_eq!(thread_split(4, 8), (4, 2));
+        assert_eq!(thread_split(3, 8), (3, 2));
+        assert_eq!(thread_split(16, 8), (16, 1));
+        assert_eq!(thread_split(0, 8), (8, 1));
+
+        let args = args_of(&["-threads", "1"]);
+        let jobs = available_cpus().to_string();
+        assert!(args.ends_with(&["-o", "threads=1", "-j", jobs.as_str()].map(String::from)));
+        let args = args_of(&["-threads", "auto"]);
+        let threads = format!("threads={}", available_cpus());
+        assert!(args.ends_with(&["-o", threads.as_str(), "-j", "1"].map(String::from)));
+        assert!(!args_of(&[]).iter().any(|a| a.starts_with("threads=")));
+    }
+
+    #[test]
+    fn summary_has_the_counts() {
+        assert_eq!(
+            summary(5, 42_140, 87, false),
+            "Built 5 theories in 42.1s, wrote 87 snippets"
+        );
+        assert_eq!(
+            summary(1, 900, 1, false),
+            "Built 1 theory in 0.9s, wrote 1 snippet"
+        );
+        assert_eq!(
+            summary(2, 0, 3, true),
+            "Built 2 theories in 0.0s, checked 3 snippets"
+        );
+    }
+
+    #[test]
+    fn output_template_names_the_theory_files() {
+        let (config, _) = parse(&["Foo.thy", "out"]);
+        let path = |out: &str, index| theory_path(Path::new(out), "Foo", index, &config);
+        assert_eq!(path("out", 1), Path::new("out/Foo.tex"));
+        assert_eq!(
+            path("figs/{index}-{theory}_snippets.tex", 2),
+            Path::new("figs/2-Foo_snippets.tex")
+        );
+
+        let dir = foo_dir();
+        let bar_thy = "theory Bar imports Main begin\nlemma bar: \"True\"\n  by simp\nend\n";
+        let bar_path = write_thy(dir.path(), "Bar", bar_thy).display().to_string();
+        let foo_path = dir.path().join("Foo.thy").display().to_string();
+        let figs = dir.path().join("figs");
+        let template = figs
+            .join("{index}-{theory}_snippets.tex")
+            .display()
+            .to_string();
+        let (config, args) = parse(&[&foo_path, &template, "-input", &bar_path]);
+        let config = with_runner(config, &Fake::default());
+        let temp = tempdir().unwrap();
+        assert_eq!(run(&config, &args, temp.path()).unwrap(), 0);
+
+        let written: Vec<PathBuf> = files(&figs).into_iter().map(|(path, _)| path).collect();
+        assert_eq!(
+            written,
+            [
+                figs.join("1-Foo_snippets.tex"),
+                figs.join("2-Bar_snippets.tex")
+            ]
+        );
+        let bar = fs::read_to_string(figs.join("2-Bar_snippets.tex")).unwrap();
+        assert!(bar.contains("{Bar:lemma:bar/0}"), "{}", bar);
+    }
+
+    #[test]
+    fn parallel_builds_keep_the_input_order() {
+        let dir = foo_dir();
+        let thy = |name: &str| {
+            let thy = format!(
+                "theory {} imports Main begin\nlemma {}: \"True\"\n  by simp\nend\n",
+                name,
+                name.to_lowercase()
+            );
+            write_thy(dir.path(), name, &thy).display().to_string()
+        };
+        let (foo, bar, baz) = (
+            dir.path().join("Foo.thy").display().to_string(),
+            thy("Bar"),
+            thy("Baz"),
+        );
+        let out = dir.path().join("out.tex").display().to_string();
+        let run_with = |baz: &str| {
+            let args = [
+                foo.as_str(),
+                &out,
+                "-input",
+                &bar,
+                "-input",
+                baz,
+                "-parallel-builds",
+                "2",
+            ];
+            let (config, args) = parse(&args);
+            let fake = Fake::default();
+            let config = with_runner(config, &fake);
+            let temp = tempdir().unwrap();
+            (run(&config, &args, temp.path()), fake.builds())
+        };
+
+        let (result, builds) = run_with(&baz);
+        assert_eq!(result.unwrap(), 0);
+        assert_eq!(builds, 3);
+        let lemmas: Vec<String> = labels(&fs::read_to_string(&out).unwrap())
+            .into_iter()
+            .filter(|l| l.contains("lemma") && l.ends_with("/0"))
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            lemmas,
+            ["Foo:lemma:foo/0", "Bar:lemma:bar/0", "Baz:lemma:baz/0"]
+        );
+
+        // The other builds still finish, and the failure is reported with its input.
+        let failing = format!("{}:HOL-Nope", baz);
+        let (result, builds) = run_with(&failing);
+        let e = result.unwrap_err().to_string();
+        assert!(e.starts_with("1 of 3 inputs failed:\n"), "{}", e);
+        assert!(
+            e.contains(&format!("{}: base session HOL-Nope", baz)),
+            "{}",
+            e
+        );
+        assert_eq!(builds, 2);
+
+        assert_eq!(
+            output_line("  ", "[Foo.thy] ", "Building"),
+            "  [Foo.thy] Building"
+        );
+    }
+
+    #[test]
+    fn map_only_prints_the_snippet_map() {
+        let dir = foo_dir();
+        let thy = dir.path().join("Foo.thy").display().to_string();
+        let out_dir = dir.path().join("annotated");
+        let out_path = out_dir.display().to_string();
+        let (config, args) = parse(&[&thy, "-map-only", "-out-dir", &out_path]);
+        let fake = Fake::default();
+        let config = with_runner(config, &fake);
+
+        let mut printed = vec![];
+        map_only(&config, &args, &mut printed).unwrap();
+        let map = String::from_utf8(printed).unwrap();
+        let lines: Vec<&str> = map.lines().collect();
+        let entry = |cmd: &str, name: &str, start: usize, end: usize| {
+            format!(
+                "  {{\"theory\": \"Foo\", \"command\": \"{}\", \"name\": \"{}\", \"start_line\": {}, \"end_line\": {}}}",
+                cmd, name, start, end
+            )
+        };
+        assert_eq!(
+            lines,
+            [
+                "[".to_string(),
+                entry("theory", "theory:Foo", 1, 1) + ",",
+                entry("lemma", "lemma:foo", 3, 4) + ",",
+                entry("definition", "definition:bar", 6, 6) + ",",
+                entry("end", "end:7158f6523c28304a", 8, 8),
+                "]".to_string(),
+            ]
+        );
+        assert!(fake.calls().is_empty());
+
+        let annotated = fs::read_to_string(out_dir.join("Foo.thy")).unwrap();
+        assert!(annotated.contains("text_raw \\<open>DefineSnippet lemma:foo lemma 3 4\\<close>"));
+    }
+
+    #[test]
+    fn quick_and_dirty_warns_or_is_refused() {
+        let dir = foo_dir();
+        let thy = dir.path().join("Foo.thy").display().to_string();
+        let input = Input {
+            path: thy.clone(),
+            ..Default::default()
+        };
+        let build = |options: &[&str], fake: &Fake| {
+            let mut args = vec![thy.as_str(), "out.tex", "-quick-and-dirty"];
+            args.extend(options);
+            let config = with_runner(parse(&args).0, fake);
+            let temp = tempdir().unwrap();
+            build_input(&input, vec![], false, &config, temp.path())
+        };
+
+        // Other tests may warn at the same time, but never take warnings back.
+        let fake = Fake::default();
+        let before = WARNINGS.load(Ordering::Relaxed);
+        assert_eq!(
+            names(&build(&[], &fake).unwrap()),
+            ["lemma:foo", "definition:bar"]
+        );
+        assert!(WARNINGS.load(Ordering::Relaxed) > before);
+        assert!(fake
+            .calls()
+            .iter()
+            .any(|c| c.contains("-o quick_and_dirty")));
+
+        let fake = Fake::default();
+        let e = build(&["-require-complete"], &fake)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            e,
+            format!(
+                "{}: quick_and_dirty is enabled, which -require-complete does not allow",
+                thy
+            )
+        );
+        assert!(fake.calls().is_empty());
+    }
+
+    #[test]
+    fn labels_survive_renaming_a_lemma() {
+        let dir = foo_dir();
+        fs::create_dir(dir.path().join("out")).unwrap();
+        let table = dir.path().join("labels.toml");
+        let table_path = table.display().to_string();
+        let out = dir.path().join("out").join("snippets.tex");
+        let lemma_labels = |locator: &str| {
+            fs::write(
+                &table,
+                format!("# Stable labels\nfoo-intro = \"{}\"\n", locator),
+            )
+            .unwrap();
+            assert_eq!(run_foo(dir.path(), &["-labels", &table_path]).unwrap(), 0);
+            let tex = fs::read_to_string(&out).unwrap();
+            labels(&tex)
+                .into_iter()
+                .filter(|l| !l.starts_with("theory:") && !l.starts_with("end:"))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+
+        let labelled = ["foo-intro/0", "foo-intro/1", "definition:bar/0"];
+        assert_eq!(lemma_labels("Foo.lemma:foo"), labelled);
+
+        // After renaming the lemma, only the label file changes, not the references.
+        let renamed = FOO.replace("lemma foo:", "lemma foo_intro:");
+        fs::write(dir.path().join("Foo.thy"), renamed).unwrap();
+        assert_eq!(lemma_labels("lemma:foo_intro"), labelled);
+
+        let error = |s: &str| parse_labels(s).unwrap_err();
+        assert_eq!(error("a"), "line 1: expected label = \"Theory.name\"");
+        assert_eq!(error("a = "), "line 1: missing label or snippet name");
+        assert!(error("a b = Foo.lemma:foo").starts_with("line 1: invalid label a b"));
+        assert_eq!(error("a = x\na = y"), "line 2: duplicate label a");
+    }
+
+    #[test]
+    fn toc_heads_the_snippets_of_every_theory() {
+        let mut bar = snippet("lemma:bar", &[&["b"]]);
+        bar.theory = "My_Bar".to_string();
+        let snippets = [
+            snippet("lemma:foo", &[&["a"]]),
+            snippet("definition:baz", &[&["c"]]),
+            bar,
+        ];
+        let out = render(&snippets, &["-toc"]);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "\\SnippetTheory{Foo}%",
+                "\\DefineSnippet{lemma:foo/0}{%",
+                "a",
+                "}%EndSnippet",
+                "\\DefineSnippet{definition:baz/0}{%",
+                "c",
+                "}%EndSnippet",
+                "\\SnippetTheory{My\\_Bar}%",
+                "\\DefineSnippet{lemma:bar/0}{%",
+                "b",
+                "}%EndSnippet",
+            ]
+        );
+        assert!(!render(&snippets, &[]).contains("SnippetTheory"));
+    }
+
+    #[test]
+    fn raw_source_builds_without_a_document() {
+        assert_eq!(
+            args_of(&["-raw-source"]),
+            ["build", "-c", "-D", ".", "-o", "document=false"]
+        );
+        let args = args_of(&["-raw-source", "-document-variant", "outline"]);
+        assert!(
+            !args.iter().any(|a| a.starts_with("document_")),
+            "{:?}",
+            args
+        );
+        assert_eq!(
+            args_of(&[]),
+            [
+                "build",
+                "-c",
+                "-D",
+                ".",
+                "-o",
+                "document=pdf",
+                "-o",
+                "document_output=output"
+            ]
+        );
+    }
+
+    #[test]
+    fn working_dir_is_below_the_temp_parent() {
+        let parent = tempdir().unwrap();
+        let parent_path = parent.path().display().to_string();
+        let (config, _) = parse(&["Foo.thy", "out.tex", "-temp-parent", &parent_path]);
+        let dir = working_dir(&config).unwrap();
+        assert!(dir.path().is_dir());
+        assert_eq!(dir.path().parent(), Some(parent.path()));
+        let path = dir.path().to_path_buf();
+        dir.close().unwrap();
+        assert!(!path.exists());
+
+        let missing = parent.path().join("missing").display().to_string();
+        let (config, _) = parse(&["Foo.thy", "out.tex", "-temp-parent", &missing]);
+        let e = working_dir(&config).unwrap_err().to_string();
+        assert_eq!(
+            e,
+            format!("The directory for -temp-parent does not exist: {}", missing)
+        );
+    }
+
+    #[test]
+    fn rst_has_a_label_and_an_indented_code_block() {
+        let lemma = snippet(
+            "lemma:foo",
+            &[
+                &["\\isacommand{lemma}\\ foo{\\isacharcolon}\\ {\\isachardoublequoteopen}{\\isasymforall}x{\\isachardot}\\ P\\ x{\\isachardoublequoteclose}\\isanewline"],
+                &["\\ \\ \\isacommand{by}\\ simp%"],
+            ],
+        );
+        let out = render(&[lemma], &["-format", "rst"]);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                ".. _`lemma:foo`:",
+                "",
+                ".. code-block:: isabelle",
+                "",
+                "   lemma foo: \"∀x. P x\"",
+                "     by simp",
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn preprocess_filters_the_theory_before_chunking() {
+        let snippets = build_foo(&["-preprocess", "sed 's/lemma foo/lemma filtered/'"]);
+        assert_eq!(names(&snippets), ["lemma:filtered", "definition:bar"]);
+
+        // A failing filter leaves the theory without snippets, or fails it with -strict.
+        let snippets = build_foo(&["-preprocess", "exit 3"]);
+        assert!(names(&snippets).is_empty());
+        let dir = foo_dir();
+        let thy = dir.path().join("Foo.thy");
+        let (config, _) = parse(&["Foo.thy", "out.tex", "-preprocess", "exit 3", "-strict"]);
+        let e = process_theory(&thy, &config).unwrap_err().to_string();
+        assert!(
+            e.starts_with(&format!("{}: -preprocess command failed", thy.display())),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn max_parts_joins_the_remaining_parts() {
+        let mut many = snippet("lemma:foo", &[]);
+        many.parts = ('a'..='l').map(|c| vec![c.to_string()]).collect();
+        cap_parts(&mut many, 3);
+        assert_eq!(many.parts.len(), 3);
+        assert_eq!(many.parts[..2], [["a"], ["b"]]);
+        assert_eq!(
+            many.parts[2],
+            ["c", "d", "e", "f", "g", "h", "i", "j", "k", "l"]
+        );
+
+        let mut few = snippet("lemma:foo", &[&["a"], &["b"]]);
+        cap_parts(&mut few, 3);
+        assert_eq!(few.parts, [["a"], ["b"]]);
+
+        let capped = build_foo(&["-max-parts", "1"]);
+        assert_eq!(find(&capped, "lemma:foo").parts.len(), 1);
+        let args = ["isasnips", "Foo.thy", "out.tex", "-max-parts", "0"].map(String::from);
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follow_symlinks_copies_linked_directories() {
+        use std::os::unix::fs::symlink;
+
+        let project = tempdir().unwrap();
+        let real = project.path().join("real");
+        let lib = project.path().join("lib");
+        fs::create_dir_all(&real).unwrap();
+        fs::create_dir_all(&lib).unwrap();
+        fs::write(real.join("Foo.thy"), FOO).unwrap();
+        write_thy(
+            &lib,
+            "Bar",
+            "theory Bar imports Main begin\nlemma bar: \"True\"\n  by simp\nend\n",
+        );
+        fs::write(
+            real.join("ROOT"),
+            "session Foo = HOL + theories Foo \"Lib/Bar\"",
+        )
+        .unwrap();
+        symlink(&lib, real.join("Lib")).unwrap();
+        // A cycle is skipped instead of walked forever.
+        symlink(&real, real.join("Loop")).unwrap();
+        let link = project.path().join("link");
+        symlink(&real, &link).unwrap();
+
+        let path = link.display().to_string();
+        let build = |options: &[&str]| {
+            let mut args = vec![path.as_str(), "out.tex"];
+            args.extend(options);
+            let config = with_runner(parse(&args).0, &Fake::default());
+            let input = Input {
+                path: path.clone(),
+                ..Default::default()
+            };
+            let temp = tempdir().unwrap();
+            let snippets = build_input(&input, vec![], false, &config, temp.path()).unwrap();
+            let mut lemmas: Vec<String> = (snippets.iter())
+                .filter(|s| s.cmd == "lemma")
+                .map(|s| s.name.clone())
+                .collect();
+            lemmas.sort();
+            (lemmas, temp.path().join("Lib").join("Bar.thy").is_file())
+        };
+
+        // The input itself is followed, the links inside it only with the option.
+        assert_eq!(build(&[]), (vec!["lemma:foo".to_string()], false));
+        assert_eq!(
+            build(&["-follow-symlinks"]),
+            (vec!["lemma:bar".to_string(), "lemma:foo".to_string()], true)
+        );
+    }
+
+    #[test]
+    fn copies_a_session_in_memory() {
+        let mem = MemFs::default();
+        mem.create_dir_all(Path::new("/in/document")).unwrap();
+        mem.write(Path::new("/in/Foo.thy"), FOO.as_bytes()).unwrap();
+        mem.write(Path::new("/in/document/root.tex"), b"root")
+            .unwrap();
+        mem.create_dir_all(Path::new("/work")).unwrap();
+        let config = with_fs(parse(&[]).0, &mem);
+
+        let theories = copy_isabelle(Path::new("/in"), Path::new("/work"), &[], &[], &config);
+        assert_eq!(theories.unwrap(), ["Foo"]);
+        let copied: Vec<PathBuf> = mem
+            .files(Path::new("/work"))
+            .into_iter()
+            .map(|(p, _)| p)
+            .collect();
+        assert_eq!(
+            copied,
+            [
+                Path::new("/work/Foo.thy"),
+                Path::new("/work/document/root.tex")
+            ]
+        );
+        let thy = mem.read_to_string(Path::new("/work/Foo.thy")).unwrap();
+        assert!(thy.contains("DefineSnippet"), "{}", thy);
+    }
+
+    #[test]
+    fn makes_a_root_in_memory() {
+        // The theory is found on the disk, but read from memory.
+        let dir = foo_dir();
+        let thy = dir.path().join("Foo.thy");
+        let mem = MemFs::default();
+        mem.create_dir_all(dir.path()).unwrap();
+        mem.write(&thy, FOO.replace("foo", "mem").as_bytes())
+            .unwrap();
+        let config = with_fs(with_runner(parse(&[]).0, &Fake::default()), &mem);
+
+        let temp = tempdir().unwrap();
+        mem.create_dir_all(temp.path()).unwrap();
+        mkroot(&thy, temp.path(), "HOL", &config).unwrap();
+        let root = mem.read_to_string(&temp.path().join("ROOT")).unwrap();
+        assert!(root.contains("theories\n    Foo"), "{}", root);
+        let annotated = mem.read_to_string(&temp.path().join("Foo.thy")).unwrap();
+        assert!(annotated.contains("lemma:mem"), "{}", annotated);
+        assert!(!temp.path().join("Foo.thy").exists());
+    }
+
+    #[test]
+    fn outputs_snippets_in_memory() {
+        let mem = MemFs::default();
+        mem.create_dir_all(Path::new("/doc")).unwrap();
+        mem.write(Path::new("/doc/Foo.tex"), FOO_TEX.as_bytes())
+            .unwrap();
+        let config = with_fs(parse(&["-checksum", "/out/sums"]).0, &mem);
+
+        let theories = [OsString::from("Foo")];
+        let doc = Path::new("/doc");
+        let snippets =
+            extract_snippets(&mem, doc, &theories, false, Backend::Latex, &HashMap::new());
+        let snippets = snippets.unwrap();
+        assert_eq!(names(&snippets), ["lemma:foo"]);
+
+        let out = Path::new("/out/snippets.tex");
+        let rendered = render_snippets(&snippets, &config);
+        assert!(output_snippets(out, &rendered, None, &config).is_err());
+        mem.create_dir_all(Path::new("/out")).unwrap();
+        assert!(output_snippets(out, &rendered, None, &config).unwrap());
+        assert!(!output_snippets(out, &rendered, None, &config).unwrap());
+        assert_eq!(mem.read_to_string(out).unwrap(), rendered);
+
+        write_checksums(&[out.to_path_buf()], &config).unwrap();
+        let sums = mem.read_to_string(Path::new("/out/sums")).unwrap();
+        let sum = sha256_hex(rendered.as_bytes());
+        assert_eq!(sums, format!("{}  /out/snippets.tex\n", sum));
+    }
+
+    #[test]
+    fn retries_a_transient_failure() {
+        let fake = Fake::failing(&["*** Database is locked"]);
+        let temp = tempdir().unwrap();
+        call_isabelle_retrying(&fake, temp.path(), &["build"], "", 1).unwrap();
+        assert_eq!(fake.builds(), 2);
+    }
+
+    #[test]
+    fn gives_up_after_the_retries() {
+        let fake = Fake::failing(&["Could not lock heap", "Database is locked"]);
+        let temp = tempdir().unwrap();
+        assert!(call_isabelle_retrying(&fake, temp.path(), &["build"], "", 1).is_err());
+        assert_eq!(fake.builds(), 2);
+    }
+
+    #[test]
+    fn does_not_retry_other_failures() {
+        let fake = Fake::failing(&["*** Undefined fact: foo"]);
+        let temp = tempdir().unwrap();
+        assert!(call_isabelle_retrying(&fake, temp.path(), &["build"], "", 3).is_err());
+        assert_eq!(fake.builds(), 1);
+    }
+
+    #[test]
+    fn transient_output() {
+        assert!(is_transient(&["*** Database is locked".to_string()]));
+        assert!(is_transient(&[
+            "Resource temporarily unavailable".to_string()
+        ]));
+        assert!(!is_transient(&["*** Failed to finish proof".to_string()]));
+        assert!(!is_transient(&[]));
+    }
 }
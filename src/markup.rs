@@ -0,0 +1,65 @@
+//! Isabelle's tag markup in the generated LaTeX.
+//!
+//! Isabelle wraps proofs, ML and other tagged parts of a theory in macros like
+//! `\isatagproof` and `\endisatagproof`, which `\isakeeptag` and friends use to
+//! keep, drop or fold them. A snippet can start or end between such a pair, so
+//! the macros are not balanced within it.
+
+/// Macros that are followed by the name of a tag, like `\isatagproof`.
+pub const TAG_MACROS: &[&str] = &["isadelim", "endisadelim", "isatag", "endisatag", "isafold"];
+
+/// Macros that are removed as they are.
+pub const MARKUP_MACROS: &[&str] = &["isamarkupfalse", "isamarkuptrue"];
+
+/// Removes the tag markup from a line of generated LaTeX.
+/// A folded tag like `{\isafoldproof}` is removed with its braces.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::markup::strip_markup;
+///
+/// assert_eq!(strip_markup("\\isacommand{by}\\isamarkupfalse%"), "\\isacommand{by}%");
+/// assert_eq!(strip_markup("\\endisatagproof"), "");
+/// assert_eq!(strip_markup("{\\isafoldproof}%"), "%");
+/// assert_eq!(strip_markup("\\isadelimML\\ x\\isanewline"), "\\ x\\isanewline");
+/// // A line break is not the start of a macro.
+/// assert_eq!(strip_markup("a\\\\isatagproof"), "a\\\\isatagproof");
+/// ```
+pub fn strip_markup(line: &str) -> String {
+    let mut out = String::new();
+    let mut rest = line;
+
+    while let Some(i) = rest.find('\\') {
+        out.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        let len = after
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(after.len());
+
+        // A backslash followed by anything else, like the line break \\, is kept as it is.
+        if len == 0 {
+            let next = after.chars().next().map_or(0, char::len_utf8);
+            out.push_str(&rest[i..i + 1 + next]);
+            rest = &after[next..];
+            continue;
+        }
+
+        let name = &after[..len];
+        let tagged = TAG_MACROS
+            .iter()
+            .any(|m| name.len() > m.len() && name.starts_with(m));
+        if !tagged && !MARKUP_MACROS.contains(&name) {
+            out.push('\\');
+            out.push_str(name);
+        } else if out.ends_with('{') && after[len..].starts_with('}') {
+            out.pop();
+            rest = &after[len + 1..];
+            continue;
+        }
+        rest = &after[len..];
+    }
+
+    out.push_str(rest);
+    out
+}
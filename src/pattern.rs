@@ -0,0 +1,258 @@
+//! Small regular expressions for selecting snippets by name.
+//!
+//! Only a common subset is supported: literals, `.`, classes like `[a-z]` and `[^:]`,
+//! the escapes `\d`, `\w` and `\s`, the anchors `^` and `$`, groups, alternatives
+//! with `|` and the repetitions `*`, `+` and `?`. Matching is by backtracking,
+//! which is plenty for names.
+
+/// A compiled pattern.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    source: String,
+    alternatives: Vec<Vec<Node>>,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Char(char),
+    Any,
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Start,
+    End,
+    Group(Vec<Vec<Node>>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+    },
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alternatives.push(self.sequence()?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, String> {
+        let mut nodes = vec![];
+        while let Some(c) = self.peek() {
+            let (min, max) = match c {
+                '|' | ')' => break,
+                '*' => (0, None),
+                '+' => (1, None),
+                '?' => (0, Some(1)),
+                _ => {
+                    nodes.push(self.atom()?);
+                    continue;
+                }
+            };
+            self.pos += 1;
+            let node = nodes
+                .pop()
+                .ok_or_else(|| format!("nothing to repeat before {}", c))?;
+            nodes.push(Node::Repeat {
+                node: Box::new(node),
+                min,
+                max,
+            });
+        }
+        Ok(nodes)
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some('(') => {
+                let group = self.alternatives()?;
+                match self.next() {
+                    Some(')') => Ok(Node::Group(group)),
+                    _ => Err("unclosed (".to_string()),
+                }
+            }
+            Some('[') => self.class(),
+            Some('.') => Ok(Node::Any),
+            Some('^') => Ok(Node::Start),
+            Some('$') => Ok(Node::End),
+            Some('\\') => self.escape(),
+            Some(c) => Ok(Node::Char(c)),
+            None => Err("unexpected end".to_string()),
+        }
+    }
+
+    fn escape(&mut self) -> Result<Node, String> {
+        let class = |ranges: &[(char, char)]| Node::Class {
+            ranges: ranges.to_vec(),
+            negated: false,
+        };
+        match self.next() {
+            Some('d') => Ok(class(&[('0', '9')])),
+            Some('w') => Ok(class(&[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')])),
+            Some('s') => Ok(class(&[
+                (' ', ' '),
+                ('\t', '\t'),
+                ('\n', '\n'),
+                ('\r', '\r'),
+            ])),
+            Some(c) => Ok(Node::Char(c)),
+            None => Err("trailing backslash".to_string()),
+        }
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+
+        let mut ranges = vec![];
+        // A ] right at the start is a literal.
+        let mut first = true;
+        loop {
+            let c = match self.next() {
+                Some(']') if !first => break,
+                Some('\\') => self.next().ok_or("trailing backslash")?,
+                Some(c) => c,
+                None => return Err("unclosed [".to_string()),
+            };
+            first = false;
+
+            let is_range = self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']');
+            if is_range {
+                self.pos += 1;
+                let end = match self.next() {
+                    Some('\\') => self.next().ok_or("trailing backslash")?,
+                    Some(end) => end,
+                    None => return Err("unclosed [".to_string()),
+                };
+                if end < c {
+                    return Err(format!("invalid range {}-{}", c, end));
+                }
+                ranges.push((c, end));
+            } else {
+                ranges.push((c, c));
+            }
+        }
+        Ok(Node::Class { ranges, negated })
+    }
+}
+
+impl Pattern {
+    /// Compiles a pattern, or describes why it is invalid.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use isasnips::pattern::Pattern;
+    ///
+    /// let pattern = Pattern::new("^lemma:sound").unwrap();
+    /// assert!(pattern.is_match("lemma:soundness"));
+    /// assert!(!pattern.is_match("lemma:completeness"));
+    ///
+    /// let pattern = Pattern::new("(lemma|theorem):.*-(2|3)$").unwrap();
+    /// assert!(pattern.is_match("theorem:foo-bar-3"));
+    /// assert!(!pattern.is_match("definition:foo-3"));
+    ///
+    /// assert!(Pattern::new("lemma:(foo").is_err());
+    /// assert!(Pattern::new("*foo").is_err());
+    /// ```
+    pub fn new(source: &str) -> Result<Pattern, String> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+        };
+        let alternatives = parser.alternatives()?;
+        if parser.pos < parser.chars.len() {
+            return Err("unmatched )".to_string());
+        }
+        Ok(Pattern {
+            source: source.to_string(),
+            alternatives,
+        })
+    }
+
+    /// The pattern as it was given.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the pattern matches anywhere in the text.
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        (0..=text.len())
+            .any(|start| match_alternatives(&self.alternatives, &text, start, &|_| true))
+    }
+}
+
+fn match_alternatives(
+    alternatives: &[Vec<Node>],
+    text: &[char],
+    pos: usize,
+    next: &dyn Fn(usize) -> bool,
+) -> bool {
+    alternatives
+        .iter()
+        .any(|nodes| match_sequence(nodes, text, pos, next))
+}
+
+fn match_sequence(nodes: &[Node], text: &[char], pos: usize, next: &dyn Fn(usize) -> bool) -> bool {
+    match nodes.split_first() {
+        None => next(pos),
+        Some((node, rest)) => match_node(node, text, pos, &|p| match_sequence(rest, text, p, next)),
+    }
+}
+
+fn match_node(node: &Node, text: &[char], pos: usize, next: &dyn Fn(usize) -> bool) -> bool {
+    match node {
+        Node::Char(c) => text.get(pos) == Some(c) && next(pos + 1),
+        Node::Any => pos < text.len() && next(pos + 1),
+        Node::Class { ranges, negated } => match text.get(pos) {
+            Some(c) => {
+                ranges.iter().any(|(lo, hi)| lo <= c && c <= hi) != *negated && next(pos + 1)
+            }
+            None => false,
+        },
+        Node::Start => pos == 0 && next(pos),
+        Node::End => pos == text.len() && next(pos),
+        Node::Group(alternatives) => match_alternatives(alternatives, text, pos, next),
+        Node::Repeat { node, min, max } => match_repeat(node, *min, *max, 0, text, pos, next),
+    }
+}
+
+// Greedy: one more repetition is tried first. Repetitions that match nothing
+// only count towards the minimum, so patterns like (a*)* terminate.
+fn match_repeat(
+    node: &Node,
+    min: usize,
+    max: Option<usize>,
+    count: usize,
+    text: &[char],
+    pos: usize,
+    next: &dyn Fn(usize) -> bool,
+) -> bool {
+    let more = max.is_none_or(|max| count < max)
+        && match_node(node, text, pos, &|p| {
+            (p != pos || count < min) && match_repeat(node, min, max, count + 1, text, p, next)
+        });
+    more || (count >= min && next(pos))
+}
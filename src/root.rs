@@ -0,0 +1,103 @@
+//! The ROOT file of the session that the snippets are built in.
+
+/// Quotes a session name like `HOL-Library`, as a ROOT file needs it.
+/// Names that are already quoted are used as given.
+pub fn session_name(name: &str) -> String {
+    if name.starts_with('"') || name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name)
+    }
+}
+
+/// A ROOT file with a single session.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::root::Root;
+///
+/// let root = Root::new("isasnips", "HOL-Library")
+///     .option("quick_and_dirty")
+///     .option("document_variants = document")
+///     .theory("Extra")
+///     .theory("\"Algebra/Group\"")
+///     .document_file("root.tex");
+/// let text = root.render();
+/// let lines: Vec<&str> = text.lines().collect();
+/// assert_eq!(
+///     lines,
+///     [
+///         "(* Generated by isasnips. *)",
+///         "",
+///         "session isasnips = \"HOL-Library\" +",
+///         "  options [quick_and_dirty, document_variants = document]",
+///         "  theories",
+///         "    Extra",
+///         "    \"Algebra/Group\"",
+///         "  document_files",
+///         "    \"root.tex\"",
+///     ]
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct Root {
+    session: String,
+    parent: String,
+    options: Vec<String>,
+    theories: Vec<String>,
+    document_files: Vec<String>,
+}
+
+impl Root {
+    /// A session with the given parent session, quoted if needed.
+    pub fn new(session: &str, parent: &str) -> Root {
+        Root {
+            session: session_name(session),
+            parent: session_name(parent),
+            options: vec![],
+            theories: vec![],
+            document_files: vec![],
+        }
+    }
+
+    /// Adds an option like `quick_and_dirty` or `document = pdf`.
+    pub fn option(mut self, option: &str) -> Root {
+        self.options.push(option.to_string());
+        self
+    }
+
+    /// Adds a theory, by name or by quoted path.
+    pub fn theory(mut self, theory: &str) -> Root {
+        self.theories.push(theory.to_string());
+        self
+    }
+
+    /// Adds a file below the `document` directory.
+    pub fn document_file(mut self, file: &str) -> Root {
+        self.document_files.push(file.to_string());
+        self
+    }
+
+    /// The text of the ROOT file, with every section on lines of its own.
+    pub fn render(&self) -> String {
+        let mut lines = vec![
+            "(* Generated by isasnips. *)".to_string(),
+            String::new(),
+            format!("session {} = {} +", self.session, self.parent),
+        ];
+        if !self.options.is_empty() {
+            lines.push(format!("  options [{}]", self.options.join(", ")));
+        }
+        if !self.theories.is_empty() {
+            lines.push("  theories".to_string());
+            lines.extend(self.theories.iter().map(|thy| format!("    {}", thy)));
+        }
+        if !self.document_files.is_empty() {
+            lines.push("  document_files".to_string());
+            lines.extend((self.document_files.iter()).map(|file| format!("    \"{}\"", file)));
+        }
+        lines.push(String::new());
+        lines.join("\n")
+    }
+}
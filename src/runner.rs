@@ -0,0 +1,142 @@
+//! Running Isabelle.
+//!
+//! The tool talks to Isabelle only through [`IsabelleRunner`], so the
+//! orchestration can be exercised with a fake runner instead of a real
+//! installation.
+
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+
+/// Something that runs `isabelle` with arguments in a directory.
+///
+/// Lines of standard output are passed to `line` as they are printed, since
+/// builds take long enough that their progress should be shown right away.
+pub trait IsabelleRunner: Sync {
+    fn run(
+        &self,
+        dir: &Path,
+        args: &[&str],
+        line: &mut dyn FnMut(String),
+    ) -> io::Result<ExitStatus>;
+
+    /// Like [`run`](IsabelleRunner::run), for queries whose failure the caller handles,
+    /// so their errors are not shown. By default it is the same as `run`.
+    fn query(
+        &self,
+        dir: &Path,
+        args: &[&str],
+        line: &mut dyn FnMut(String),
+    ) -> io::Result<ExitStatus> {
+        self.run(dir, args, line)
+    }
+}
+
+/// Runs the `isabelle` found on the `PATH`. Standard error is passed through,
+/// except for queries.
+pub struct SystemIsabelle;
+
+impl IsabelleRunner for SystemIsabelle {
+    fn run(
+        &self,
+        dir: &Path,
+        args: &[&str],
+        line: &mut dyn FnMut(String),
+    ) -> io::Result<ExitStatus> {
+        spawn(dir, args, line, Stdio::inherit())
+    }
+
+    fn query(
+        &self,
+        dir: &Path,
+        args: &[&str],
+        line: &mut dyn FnMut(String),
+    ) -> io::Result<ExitStatus> {
+        spawn(dir, args, line, Stdio::null())
+    }
+}
+
+fn spawn(
+    dir: &Path,
+    args: &[&str],
+    line: &mut dyn FnMut(String),
+    stderr: Stdio,
+) -> io::Result<ExitStatus> {
+    let mut child = Command::new("isabelle")
+        .current_dir(dir)
+        .stdout(Stdio::piped())
+        .stderr(stderr)
+        .args(args)
+        .spawn()?;
+
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| io::Error::other("Could not capture stdout."))?;
+
+    for output in io::BufReader::new(stdout).lines().map_while(Result::ok) {
+        line(output);
+    }
+
+    child.wait()
+}
+
+/// Lists the sessions known to Isabelle, including those in the given
+/// directories, or None if they cannot be queried. It is run as a query.
+///
+/// # Examples
+///
+/// ```
+/// # #[cfg(unix)] {
+/// use isasnips::runner::{sessions, IsabelleRunner};
+/// use std::io;
+/// use std::os::unix::process::ExitStatusExt;
+/// use std::path::Path;
+/// use std::process::ExitStatus;
+/// use std::sync::Mutex;
+///
+/// struct Fake {
+///     calls: Mutex<Vec<String>>,
+///     code: i32,
+/// }
+///
+/// impl IsabelleRunner for Fake {
+///     fn run(
+///         &self,
+///         _dir: &Path,
+///         args: &[&str],
+///         line: &mut dyn FnMut(String),
+///     ) -> io::Result<ExitStatus> {
+///         self.calls.lock().unwrap().push(args.join(" "));
+///         line("HOL".to_string());
+///         line("  HOL-Library ".to_string());
+///         Ok(ExitStatus::from_raw(self.code << 8))
+///     }
+/// }
+///
+/// let fake = Fake { calls: Mutex::new(vec![]), code: 0 };
+/// let dirs = vec!["deps".to_string()];
+/// assert_eq!(sessions(&fake, &dirs), Some(vec!["HOL".into(), "HOL-Library".into()]));
+/// assert_eq!(*fake.calls.lock().unwrap(), ["sessions -a -d deps"]);
+///
+/// let failing = Fake { calls: Mutex::new(vec![]), code: 1 };
+/// assert_eq!(sessions(&failing, &[]), None);
+/// # }
+/// ```
+pub fn sessions(runner: &dyn IsabelleRunner, dirs: &[String]) -> Option<Vec<String>> {
+    let mut args = vec!["sessions", "-a"];
+    for dir in dirs {
+        args.extend(["-d", dir.as_str()]);
+    }
+
+    let mut sessions = vec![];
+    let status = runner
+        .query(Path::new("."), &args, &mut |line| {
+            sessions.push(line.trim().to_string())
+        })
+        .ok()?;
+    if !status.success() {
+        return None;
+    }
+    Some(sessions)
+}
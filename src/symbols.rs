@@ -0,0 +1,160 @@
+// Unicode renderings of Isabelle symbols, keyed by the name inside \<...>.
+// Names with a leading ^ are control symbols like \<^sub>.
+// The table must stay sorted by name, so extend it in place.
+pub const SYMBOLS: &[(&str, &str)] = &[
+    ("And", "⋀"),
+    ("Colon", "∷"),
+    ("Delta", "Δ"),
+    ("Gamma", "Γ"),
+    ("Inter", "⋂"),
+    ("Lambda", "Λ"),
+    ("Longleftrightarrow", "⟺"),
+    ("Longrightarrow", "⟹"),
+    ("Or", "⋁"),
+    ("Phi", "Φ"),
+    ("Pi", "Π"),
+    ("Psi", "Ψ"),
+    ("Rightarrow", "⇒"),
+    ("Sigma", "Σ"),
+    ("Theta", "Θ"),
+    ("Union", "⋃"),
+    ("Xi", "Ξ"),
+    ("^sub", "⇩"),
+    ("^sup", "⇧"),
+    ("alpha", "α"),
+    ("and", "∧"),
+    ("beta", "β"),
+    ("bottom", "⊥"),
+    ("bullet", "∙"),
+    ("cdot", "⋅"),
+    ("chi", "χ"),
+    ("circ", "∘"),
+    ("close", "›"),
+    ("comment", "—"),
+    ("delta", "δ"),
+    ("diamond", "◇"),
+    ("epsilon", "ε"),
+    ("equiv", "≡"),
+    ("eta", "η"),
+    ("exists", "∃"),
+    ("forall", "∀"),
+    ("gamma", "γ"),
+    ("ge", "≥"),
+    ("ggreater", "≫"),
+    ("in", "∈"),
+    ("inter", "∩"),
+    ("iota", "ι"),
+    ("kappa", "κ"),
+    ("lambda", "λ"),
+    ("langle", "⟨"),
+    ("le", "≤"),
+    ("leftarrow", "←"),
+    ("leftrightarrow", "↔"),
+    ("lless", "≪"),
+    ("longleftrightarrow", "⟷"),
+    ("longrightarrow", "⟶"),
+    ("mapsto", "↦"),
+    ("mu", "μ"),
+    ("not", "¬"),
+    ("noteq", "≠"),
+    ("notin", "∉"),
+    ("nu", "ν"),
+    ("odot", "⊙"),
+    ("omega", "ω"),
+    ("ominus", "⊖"),
+    ("open", "‹"),
+    ("oplus", "⊕"),
+    ("or", "∨"),
+    ("otimes", "⊗"),
+    ("phi", "φ"),
+    ("pi", "π"),
+    ("prec", "≺"),
+    ("preceq", "≼"),
+    ("psi", "ψ"),
+    ("rangle", "⟩"),
+    ("rho", "ρ"),
+    ("rightarrow", "→"),
+    ("sigma", "σ"),
+    ("sqinter", "⊓"),
+    ("sqsubseteq", "⊑"),
+    ("squnion", "⊔"),
+    ("star", "⋆"),
+    ("subset", "⊂"),
+    ("subseteq", "⊆"),
+    ("supset", "⊃"),
+    ("supseteq", "⊇"),
+    ("tau", "τ"),
+    ("theta", "θ"),
+    ("times", "×"),
+    ("top", "⊤"),
+    ("turnstile", "⊢"),
+    ("union", "∪"),
+    ("upsilon", "υ"),
+    ("xi", "ξ"),
+    ("zeta", "ζ"),
+];
+
+// Characters that Isabelle typesets as \isacharNAME.
+pub const CHARS: &[(&str, &str)] = &[
+    ("ampersand", "&"),
+    ("asterisk", "*"),
+    ("at", "@"),
+    ("backquote", "`"),
+    ("backslash", "\\"),
+    ("bar", "|"),
+    ("braceleft", "{"),
+    ("braceright", "}"),
+    ("bracketleft", "["),
+    ("bracketright", "]"),
+    ("cartoucheclose", "›"),
+    ("cartoucheopen", "‹"),
+    ("circum", "^"),
+    ("colon", ":"),
+    ("comma", ","),
+    ("dollar", "$"),
+    ("dot", "."),
+    ("doublequote", "\""),
+    ("doublequoteclose", "\""),
+    ("doublequoteopen", "\""),
+    ("equal", "="),
+    ("exclam", "!"),
+    ("greater", ">"),
+    ("hash", "#"),
+    ("less", "<"),
+    ("minus", "-"),
+    ("parenleft", "("),
+    ("parenright", ")"),
+    ("percent", "%"),
+    ("plus", "+"),
+    ("prime", "'"),
+    ("query", "?"),
+    ("semicolon", ";"),
+    ("slash", "/"),
+    ("tilde", "~"),
+    ("underscore", "_"),
+];
+
+fn lookup(table: &[(&str, &'static str)], key: &str) -> Option<&'static str> {
+    table
+        .binary_search_by(|(k, _)| k.cmp(&key))
+        .map(|x| table[x].1)
+        .ok()
+}
+
+pub fn get_symbol(name: &str) -> Option<&'static str> {
+    lookup(SYMBOLS, name)
+}
+
+pub fn get_char(name: &str) -> Option<&'static str> {
+    lookup(CHARS, name)
+}
+
+// The reverse of get_symbol, for sources that use Unicode directly.
+pub fn get_name(unicode: char) -> Option<&'static str> {
+    let mut buf = [0; 4];
+    let unicode = &*unicode.encode_utf8(&mut buf);
+    SYMBOLS
+        .iter()
+        .find(|(_, u)| *u == unicode)
+        .map(|(name, _)| *name)
+}
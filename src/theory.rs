@@ -0,0 +1,1963 @@
+//! Splitting theories into snippets, without Isabelle.
+//!
+//! The tool annotates every theory with `text_raw` markers around the snippets, builds it
+//! with Isabelle and reads the snippets back from the generated LaTeX. The annotation is
+//! done here, as is reading the snippets back from the annotated source itself, which
+//! needs no build and is what the `-raw-source` option of the tool does.
+
+use crate::commands::{get_cmd_type, CmdType, COMMANDS};
+use crate::symbols::get_symbol;
+use crate::words::{make_words, make_words_with, CLOSE, OPEN};
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+
+/// Options for annotating theories.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Command classifications that take precedence over [`crate::commands::COMMANDS`].
+    pub commands: Vec<(String, CmdType)>,
+    /// Further characters that separate words, see [`make_words_with`].
+    pub breakers: Vec<char>,
+    /// If not empty, only these commands get snippets.
+    pub only_commands: Vec<String>,
+    /// These commands get no snippets.
+    pub skip_commands: Vec<String>,
+    /// Leave out `text` blocks entirely.
+    pub skip_text: bool,
+    /// Remove comments from the snippets.
+    pub strip_comments: bool,
+    /// Merge runs of adjacent snippets of a single line into one snippet.
+    pub merge_adjacent: bool,
+    /// Only merge runs of the same command, see [`Options::merge_adjacent`].
+    pub merge_same_command: bool,
+    /// Mark every step of the proof of a named theorem as its own snippet, see [`split_step_name`].
+    pub proof_steps: bool,
+    /// Mark every defining equation of a function as its own snippet, see [`split_equation_name`].
+    pub split_equations: bool,
+    /// Name `text` and `txt` blocks after a leading label like `[intro]`, see [`text_label`].
+    pub text_snippets: bool,
+    /// Mark everything between the theory header and its final `end` as one snippet.
+    pub whole_theory: bool,
+    /// Pass the header of the enclosing block, like `context Foo begin`, along with every snippet,
+    /// see [`Snippet::context`].
+    pub locale_headers: bool,
+}
+
+impl Options {
+    /// The classification of a command, from [`Options::commands`] or the built-in table.
+    pub fn cmd_type(&self, cmd: &str) -> Option<CmdType> {
+        match self.commands.iter().rev().find(|(c, _)| c == cmd) {
+            Some((_, typ)) => Some(*typ),
+            None => get_cmd_type(cmd),
+        }
+    }
+
+    /// Every known command with its classification, sorted by name.
+    /// Commands from [`Options::commands`] are added to the built-in table or replace their entry.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use isasnips::commands::CmdType;
+    /// use isasnips::theory::Options;
+    ///
+    /// let options = Options {
+    ///     commands: vec![
+    ///         ("my_lemma".to_string(), CmdType::OuterNamed),
+    ///         ("text".to_string(), CmdType::Inner),
+    ///     ],
+    ///     ..Default::default()
+    /// };
+    /// let table = options.command_table();
+    /// assert!(table.contains(&("lemma".to_string(), CmdType::OuterNamed)));
+    /// assert!(table.contains(&("section".to_string(), CmdType::Outer)));
+    /// assert!(table.contains(&("apply".to_string(), CmdType::Inner)));
+    /// assert!(table.contains(&("my_lemma".to_string(), CmdType::OuterNamed)));
+    /// assert!(table.contains(&("text".to_string(), CmdType::Inner)));
+    /// assert!(!table.contains(&("text".to_string(), CmdType::Outer)));
+    /// ```
+    pub fn command_table(&self) -> Vec<(String, CmdType)> {
+        let mut table: Vec<(String, CmdType)> = (COMMANDS.iter())
+            .map(|(cmd, _)| (cmd.to_string(), self.cmd_type(cmd).expect("Known command.")))
+            .collect();
+        for (cmd, typ) in &self.commands {
+            if !table.iter().any(|(c, _)| c == cmd) {
+                table.push((cmd.clone(), *typ));
+            }
+        }
+        table.sort();
+        table
+    }
+
+    /// Whether a command gets snippets under [`Options::only_commands`] and [`Options::skip_commands`].
+    pub fn selects_command(&self, cmd: &str) -> bool {
+        (self.only_commands.is_empty() || self.only_commands.iter().any(|c| c == cmd))
+            && !self.skip_commands.iter().any(|c| c == cmd)
+    }
+}
+
+/// A theory with markers around its snippets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AnnotatedTheory {
+    /// The theory source with a `text_raw` marker before and after every snippet.
+    pub text: String,
+    /// The line and command of every named command that could not be named, and got a hash instead.
+    pub unnamed: Vec<(usize, String)>,
+    /// The line, name and new name of every snippet whose name was already taken, like `f_x`
+    /// and `f-x` after replacing underscores. The later ones get a suffix like `-2`.
+    pub renamed: Vec<(usize, String, String)>,
+}
+
+/// The marker that starts a snippet, followed by its encoded name, command and source lines.
+pub const BEGIN: &str = "DefineSnippet";
+
+/// The marker that ends a snippet.
+pub const END: &str = "EndSnippet";
+
+/// Separates the name of a snippet from the number of a part, as in `lemma:foo/0`.
+///
+/// Snippet names never contain it, so a label can always be split again by [`split_part_label`].
+pub const PART_SEPARATOR: char = '/';
+
+/// The label of a part of a snippet.
+pub fn part_label(name: &str, part: usize) -> String {
+    format!("{}{}{}", name, PART_SEPARATOR, part)
+}
+
+/// Splits the label of a part into the name of the snippet and the number of the part.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::theory::{part_label, split_part_label};
+///
+/// let label = part_label("lemma:foo-bar-1", 0);
+/// assert_eq!(label, "lemma:foo-bar-1/0");
+/// assert_eq!(split_part_label(&label), Some(("lemma:foo-bar-1", 0)));
+/// assert_eq!(split_part_label("lemma:foo-bar-1"), None);
+/// ```
+pub fn split_part_label(label: &str) -> Option<(&str, usize)> {
+    let (name, part) = label.rsplit_once(PART_SEPARATOR)?;
+    Some((name, part.parse().ok()?))
+}
+
+fn step_name(name: &str, step: usize) -> String {
+    format!("{}:step-{}", name, step)
+}
+
+/// Splits the name of a proof step, like `lemma:foo:step-1`, into the name of
+/// the theorem and the number of the step.
+///
+/// With [`Options::proof_steps`], the statement of a named theorem is marked together with the first
+/// proof command, as step 0, and every following proof command on a line of its own is a further step.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::theory::{annotate_theory_with, split_step_name, Options};
+///
+/// let thy = "theory Foo imports Main begin\n\nlemma foo: \"True\"\n  apply (rule TrueI)\n  apply simp\n  done\n\nend";
+/// let options = Options {
+///     proof_steps: true,
+///     ..Default::default()
+/// };
+/// let annotated = annotate_theory_with(thy, &options);
+/// assert!(annotated.text.contains("DefineSnippet lemma:foo:step-0 lemma 3 4"));
+/// assert!(annotated.text.contains("DefineSnippet lemma:foo:step-1 lemma 5 5"));
+/// assert!(annotated.text.contains("DefineSnippet lemma:foo:step-2 lemma 6 6"));
+/// assert_eq!(split_step_name("lemma:foo:step-2"), Some(("lemma:foo", 2)));
+/// ```
+pub fn split_step_name(name: &str) -> Option<(&str, usize)> {
+    let (theorem, step) = name.rsplit_once(":step-")?;
+    Some((theorem, step.parse().ok()?))
+}
+
+fn equation_name(name: &str, equation: usize) -> String {
+    format!("{}:eq-{}", name, equation)
+}
+
+/// Splits the name of a defining equation, like `fun:f:eq-1`, into the name of
+/// the function and the number of the equation.
+///
+/// With [`Options::split_equations`], the equations of a `fun`, `function`, `primrec` or
+/// `primcorec` are marked one by one, separated by a `|` at the start or end of a line.
+/// The header of the function goes with equation 0, and a proof of termination with the last one.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::theory::{annotate_theory_with, split_equation_name, Options};
+///
+/// let thy = "theory Foo imports Main begin\n\nfun f :: \"nat \\<Rightarrow> nat\" where\n  \"f 0 = 0\"\n| \"f (Suc n) = (case n of 0 \\<Rightarrow> 1\n    | _ \\<Rightarrow> f n)\"\n\nend";
+/// let options = Options {
+///     split_equations: true,
+///     ..Default::default()
+/// };
+/// let annotated = annotate_theory_with(thy, &options);
+/// assert!(annotated.text.contains("DefineSnippet fun:f:eq-0 fun 3 4"));
+/// // A bar inside of the string is part of the equation.
+/// assert!(annotated.text.contains("DefineSnippet fun:f:eq-1 fun 5 6"));
+/// assert!(!annotated.text.contains("fun:f:eq-2"));
+/// assert_eq!(split_equation_name("fun:f:eq-1"), Some(("fun:f", 1)));
+/// ```
+pub fn split_equation_name(name: &str) -> Option<(&str, usize)> {
+    let (function, equation) = name.rsplit_once(":eq-")?;
+    Some((function, equation.parse().ok()?))
+}
+
+/// Underscores are replaced by hyphens in snippet names, since LaTeX treats them specially.
+pub fn escape_underscores(s: &str) -> String {
+    s.replace("_", "-")
+}
+
+fn strip_superscripts(s: &str) -> String {
+    s.replace("^", "")
+}
+
+// The part separator is replaced too, so labels stay unambiguous.
+fn snippet_name(key: &str, name: &str) -> String {
+    let clean = |s: &str| strip_superscripts(&escape_underscores(s)).replace(PART_SEPARATOR, "-");
+    format!("{}:{}", clean(key), clean(name))
+}
+
+/// The label at the start of a `text` or `txt` block, and the lines without it.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::theory::text_label;
+///
+/// let lines = vec!["text \\<open>[intro] Groups are".to_string(), "  everywhere.\\<close>".to_string()];
+/// let (label, lines) = text_label(&lines).unwrap();
+/// assert_eq!(label, "intro");
+/// assert_eq!(lines[0], "text \\<open>Groups are");
+///
+/// assert!(text_label(&["text \\<open>No label here.\\<close>".to_string()]).is_none());
+/// ```
+pub fn text_label(lines: &[String]) -> Option<(String, Vec<String>)> {
+    let (i, line) = lines
+        .iter()
+        .enumerate()
+        .find(|(_, l)| !l.trim().is_empty())?;
+    let open = [OPEN, "‹"]
+        .iter()
+        .filter_map(|o| line.find(o).map(|j| j + o.len()))
+        .min()?;
+
+    let rest = line[open..].trim_start();
+    let label_end = rest.strip_prefix('[')?.find(']')? + 1;
+    let label = &rest[1..label_end];
+    let is_label_char = |c: char| c.is_alphanumeric() || "_-:.".contains(c);
+    if label.is_empty() || !label.chars().all(is_label_char) {
+        return None;
+    }
+
+    let mut lines = lines.to_vec();
+    lines[i] = [&line[..open], rest[label_end + 1..].trim_start()].concat();
+    Some((label.to_string(), lines))
+}
+
+fn text_raw(s: &str) -> String {
+    ["text_raw", " ", OPEN, s, CLOSE].join("")
+}
+
+// Marker arguments pass through both Isabelle and LaTeX when the document is built,
+// so anything but a few plain characters is hex encoded as +XX per byte.
+fn encode_marker_arg(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b":.-'".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("+{:02X}", b));
+        }
+    }
+    out
+}
+
+fn decode_marker_arg(s: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail.get(..2).and_then(|h| std::str::from_utf8(h).ok());
+        match hex.map(|h| u8::from_str_radix(h, 16)) {
+            Some(Ok(decoded)) if b == b'+' => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// The source lines of the snippet are passed along for the manifest,
+// and the header of the enclosing block for -with-locale-header.
+fn begin_marker(
+    name: &str,
+    cmd: &str,
+    (start, end): (usize, usize),
+    context: Option<&str>,
+) -> String {
+    let mut args = vec![
+        BEGIN.to_string(),
+        encode_marker_arg(name),
+        encode_marker_arg(&escape_underscores(cmd)),
+        start.to_string(),
+        end.to_string(),
+    ];
+    args.extend(context.map(encode_marker_arg));
+    text_raw(&args.join(" "))
+}
+
+fn end_marker() -> String {
+    text_raw(END)
+}
+
+struct Chunk {
+    cmd: String,
+    typ: CmdType,
+    // Source line (counting from 1) of the command keyword.
+    line: usize,
+    // Source line (counting from 1) of the first element of lines.
+    start: usize,
+    // An end that closes a block like context ... begin, not the theory.
+    block_end: bool,
+    // The index of the chunk that opened the innermost enclosing block, if any.
+    block: Option<usize>,
+    lines: Vec<String>,
+}
+
+// Strings, cartouches and comments that continue from one line to the next.
+// The lines inside them are text, and never start a command.
+#[derive(Default)]
+struct Nesting {
+    string: bool,
+    cartouches: usize,
+    comments: usize,
+}
+
+impl Nesting {
+    fn is_open(&self) -> bool {
+        self.string || self.cartouches > 0 || self.comments > 0
+    }
+
+    // Reads a line, where inside a string, cartouches and comments are text, and so on.
+    // Every character outside of everything, except the start of a comment, is passed to visit.
+    fn scan(&mut self, line: &str, mut visit: impl FnMut(usize, char)) {
+        let mut i = 0;
+
+        while i < line.len() {
+            let rest = &line[i..];
+            if !self.is_open() && !rest.starts_with("(*") {
+                if let Some(c) = rest.chars().next() {
+                    visit(i, c);
+                }
+            }
+            let delimiter = |s: &str| rest.starts_with(s).then_some(s.len());
+            let open = delimiter("\\<open>").or_else(|| delimiter("‹"));
+            let close = delimiter("\\<close>").or_else(|| delimiter("›"));
+
+            let step = if self.string {
+                if let Some(n) = delimiter("\\\"") {
+                    n
+                } else if let Some(n) = delimiter("\"") {
+                    self.string = false;
+                    n
+                } else {
+                    0
+                }
+            } else if self.cartouches > 0 {
+                if let Some(n) = open {
+                    self.cartouches += 1;
+                    n
+                } else if let Some(n) = close {
+                    self.cartouches -= 1;
+                    n
+                } else {
+                    0
+                }
+            } else if self.comments > 0 {
+                if let Some(n) = delimiter("(*") {
+                    self.comments += 1;
+                    n
+                } else if let Some(n) = delimiter("*)") {
+                    self.comments -= 1;
+                    n
+                } else {
+                    0
+                }
+            } else if let Some(n) = delimiter("\"") {
+                self.string = true;
+                n
+            } else if let Some(n) = delimiter("(*") {
+                self.comments += 1;
+                n
+            } else if let Some(n) = open {
+                self.cartouches += 1;
+                n
+            } else {
+                0
+            };
+
+            i += if step > 0 {
+                step
+            } else {
+                rest.chars().next().map_or(1, char::len_utf8)
+            };
+        }
+    }
+}
+
+// Editors on Windows may save theories with a byte order mark, which would be part of the first word.
+fn strip_bom(thy: &str) -> &str {
+    thy.strip_prefix('\u{feff}').unwrap_or(thy)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '\'' || c == '.'
+}
+
+// The outer commands on a line, outside of strings, cartouches, comments and parentheses,
+// and the positions of the begin keywords outside of strings, cartouches and comments.
+// A command that only follows whitespace and comments starts the whole line, at position 0.
+// Other commands split the line, which is rare, as in 'definition a where "a = 1" lemma ...'.
+// They only do so after the head of the command before them on the line, up to a : or =,
+// a begin or a string or cartouche, since a name like in 'lemma term: ...' may be a command.
+fn command_starts(
+    line: &str,
+    nesting: &mut Nesting,
+    options: &Options,
+) -> (Vec<(usize, String)>, Vec<usize>) {
+    let mut starts = vec![];
+    let mut begins = vec![];
+    // Whether anything but whitespace and comments came before on the line.
+    let mut content = nesting.is_open();
+    let mut parens = 0;
+    // The end of the previous character passed to the visitor, and whether it was whitespace.
+    let mut last: Option<(usize, bool)> = None;
+    // Whether the head of the last command is behind, which it is for one from an earlier line.
+    let mut head_done = true;
+
+    nesting.scan(line, |i, c| {
+        match c {
+            '(' | '[' => parens += 1,
+            ')' | ']' => parens -= 1,
+            ':' | '=' if parens == 0 => head_done = true,
+            _ => {}
+        }
+
+        // Something was skipped before this character, a string or cartouche ends the head.
+        let skipped = last.is_some_and(|(end, _)| end < i);
+        let closed = ["\"", "\\<close>", "›"]
+            .iter()
+            .any(|s| line[..i].ends_with(s));
+        if skipped && closed {
+            head_done = true;
+        }
+
+        // Words also start right after a comment, string or cartouche.
+        let word_start = is_word_char(c) && last.is_none_or(|(end, space)| space || end < i);
+        let word: String = if word_start {
+            line[i..].chars().take_while(|c| is_word_char(*c)).collect()
+        } else {
+            String::new()
+        };
+        if word == "begin" {
+            begins.push(i);
+        }
+        if word_start && parens == 0 {
+            let command = matches!(
+                options.cmd_type(&word),
+                Some(CmdType::Outer) | Some(CmdType::OuterNamed)
+            );
+            if command && (!content || head_done) {
+                starts.push((if content { i } else { 0 }, word));
+                head_done = false;
+            } else if word == "begin" {
+                head_done = true;
+            }
+        }
+
+        if !c.is_whitespace() {
+            content = true;
+        }
+        last = Some((i + c.len_utf8(), c.is_whitespace()));
+    });
+
+    (starts, begins)
+}
+
+fn chunk_theory(thy: &str, options: &Options) -> Vec<Chunk> {
+    let mut chunks = vec![];
+
+    let mut current_cmd: Option<(String, CmdType, usize)> = None;
+    let mut current_chunk: Vec<String> = vec![];
+    // Source line of the first element of current_chunk.
+    let mut chunk_start = 1;
+
+    // Blocks opened by begin, including the theory itself.
+    let mut depth: usize = 0;
+    let mut block_end = false;
+    // The blocks other than the theory, by their depth inside and the chunk that opened them.
+    let mut blocks: Vec<(usize, usize)> = vec![];
+    let mut block = None;
+    let mut chunk_depth = 0;
+
+    let mut nesting = Nesting::default();
+
+    for (lineno, line) in thy.lines().enumerate() {
+        let (starts, begins) = command_starts(line, &mut nesting, options);
+
+        // The line is split before every command that does not start it.
+        let mut segments: Vec<(usize, Option<String>)> = vec![(0, None)];
+        for (i, cmd) in starts {
+            if i == 0 {
+                segments[0].1 = Some(cmd);
+            } else {
+                segments.push((i, Some(cmd)));
+            }
+        }
+
+        for (k, (from, first)) in segments.iter().enumerate() {
+            let to = segments.get(k + 1).map_or(line.len(), |(i, _)| *i);
+            let segment = &line[*from..to];
+            let segment = if to < line.len() {
+                segment.trim_end()
+            } else {
+                segment
+            };
+
+            let cmd_type = first.as_ref().and_then(|f| options.cmd_type(f));
+
+            if cmd_type.is_some() {
+                if let Some((ref cmd, typ, line)) = current_cmd {
+                    if depth > chunk_depth && cmd != "theory" {
+                        blocks.push((depth, chunks.len()));
+                    }
+                    chunks.push(Chunk {
+                        cmd: cmd.to_owned(),
+                        typ,
+                        line,
+                        start: chunk_start,
+                        block_end,
+                        block,
+                        lines: current_chunk.clone(),
+                    });
+                    current_chunk.clear();
+                }
+
+                block_end = false;
+                if first.as_deref() == Some("end") {
+                    block_end = depth > 1;
+                    depth = depth.saturating_sub(1);
+                    blocks.retain(|(d, _)| *d <= depth);
+                }
+                block = blocks.last().map(|(_, i)| *i);
+                chunk_depth = depth;
+                current_cmd = first.clone().map(|s| (s, cmd_type.unwrap(), lineno + 1));
+            }
+
+            depth += begins.iter().filter(|i| (*from..to).contains(i)).count();
+
+            if current_chunk.is_empty() {
+                chunk_start = lineno + 1;
+            }
+            current_chunk.push(segment.to_owned());
+        }
+    }
+
+    // The last chunk keeps everything after its command. Lines before any command, in a
+    // theory without commands, get a chunk without command, which is copied as is.
+    if !current_chunk.is_empty() {
+        let (cmd, typ, line) = current_cmd.unwrap_or((String::new(), CmdType::Inner, chunk_start));
+        chunks.push(Chunk {
+            cmd,
+            typ,
+            line,
+            start: chunk_start,
+            block_end,
+            block,
+            lines: current_chunk,
+        });
+    }
+
+    chunks
+}
+
+// Symbols like \<oplus> are read as their names, but only letters like \<pi> name constants.
+fn is_operator(word: &str) -> bool {
+    match word.chars().next() {
+        // Control symbols like ^sub continue the word before them.
+        Some('^') => false,
+        Some(c) if c.is_alphanumeric() || c == '_' || c == '\'' => {
+            get_symbol(word).is_some_and(|u| !u.starts_with(char::is_alphabetic))
+        }
+        _ => true,
+    }
+}
+
+const THEOREMS: [&str; 4] = ["lemma", "theorem", "corollary", "proposition"];
+
+// Commands that define a function by equations separated by |.
+const FUNCTIONS: [&str; 4] = ["fun", "function", "primrec", "primcorec"];
+
+/// The commands that become prose snippets with [`Options::text_snippets`].
+pub const TEXTS: [&str; 2] = ["text", "txt"];
+
+fn chunk_name(
+    cmd: &str,
+    words: &[String],
+    last_fun: &Option<String>,
+    last_instantiation: &Option<String>,
+) -> Option<String> {
+    let mut inside_parens = 0;
+    let mut inside_open = 0;
+
+    let mut name_parts = vec![];
+    let mut content = vec![];
+
+    // Names occur before these.
+    let markers = vec![
+        "[", ":", "=", "where", "and", "by", "imports", "begin", "fixes", "assumes", "shows",
+    ];
+
+    // Before the statement of a theorem, 'and' separates several names, as in 'lemma a and b:'.
+    // Inside the statement, e.g. in 'fixes x and y', it still ends the name.
+    let theorem_like = THEOREMS.contains(&cmd);
+
+    // The last word is considered too, so 'locale Foo' on its own is named.
+    for i in 0..words.len() {
+        let next = words.get(i + 1).map_or("", |w| w.as_str());
+        let separates_names = theorem_like
+            && inside_parens == 0
+            && inside_open == 0
+            && i > 0
+            && !markers.contains(&words[i].as_str())
+            && next == "and"
+            && words
+                .get(i + 2)
+                .is_some_and(|w| !markers.contains(&w.as_str()));
+        if separates_names {
+            name_parts.push(words[i].clone());
+            name_parts.push("-and-".to_string());
+            continue;
+        }
+        if theorem_like && words[i] == "and" && name_parts.last().is_some_and(|p| p == "-and-") {
+            continue;
+        }
+
+        // Parentheses are kept in the content, to tell the arguments of a definition apart.
+        if inside_open > 0 && (words[i] == "(" || words[i] == ")") {
+            content.push(words[i].clone());
+        }
+
+        if words[i] == "(" {
+            inside_parens += 1;
+        } else if words[i] == ")" {
+            inside_parens -= 1;
+        } else if inside_parens == 0 && words[i] == OPEN {
+            inside_open += 1;
+        } else if inside_parens == 0 && words[i] == CLOSE {
+            if inside_open == 0 {
+                // A cartouche closed before it was opened, so the words tell nothing reliable.
+                return None;
+            }
+            inside_open -= 1;
+        } else if inside_open > 0 {
+            content.push(words[i].clone());
+        }
+
+        // Only the first cartouche counts. A type cartouche, as in 'foo :: \<open>t\<close>',
+        // comes after the name, so the scan has already stopped at the ':' before it.
+        if words[i] == CLOSE && inside_open == 0 {
+            break;
+        }
+
+        if inside_parens == 0
+            && inside_open == 0
+            && words[i] != ")"
+            && !words[i].starts_with("'")
+            && words[i] != CLOSE
+        {
+            name_parts.push(words[i].clone());
+        }
+
+        if inside_parens == 0 && inside_open == 0 && markers.contains(&next) {
+            break;
+        }
+    }
+
+    // A cartouche that is never closed, as in a theory in the middle of an edit,
+    // swallows everything after it. The snippet is better named by a hash then.
+    if inside_open > 0 {
+        return None;
+    }
+
+    let mut name = None;
+
+    if name_parts.len() > 1 {
+        name = Some(
+            name_parts[1..]
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<String>(),
+        );
+    }
+
+    if name.is_none() && (cmd == "definition" || cmd == "abbreviation") {
+        // The constant is the head of the left-hand side, unless it is written with
+        // operators as in 'x \<oplus> y \<equiv> ...', which is better left unnamed.
+        let lhs: Vec<&String> = content
+            .iter()
+            .take_while(|w| !["=", "equiv", "longleftrightarrow"].contains(&w.as_str()))
+            .collect();
+        let mut depth = 0;
+        let named = lhs
+            .first()
+            .is_some_and(|w| w.starts_with(char::is_alphabetic))
+            && lhs.iter().all(|w| match w.as_str() {
+                "(" => {
+                    depth += 1;
+                    true
+                }
+                ")" => {
+                    depth -= 1;
+                    true
+                }
+                _ => depth > 0 || !is_operator(w),
+            });
+        if named {
+            // Make sure to include subscripts. A bit of a hack.
+            let subscripts = lhs[1..].iter().take_while(|w| w.contains('^'));
+            name = Some(
+                std::iter::once(lhs[0])
+                    .chain(subscripts.copied())
+                    .map(|s| s.as_str())
+                    .collect(),
+            );
+        }
+    }
+
+    if cmd == "termination" && name_parts.len() > 1 && name_parts[1] == "proof" {
+        name = None;
+    }
+
+    if name.is_none() && last_fun.is_some() && (cmd == "termination") {
+        name = last_fun.clone();
+    }
+
+    if name.is_none() && last_instantiation.is_some() && (cmd == "instance") {
+        name = last_instantiation.clone();
+    }
+
+    name.map(|n| snippet_name(cmd, &n))
+}
+
+// Blank lines inside a chunk are kept, but leading and trailing ones are dropped,
+// since the markers already separate the chunks.
+fn trim_blank_lines(lines: &[String]) -> Range<usize> {
+    let is_blank = |l: &String| l.trim().is_empty();
+    let start = lines
+        .iter()
+        .position(|l| !is_blank(l))
+        .unwrap_or(lines.len());
+    let end = lines
+        .iter()
+        .rposition(|l| !is_blank(l))
+        .map_or(start, |i| i + 1);
+    start..end
+}
+
+fn starts_with_any(s: &str, patterns: &[&str]) -> Option<usize> {
+    patterns.iter().find(|p| s.starts_with(*p)).map(|p| p.len())
+}
+
+// Removes (* *) comments, which may nest, and \<comment> or -- together with the cartouche
+// or string after it. Strings and cartouches are skipped, so a (* inside them is kept.
+// Lines that only held a comment are dropped.
+fn strip_comments(lines: &[String]) -> Vec<String> {
+    let text = lines.join("\n");
+    let mut out = String::new();
+
+    let mut comment = 0;
+    let mut cartouche = 0;
+    let mut quote = None;
+    // After \<comment> or --, until its argument starts.
+    let mut argument = false;
+    // Inside the argument of \<comment> or --.
+    let mut dropping = false;
+
+    let mut i = 0;
+    while let Some(c) = text[i..].chars().next() {
+        let rest = &text[i..];
+        let mut len = c.len_utf8();
+        let mut keep = !dropping;
+
+        if comment > 0 {
+            if rest.starts_with("(*") {
+                comment += 1;
+                len = 2;
+            } else if rest.starts_with("*)") {
+                comment -= 1;
+                len = 2;
+            }
+            keep = false;
+        } else if let Some(q) = quote {
+            if c == '\\' {
+                len += rest[1..].chars().next().map_or(0, char::len_utf8);
+            } else if c == q {
+                quote = None;
+                dropping = false;
+            }
+        } else if cartouche > 0 {
+            if let Some(n) = starts_with_any(rest, &[OPEN, "\u{2039}"]) {
+                cartouche += 1;
+                len = n;
+            } else if let Some(n) = starts_with_any(rest, &[CLOSE, "\u{203a}"]) {
+                cartouche -= 1;
+                len = n;
+                dropping = dropping && cartouche > 0;
+            }
+        } else if argument {
+            if c.is_whitespace() {
+                keep = false;
+            } else {
+                argument = false;
+                if let Some(n) = starts_with_any(rest, &[OPEN, "\u{2039}"]) {
+                    cartouche = 1;
+                    len = n;
+                    dropping = true;
+                    keep = false;
+                } else if c == '"' {
+                    quote = Some(c);
+                    dropping = true;
+                    keep = false;
+                } else {
+                    // A comment marker without an argument, look at this again as usual.
+                    continue;
+                }
+            }
+        } else if rest.starts_with("(*") {
+            comment = 1;
+            len = 2;
+            keep = false;
+        } else if let Some(n) = starts_with_any(rest, &["\\<comment>", "\u{2014}", "-- "]) {
+            argument = true;
+            len = n;
+            keep = false;
+        } else if let Some(n) = starts_with_any(rest, &[OPEN, "\u{2039}"]) {
+            cartouche = 1;
+            len = n;
+        } else if c == '"' || c == '`' {
+            quote = Some(c);
+        }
+
+        // Newlines are always kept, so the lines still line up with the original ones.
+        if c == '\n' {
+            out.push(c);
+        } else if keep {
+            out.push_str(&rest[..len]);
+        }
+        i += len;
+    }
+
+    out.split('\n')
+        .zip(lines)
+        .filter_map(|(new, old)| {
+            if new == old {
+                Some(old.clone())
+            } else if new.trim().is_empty() {
+                None
+            } else {
+                Some(new.trim_end().to_string())
+            }
+        })
+        .collect()
+}
+
+/// A snippet extracted from a theory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snippet {
+    /// The name of the theory it comes from.
+    pub theory: String,
+    /// Set when the snippets of several theories are combined.
+    pub prefix: Option<String>,
+    /// The name, like `lemma:foo`, without prefix or part number.
+    pub name: String,
+    /// The command that starts the snippet.
+    pub cmd: String,
+    /// The first and last line of the snippet in the theory source, counting from 1.
+    pub source_lines: Option<(usize, usize)>,
+    /// The lines of the snippet, separated by `\isanewline` in the generated LaTeX.
+    pub parts: Vec<Vec<String>>,
+    /// The name with prefix that was replaced by an opaque one, see [`anonymize`].
+    pub original_name: Option<String>,
+    /// The source of the header of the enclosing block, see [`Options::locale_headers`].
+    pub context: Option<String>,
+}
+
+impl Snippet {
+    /// An empty snippet for the given begin marker.
+    pub fn from_marker(line: &str, theory: &str, prefix: Option<String>) -> Snippet {
+        let args = marker_args(line);
+        let arg = |i: usize| args.get(i).cloned().unwrap_or_default();
+        Snippet {
+            theory: theory.to_string(),
+            prefix,
+            name: arg(0),
+            cmd: arg(1),
+            source_lines: arg(2).parse().ok().zip(arg(3).parse().ok()),
+            parts: vec![],
+            original_name: None,
+            context: args.get(4).cloned(),
+        }
+    }
+
+    /// Keeps only the content of a `text` or `txt` block, without the command and cartouche.
+    ///
+    /// With raw set the parts are source lines, otherwise Isabelle's LaTeX,
+    /// where the content is wrapped in an `isamarkuptext` environment.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use isasnips::theory::{extract_from_source, Options};
+    ///
+    /// let thy = "theory Foo imports Main begin\n\ntext \\<open>[intro] Every @{term x}\nis equal to itself.\\<close>\n\nend";
+    /// let options = Options {
+    ///     text_snippets: true,
+    ///     ..Default::default()
+    /// };
+    /// let mut text = extract_from_source(thy, &options).remove(1);
+    /// assert_eq!(text.name, "text:intro");
+    ///
+    /// text.unwrap_text(true);
+    /// assert_eq!(text.parts, [["Every @{term x}"], ["is equal to itself."]]);
+    /// ```
+    pub fn unwrap_text(&mut self, raw: bool) {
+        if raw {
+            if let Some(first) = self.parts.first_mut().and_then(|p| p.first_mut()) {
+                let open = [OPEN, "‹"]
+                    .iter()
+                    .filter_map(|o| first.find(o).map(|j| j + o.len()))
+                    .min();
+                if let Some(open) = open {
+                    first.replace_range(..open, "");
+                }
+            }
+            if let Some(last) = self.parts.last_mut().and_then(|p| p.last_mut()) {
+                let close = [CLOSE, "›"].iter().filter_map(|c| last.rfind(c)).max();
+                if let Some(close) = close {
+                    last.truncate(close);
+                }
+            }
+        } else {
+            for part in &mut self.parts {
+                part.retain(|line| {
+                    let line = line.trim_start();
+                    !line.starts_with("\\begin{isamarkup") && !line.starts_with("\\end{isamarkup")
+                });
+            }
+        }
+
+        while self.parts.last().is_some_and(|p| is_empty_part(p)) {
+            self.parts.pop();
+        }
+        let leading = self.parts.iter().take_while(|p| is_empty_part(p)).count();
+        self.parts.drain(..leading);
+    }
+
+    /// Gives the snippet a part for every line of its source, so part `k` is line
+    /// `start + k` of [`Snippet::source_lines`].
+    ///
+    /// Isabelle ends a part at every line break of the source, except e.g. in text,
+    /// where the parts it joined are left empty. Any parts beyond are joined into the last.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use isasnips::theory::Snippet;
+    ///
+    /// let marker = "\\DefineSnippet lemma:foo lemma 3 5%";
+    /// let mut snippet = Snippet::from_marker(marker, "Foo", None);
+    /// let line = |s: &str| vec![s.to_string()];
+    ///
+    /// snippet.parts = vec![line("a"), line("b"), line("c"), line("d")];
+    /// snippet.split_source_lines();
+    /// assert_eq!(snippet.parts, [vec!["a"], vec!["b"], vec!["c", "d"]]);
+    ///
+    /// snippet.parts = vec![line("text")];
+    /// snippet.split_source_lines();
+    /// assert_eq!(snippet.parts, [vec!["text"], vec![], vec![]]);
+    /// ```
+    pub fn split_source_lines(&mut self) {
+        let lines = match self.source_lines {
+            Some((start, end)) if end >= start => end - start + 1,
+            _ => return,
+        };
+        if self.parts.len() > lines {
+            let rest = self.parts.split_off(lines - 1).concat();
+            self.parts.push(rest);
+        }
+        self.parts.resize(lines, vec![]);
+    }
+}
+
+/// The arguments of a begin marker, in the LaTeX or in the annotated theory.
+///
+/// Every argument is a single token, since whitespace in names is encoded,
+/// so names with spaces survive. Trailing LaTeX comments are ignored.
+pub fn marker_args(line: &str) -> Vec<String> {
+    let start = line.find(BEGIN).unwrap_or(0) + BEGIN.len();
+    let args: Vec<_> = line[start..]
+        .trim_end()
+        .trim_end_matches(CLOSE)
+        .trim_end_matches('%')
+        .split_whitespace()
+        .collect();
+    args.iter().map(|arg| decode_marker_arg(arg)).collect()
+}
+
+/// Whether a line of an annotated theory is the given marker.
+pub fn is_raw_marker(line: &str, marker: &str) -> bool {
+    line.starts_with("text_raw") && line.contains(marker)
+}
+
+/// Marks every snippet of a theory, with the default options.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::theory::annotate_theory;
+///
+/// let annotated = annotate_theory("theory Foo imports Main begin\n\nlemma foo: \"True\"\n  by simp\n\nend");
+/// assert!(annotated.text.contains("text_raw \\<open>DefineSnippet lemma:foo lemma 3 4\\<close>"));
+/// assert!(annotated.unnamed.is_empty());
+/// ```
+pub fn annotate_theory(thy: &str) -> AnnotatedTheory {
+    annotate_theory_with(thy, &Options::default())
+}
+
+/// Like [`annotate_theory`], but with the given options.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::theory::{annotate_theory_with, Options};
+///
+/// let thy = "theory Foo imports Main begin\n\ndefinition \"a = 1\"\ndefinition \"b = 2\"\ndefinition \"c = 3\"\n\nend";
+/// let options = Options {
+///     merge_adjacent: true,
+///     ..Default::default()
+/// };
+/// let annotated = annotate_theory_with(thy, &options);
+/// assert!(annotated.text.contains("DefineSnippet definition:a-merged-3 definition 3 5"));
+/// assert!(!annotated.text.contains("definition:b"));
+///
+/// // With whole_theory, all commands between the header and the end are one snippet.
+/// let options = Options {
+///     whole_theory: true,
+///     ..Default::default()
+/// };
+/// let annotated = annotate_theory_with(thy, &options);
+/// let text: Vec<&str> = annotated.text.lines().collect();
+/// assert_eq!(
+///     text[2..7],
+///     [
+///         "text_raw \\<open>DefineSnippet theory:Foo theory 3 5\\<close>",
+///         "definition \"a = 1\"",
+///         "definition \"b = 2\"",
+///         "definition \"c = 3\"",
+///         "text_raw \\<open>EndSnippet\\<close>",
+///     ]
+/// );
+/// assert_eq!(annotated.text.matches("DefineSnippet").count(), 1);
+///
+/// // A byte order mark is dropped, so it does not hide the theory header.
+/// let plain = "theory Foo imports Main begin\n\nlemma foo: \"True\" by simp\n\nend";
+/// let with_bom = ["\u{feff}", plain].concat();
+/// assert_eq!(
+///     annotate_theory_with(&with_bom, &Options::default()),
+///     annotate_theory_with(plain, &Options::default())
+/// );
+/// ```
+pub fn annotate_theory_with(thy: &str, options: &Options) -> AnnotatedTheory {
+    let thy = strip_bom(thy);
+    let chunks = chunk_theory(thy, options);
+    if options.whole_theory {
+        return AnnotatedTheory {
+            text: mark_entries(whole_theory(&chunks, options), options),
+            unnamed: vec![],
+            renamed: vec![],
+        };
+    }
+
+    let mut entries = vec![];
+    let mut last_fun = None;
+    let mut last_instantiation = None;
+    // Chunks with the same words get the same hash, and are numbered in source order,
+    // so the suffixes only depend on the theory. The map is never iterated.
+    let mut hashes = BTreeMap::new();
+    let mut unnamed = vec![];
+    let mut names = BTreeSet::new();
+    let mut renamed = vec![];
+
+    for chunk in &chunks {
+        let cmd = &chunk.cmd;
+
+        // Dropped chunks are kept as empty entries, so they end a run of merged snippets.
+        if options.skip_text && cmd == "text" {
+            entries.push(Entry::Copied(vec![]));
+            continue;
+        }
+
+        // Unselected commands, the ends of blocks and lines without a command are copied
+        // through without markers.
+        if !options.selects_command(cmd) || chunk.block_end || cmd.is_empty() {
+            entries.push(Entry::Copied(chunk.lines.clone()));
+            continue;
+        }
+
+        let cont = chunk.lines.join("\n");
+        let words = make_words_with(&cont, &options.breakers);
+
+        if words.is_empty() {
+            entries.push(Entry::Copied(vec![]));
+            continue;
+        }
+
+        let mut outer_name = None;
+        let mut lines = chunk.lines.clone();
+        if options.text_snippets && TEXTS.contains(&cmd.as_str()) {
+            if let Some((label, unlabeled)) = text_label(&chunk.lines) {
+                outer_name = Some(snippet_name(cmd, &label));
+                lines = unlabeled;
+            }
+        }
+        if chunk.typ == CmdType::OuterNamed {
+            outer_name = chunk_name(cmd, &words, &last_fun, &last_instantiation);
+            // An unnamed context, as in 'context begin', is not missing a name.
+            if outer_name.is_none() && cmd != "context" {
+                unnamed.push((chunk.line, cmd.clone()));
+            }
+        }
+
+        let named = outer_name.is_some();
+        let name = match outer_name {
+            Some(n) => n,
+            None => {
+                let mut hasher = DefaultHasher::new();
+                words.hash(&mut hasher);
+                let hash = hasher.finish();
+                let suffix = hashes.entry(hash).or_insert(0);
+                let name = if *suffix > 0 {
+                    snippet_name(cmd, &format!("{:x}-{}", hash, suffix))
+                } else {
+                    snippet_name(cmd, &format!("{:x}", hash))
+                };
+                *suffix += 1;
+                name
+            }
+        };
+
+        if cmd == "function" {
+            let colon = name.find(':').unwrap_or(0);
+            let last_name = name[colon + 1..].to_string();
+            last_fun = Some(last_name);
+        }
+
+        if cmd == "instantiation" {
+            let colon = name.find(':').unwrap_or(0);
+            let last_name = name[colon + 1..].to_string();
+            last_instantiation = Some(last_name);
+        }
+
+        // Different names in the source may be the same after sanitizing.
+        let name = if names.contains(&name) {
+            let new = (2..)
+                .map(|n| format!("{}-{}", name, n))
+                .find(|new| !names.contains(new))
+                .expect("Some suffix is free.");
+            renamed.push((chunk.line, name, new.clone()));
+            new
+        } else {
+            name
+        };
+        names.insert(name.clone());
+
+        let region = Region {
+            name,
+            cmd: cmd.clone(),
+            start: chunk.start,
+            lines,
+            mergeable: chunk.typ == CmdType::OuterNamed && cmd != "theory",
+            context: chunk
+                .block
+                .filter(|_| options.locale_headers)
+                .map(|b| block_header(&chunks[b])),
+        };
+        if options.proof_steps && named && THEOREMS.contains(&cmd.as_str()) {
+            entries.extend(proof_steps(region, options).into_iter().map(Entry::Marked));
+        } else if options.split_equations && named && FUNCTIONS.contains(&cmd.as_str()) {
+            entries.extend(equations(region).into_iter().map(Entry::Marked));
+        } else {
+            entries.push(Entry::Marked(region));
+        }
+    }
+
+    if options.merge_adjacent {
+        entries = merge_adjacent(entries, options.merge_same_command);
+    }
+
+    AnnotatedTheory {
+        text: mark_entries(entries, options),
+        unnamed,
+        renamed,
+    }
+}
+
+// The text of the entries, with markers around the marked ones.
+fn mark_entries(entries: Vec<Entry>, options: &Options) -> String {
+    let mut annotated: Vec<String> = vec![];
+    for entry in entries {
+        let region = match entry {
+            Entry::Copied(lines) => {
+                annotated.extend(lines);
+                continue;
+            }
+            Entry::Marked(region) => region,
+        };
+
+        let body = region.body();
+        let source_lines = (region.start + body.start, region.start + body.end - 1);
+        annotated.push(begin_marker(
+            &region.name,
+            &region.cmd,
+            source_lines,
+            region.context.as_deref(),
+        ));
+        if options.strip_comments {
+            annotated.extend(strip_comments(&region.lines[body]));
+        } else {
+            annotated.extend(region.lines[body].iter().cloned());
+        }
+        annotated.push(end_marker());
+        annotated.push(String::new());
+    }
+
+    annotated.join("\n")
+}
+
+// With -whole-theory, everything between the header and the final end is one snippet,
+// named after the theory.
+fn whole_theory(chunks: &[Chunk], options: &Options) -> Vec<Entry> {
+    let header = chunks.iter().position(|c| c.cmd == "theory");
+    let end = chunks.iter().rposition(|c| c.cmd == "end" && !c.block_end);
+    let body_start = header.map_or(0, |h| h + 1);
+    let body_end = end.filter(|&e| e >= body_start).unwrap_or(chunks.len());
+
+    let copied =
+        |chunks: &[Chunk]| Entry::Copied(chunks.iter().flat_map(|c| c.lines.clone()).collect());
+    let body = &chunks[body_start..body_end];
+    if body
+        .iter()
+        .all(|c| c.lines.iter().all(|l| l.trim().is_empty()))
+    {
+        return vec![copied(chunks)];
+    }
+
+    let theory = header
+        .map(|h| make_words_with(&chunks[h].lines.join("\n"), &options.breakers))
+        .and_then(|words| words.get(1).cloned())
+        .unwrap_or_default();
+
+    vec![
+        copied(&chunks[..body_start]),
+        Entry::Marked(Region {
+            name: snippet_name("theory", &theory),
+            cmd: "theory".to_string(),
+            start: body[0].start,
+            lines: body.iter().flat_map(|c| c.lines.clone()).collect(),
+            mergeable: false,
+            context: None,
+        }),
+        copied(&chunks[body_end..]),
+    ]
+}
+
+// The source of a block header like 'context Foo begin', without blank lines around it.
+fn block_header(chunk: &Chunk) -> String {
+    let lines = &chunk.lines[trim_blank_lines(&chunk.lines)];
+    lines.join("\n")
+}
+
+// A snippet to be marked, with the lines of its chunk.
+struct Region {
+    name: String,
+    cmd: String,
+    start: usize,
+    lines: Vec<String>,
+    // Only named commands are merged, so headings and the theory itself stay apart.
+    mergeable: bool,
+    // The header of the enclosing block, with -with-locale-header.
+    context: Option<String>,
+}
+
+impl Region {
+    fn body(&self) -> Range<usize> {
+        trim_blank_lines(&self.lines)
+    }
+}
+
+enum Entry {
+    Copied(Vec<String>),
+    Marked(Region),
+}
+
+// The lines of a chunk, after its first, that start with a proof command.
+fn proof_step_lines(lines: &[String], body: &Range<usize>, options: &Options) -> Vec<usize> {
+    (body.start + 1..body.end)
+        .filter(|&i| {
+            let words = make_words_with(&lines[i], &options.breakers);
+            words.first().is_some_and(|w| {
+                options.cmd_type(w) == Some(CmdType::Inner)
+                    && !["(*", "*)", "\\<comment>"].contains(&w.as_str())
+            })
+        })
+        .collect()
+}
+
+// The regions of the steps of a proof, each from one step up to the next.
+// The statement goes with the first step.
+fn proof_steps(region: Region, options: &Options) -> Vec<Region> {
+    let body = region.body();
+    let steps = proof_step_lines(&region.lines, &body, options);
+    if steps.is_empty() {
+        return vec![region];
+    }
+
+    let mut starts = vec![body.start];
+    starts.extend(&steps[1..]);
+    let mut ends = steps[1..].to_vec();
+    ends.push(body.end);
+
+    starts
+        .into_iter()
+        .zip(ends)
+        .enumerate()
+        .map(|(step, (start, end))| Region {
+            name: step_name(&region.name, step),
+            cmd: region.cmd.clone(),
+            start: region.start + start,
+            lines: region.lines[start..end].to_vec(),
+            mergeable: false,
+            context: region.context.clone(),
+        })
+        .collect()
+}
+
+// The lines of a chunk, after its first, where an equation starts. That is after a | that
+// ends a line, or at a | that starts one, outside of strings, cartouches, comments and parentheses.
+// Equations on the same line stay together.
+fn equation_lines(lines: &[String], body: &Range<usize>) -> Vec<usize> {
+    let mut nesting = Nesting::default();
+    let mut parens = 0;
+    let mut starts = vec![];
+
+    for i in body.clone() {
+        let line = &lines[i];
+        let mut bars = vec![];
+        nesting.scan(line, |j, c| match c {
+            '(' | '[' | '{' => parens += 1,
+            ')' | ']' | '}' => parens -= 1,
+            '|' if parens == 0 => bars.push(j),
+            _ => {}
+        });
+
+        let first = line.len() - line.trim_start().len();
+        let last = line.trim_end().len().saturating_sub(1);
+        if bars.contains(&first) && i > body.start {
+            starts.push(i);
+        } else if bars.contains(&last) && i + 1 < body.end {
+            starts.push(i + 1);
+        }
+    }
+
+    starts.dedup();
+    starts
+}
+
+// The regions of the equations of a function, each from one equation up to the next.
+// The header goes with the first equation.
+fn equations(region: Region) -> Vec<Region> {
+    let body = region.body();
+    let lines = equation_lines(&region.lines, &body);
+    if lines.is_empty() {
+        return vec![region];
+    }
+
+    let mut starts = vec![body.start];
+    starts.extend(&lines);
+    let mut ends = lines;
+    ends.push(body.end);
+
+    starts
+        .into_iter()
+        .zip(ends)
+        .enumerate()
+        .map(|(equation, (start, end))| Region {
+            name: equation_name(&region.name, equation),
+            cmd: region.cmd.clone(),
+            start: region.start + start,
+            lines: region.lines[start..end].to_vec(),
+            mergeable: false,
+            context: region.context.clone(),
+        })
+        .collect()
+}
+
+// A run of regions becomes one region named after the first and the length of the run.
+// The regions of a run come from consecutive chunks, so their lines are contiguous.
+fn merge_run(run: &mut Vec<Region>, entries: &mut Vec<Entry>) {
+    if run.len() > 1 {
+        let name = format!("{}-merged-{}", run[0].name, run.len());
+        let lines = run.iter().flat_map(|r| r.lines.iter().cloned()).collect();
+        entries.push(Entry::Marked(Region {
+            name,
+            cmd: run[0].cmd.clone(),
+            start: run[0].start,
+            lines,
+            mergeable: true,
+            context: run[0].context.clone(),
+        }));
+        run.clear();
+    }
+    entries.extend(run.drain(..).map(Entry::Marked));
+}
+
+fn merge_adjacent(entries: Vec<Entry>, same_command: bool) -> Vec<Entry> {
+    let mut merged = vec![];
+    let mut run: Vec<Region> = vec![];
+
+    for entry in entries {
+        let region = match entry {
+            Entry::Marked(region) if region.mergeable && region.body().len() == 1 => region,
+            entry => {
+                merge_run(&mut run, &mut merged);
+                merged.push(entry);
+                continue;
+            }
+        };
+        if same_command && run.first().is_some_and(|first| first.cmd != region.cmd) {
+            merge_run(&mut run, &mut merged);
+        }
+        run.push(region);
+    }
+    merge_run(&mut run, &mut merged);
+
+    merged
+}
+
+/// Whether a part holds nothing but blank lines and LaTeX comments.
+pub fn is_empty_part(part: &[String]) -> bool {
+    part.iter()
+        .all(|line| line.trim().chars().all(|c| c == '%'))
+}
+
+/// Replaces the names of the snippets by `snip-0001`, `snip-0002` and so on, in the given order.
+///
+/// The parts of a snippet share its name, and the steps of a proof get names of their own.
+/// The old names, with prefix, are kept in [`Snippet::original_name`].
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::theory::{anonymize, extract_from_source, Options};
+///
+/// let thy = "theory Foo imports Main begin\n\nlemma secret: \"True\" by simp\n\nend";
+/// let mut snippets = extract_from_source(thy, &Options::default());
+/// anonymize(&mut snippets);
+///
+/// let names: Vec<_> = snippets.iter().map(|s| s.name.as_str()).collect();
+/// assert_eq!(names, ["snip-0001", "snip-0002", "snip-0003"]);
+/// assert_eq!(snippets[1].original_name.as_deref(), Some("lemma:secret"));
+/// ```
+pub fn anonymize(snippets: &mut [Snippet]) {
+    for (i, snippet) in snippets.iter_mut().enumerate() {
+        let original = match snippet.prefix.take() {
+            Some(prefix) => format!("{}:{}", prefix, snippet.name),
+            None => snippet.name.clone(),
+        };
+        snippet.original_name = Some(original);
+        snippet.name = format!("snip-{:04}", i + 1);
+    }
+}
+
+/// Reads the snippets of an annotated theory, where every source line becomes a part.
+///
+/// The markers are expected to be balanced, as produced by [`annotate_theory`].
+pub fn extract_raw(theory: &str, lines: &[String], prefix: &Option<String>) -> Vec<Snippet> {
+    let mut snippets = vec![];
+    let mut current: Option<Snippet> = None;
+
+    for line in lines {
+        if is_raw_marker(line, BEGIN) {
+            current = Some(Snippet::from_marker(line, theory, prefix.clone()));
+            continue;
+        }
+
+        let snippet = match current.as_mut() {
+            Some(snippet) => snippet,
+            None => continue,
+        };
+
+        if is_raw_marker(line, END) {
+            while snippet.parts.len() > 1 && is_empty_part(snippet.parts.last().unwrap()) {
+                snippet.parts.pop();
+            }
+            snippets.extend(current.take());
+            continue;
+        }
+
+        snippet.parts.push(vec![line.clone()]);
+    }
+
+    snippets
+}
+
+/// Annotates a theory and reads its snippets back, all in memory.
+///
+/// This gives the snippets of the source, as the tool does with `-raw-source`.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::theory::{extract_from_source, Options};
+///
+/// let thy = "theory Foo imports Main begin\n\ndefinition \"\\<pi> \\<equiv> 3\"\n\nlemma foo: \"True\"\n  by simp\n\nend";
+/// let snippets = extract_from_source(thy, &Options::default());
+///
+/// let names: Vec<_> = snippets.iter().map(|s| s.name.as_str()).collect();
+/// assert_eq!(&names[..3], ["theory:Foo", "definition:pi", "lemma:foo"]);
+///
+/// let lemma = &snippets[2];
+/// assert_eq!(lemma.theory, "Foo");
+/// assert_eq!(lemma.parts, [["lemma foo: \"True\""], ["  by simp"]]);
+/// assert_eq!(lemma.source_lines, Some((5, 6)));
+///
+/// // The type cartouche of a definition does not mix with its body.
+/// let thy = "theory Foo imports Main begin\n\ndefinition foo :: \\<open>nat \\<Rightarrow> nat\\<close>\n  where \\<open>foo x = x + 1\\<close>\n\ndefinition \\<open>bar (x :: nat) = x\\<close>\n\nend";
+/// let names: Vec<_> = extract_from_source(thy, &Options::default())
+///     .into_iter()
+///     .map(|s| s.name)
+///     .collect();
+/// assert_eq!(names[1..3], ["definition:foo", "definition:bar"]);
+///
+/// // With locale_headers, a snippet inside a block knows the header of the block.
+/// let thy = "theory Foo imports Main begin\n\nlocale group =\n  fixes f assumes \"f x = x\"\nbegin\n\nlemma id: \"f y = y\"\n  by simp\n\nend\n\nlemma outside: \"True\" by simp\n\nend";
+/// let options = Options {
+///     locale_headers: true,
+///     ..Default::default()
+/// };
+/// let snippets = extract_from_source(thy, &options);
+/// let lemma = snippets.iter().find(|s| s.name == "lemma:id").unwrap();
+/// assert_eq!(
+///     lemma.context.as_deref(),
+///     Some("locale group =\n  fixes f assumes \"f x = x\"\nbegin")
+/// );
+/// let outside = snippets.iter().find(|s| s.name == "lemma:outside").unwrap();
+/// assert_eq!(outside.context, None);
+/// ```
+pub fn extract_from_source(thy: &str, options: &Options) -> Vec<Snippet> {
+    let annotated = annotate_theory_with(thy, options);
+    let lines: Vec<String> = annotated.text.lines().map(String::from).collect();
+
+    let words = make_words(thy);
+    let theory = match words.iter().position(|w| w == "theory") {
+        Some(i) => words.get(i + 1).cloned().unwrap_or_default(),
+        None => String::new(),
+    };
+
+    extract_raw(&theory, &lines, &None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_cmds(thy: &str) -> Vec<String> {
+        (chunk_theory(thy, &Options::default()).into_iter())
+            .map(|c| c.cmd)
+            .collect()
+    }
+
+    #[test]
+    fn command_in_type_name_does_not_split() {
+        let thy = "theory Foo imports Main begin\n\
+                   datatype ('f, 'v) term = Var 'v | Fun 'f \"('f, 'v) term list\"\n\
+                   end";
+        assert_eq!(chunk_cmds(thy), ["theory", "datatype", "end"]);
+    }
+
+    #[test]
+    fn command_as_lemma_name_does_not_split() {
+        let thy = "theory Foo imports Main begin\nlemma term: \"True\"\n  by simp\nend";
+        let chunks = chunk_theory(thy, &Options::default());
+        assert_eq!(chunks[1].cmd, "lemma");
+        assert_eq!(chunks[1].lines, ["lemma term: \"True\"", "  by simp"]);
+        assert_eq!(chunks.len(), 3);
+    }
+
+    #[test]
+    fn command_after_string_splits() {
+        let thy = "theory Foo imports Main begin\n\
+                   definition a where \"a = 1\" lemma term: \"a = 1\"\n\
+                   end";
+        assert_eq!(chunk_cmds(thy), ["theory", "definition", "lemma", "end"]);
+    }
+
+    #[test]
+    fn begin_in_comment_or_string_opens_no_block() {
+        let thy = "theory Foo imports Main begin\n\
+                   lemma foo: \"True\" (* we begin here *)\n  by simp\n\
+                   lemma bar: \"begin = begin\"\n  by simp\n\
+                   lemma baz: \\<open>True \\<comment> \\<open>begin\\<close>\\<close>\n  by simp\n\
+                   end";
+        let chunks = chunk_theory(thy, &Options::default());
+        let end = chunks.last().unwrap();
+        assert_eq!(end.cmd, "end");
+        assert!(!end.block_end);
+        assert!(chunks.iter().all(|c| c.block.is_none()));
+    }
+
+    #[test]
+    fn begin_opens_block() {
+        let thy = "theory Foo imports Main begin\n\
+                   context Bar begin (* a comment *)\n\
+                   lemma foo: \"True\" by simp\n\
+                   end\n\
+                   end";
+        let chunks = chunk_theory(thy, &Options::default());
+        assert_eq!(chunks[2].block, Some(1));
+        assert!(chunks[3].block_end);
+        assert!(!chunks[4].block_end);
+    }
+
+    fn name(line: &str) -> Option<String> {
+        let words = make_words(line);
+        chunk_name(&words[0], &words, &None, &None)
+    }
+
+    #[test]
+    fn locale_is_named_by_itself() {
+        assert_eq!(name("locale Foo"), Some("locale:Foo".to_string()));
+        assert_eq!(name("locale Foo = Bar"), Some("locale:Foo".to_string()));
+        assert_eq!(
+            name("locale Foo = Bar + fixes x"),
+            Some("locale:Foo".to_string())
+        );
+    }
+
+    #[test]
+    fn context_is_named_like_locale() {
+        assert_eq!(name("context Foo begin"), Some("context:Foo".to_string()));
+        assert_eq!(name("context begin"), None);
+
+        let thy = "theory Foo imports Main begin\ncontext begin\nend\nend";
+        assert!(annotate_theory(thy).unnamed.is_empty());
+    }
+
+    fn source_names(thy: &str, options: &Options) -> Vec<String> {
+        (extract_from_source(thy, options).into_iter())
+            .filter(|s| s.cmd != "theory" && s.cmd != "end")
+            .map(|s| s.name)
+            .collect()
+    }
+
+    const DEFINITIONS: &str = "theory Foo imports Main begin\n\
+                               definition a where \"a = 1\"\n\
+                               definition b where \"b = 2\"\n\
+                               abbreviation c where \"c \\<equiv> 3\"\n\
+                               definition d where \"d = 4\"\n\
+                               lemma e: \"True\"\n  by simp\n\
+                               end";
+
+    #[test]
+    fn merge_adjacent_merges_single_lines() {
+        let options = Options {
+            merge_adjacent: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            source_names(DEFINITIONS, &options),
+            ["definition:a-merged-4", "lemma:e"]
+        );
+    }
+
+    #[test]
+    fn merge_same_command_merges_runs_of_a_command() {
+        let options = Options {
+            merge_adjacent: true,
+            merge_same_command: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            source_names(DEFINITIONS, &options),
+            [
+                "definition:a-merged-2",
+                "abbreviation:c",
+                "definition:d",
+                "lemma:e"
+            ]
+        );
+    }
+
+    #[test]
+    fn marker_names_survive_escaping() {
+        let name = "lemma:a_{b}\\c d%π";
+        let marker = begin_marker(name, "lemma", (3, 4), None);
+        let inner = &marker["text_raw ".len() + OPEN.len()..marker.len() - CLOSE.len()];
+        let verbatim = |c: char| c.is_ascii_alphanumeric() || ":.-'+ ".contains(c);
+        assert!(inner.chars().all(verbatim), "{}", inner);
+
+        let snippet = Snippet::from_marker(inner, "Foo", None);
+        assert_eq!(snippet.name, name);
+        assert_eq!(snippet.cmd, "lemma");
+        assert_eq!(snippet.source_lines, Some((3, 4)));
+    }
+
+    #[test]
+    fn only_commands_selects_the_snippets() {
+        let options = Options {
+            only_commands: vec!["definition".to_string(), "lemma".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            source_names(DEFINITIONS, &options),
+            ["definition:a", "definition:b", "definition:d", "lemma:e"]
+        );
+    }
+
+    #[test]
+    fn skip_commands_leaves_out_the_snippets() {
+        let options = Options {
+            skip_commands: vec!["definition".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            source_names(DEFINITIONS, &options),
+            ["abbreviation:c", "lemma:e"]
+        );
+
+        // The skipped commands are copied as they are.
+        let text = annotate_theory_with(DEFINITIONS, &options).text;
+        let lines: Vec<&str> = text.lines().collect();
+        let a = lines
+            .iter()
+            .position(|l| l.starts_with("definition a"))
+            .unwrap();
+        assert!(!lines[a - 1].contains(BEGIN));
+        assert!(!lines[a + 1].contains(END));
+    }
+
+    #[test]
+    fn and_separates_theorem_names() {
+        assert_eq!(
+            name("lemma a and b: \"True\" and \"True\""),
+            Some("lemma:a-and-b".to_string())
+        );
+        assert_eq!(
+            name("lemma a and b and c [simp]: \"True\""),
+            Some("lemma:a-and-b-and-c".to_string())
+        );
+    }
+
+    #[test]
+    fn and_in_the_statement_ends_the_name() {
+        assert_eq!(
+            name("theorem foo: fixes x and y assumes \"x = y\" shows \"y = x\""),
+            Some("theorem:foo".to_string())
+        );
+        assert_eq!(
+            name("lemma foo fixes x and y shows \"x = x\""),
+            Some("lemma:foo".to_string())
+        );
+    }
+
+    #[test]
+    fn interior_blank_lines_are_kept() {
+        let thy = "theory Foo imports Main begin\n\n\
+                   lemma foo:\n  \"True\"\n\n  by simp\n\n\n\
+                   lemma bar: \"True\"\n  by simp\n\
+                   end";
+        let snippets = extract_from_source(thy, &Options::default());
+        let foo = snippets.iter().find(|s| s.name == "lemma:foo").unwrap();
+        let lines: Vec<&str> = foo.parts.iter().flatten().map(String::as_str).collect();
+        assert_eq!(lines, ["lemma foo:", "  \"True\"", "", "  by simp"]);
+        assert_eq!(foo.source_lines, Some((3, 6)));
+    }
+
+    #[test]
+    fn commands_table_changes_the_chunks() {
+        let table = "# My commands\n[commands]\nmy_lemma = \"OuterNamed\"\nlemma = \"Inner\"\n";
+        let options = Options {
+            commands: crate::commands::parse_commands(table).unwrap(),
+            ..Default::default()
+        };
+        let thy = "theory Foo imports Main begin\n\
+                   my_lemma foo: \"True\"\n  by simp\n\
+                   lemma bar: \"True\"\n  by simp\n\
+                   end";
+        assert_eq!(chunk_cmds(thy), ["theory", "lemma", "end"]);
+        let cmds: Vec<String> = (chunk_theory(thy, &options).into_iter())
+            .map(|c| c.cmd)
+            .collect();
+        assert_eq!(cmds, ["theory", "my_lemma", "end"]);
+        assert_eq!(source_names(thy, &options), ["my-lemma:foo"]);
+    }
+
+    #[test]
+    fn commands_table_errors() {
+        use crate::commands::parse_commands;
+        assert_eq!(
+            parse_commands("a = \"Outer\"\nb \"Inner\""),
+            Err("line 2: expected command = \"Type\"".to_string())
+        );
+        assert!(parse_commands("a = \"Named\"")
+            .unwrap_err()
+            .contains("unknown command type"));
+        assert!(parse_commands(" = \"Inner\"").is_err());
+    }
+
+    #[test]
+    fn snippets_have_their_source_lines() {
+        let lines = |thy: &str| -> Vec<(String, Option<(usize, usize)>)> {
+            (extract_from_source(thy, &Options::default()).into_iter())
+                .map(|s| (s.cmd, s.source_lines))
+                .collect()
+        };
+        let thy = "theory Foo imports Main begin\n\n\
+                   lemma foo: \"True\"\n  by simp\n\n\
+                   definition bar where\n  \"bar = (1::nat)\"\n\n\
+                   end";
+        assert_eq!(
+            lines(thy),
+            [
+                ("theory".to_string(), Some((1, 1))),
+                ("lemma".to_string(), Some((3, 4))),
+                ("definition".to_string(), Some((6, 7))),
+                ("end".to_string(), Some((9, 9))),
+            ]
+        );
+    }
+
+    #[test]
+    fn strip_comments_keeps_strings_and_cartouches() {
+        let strip = |s: &str| {
+            let lines: Vec<String> = s.lines().map(String::from).collect();
+            strip_comments(&lines)
+        };
+        assert_eq!(
+            strip("lemma foo: \"(* x *) = y\" (* a (* nested *) comment *)\n  by simp"),
+            ["lemma foo: \"(* x *) = y\"", "  by simp"]
+        );
+        assert_eq!(
+            strip("(* only a comment *)\nlemma \\<open>(* kept *)\\<close>"),
+            ["lemma \\<open>(* kept *)\\<close>"]
+        );
+        assert_eq!(
+            strip("by simp \\<comment> \\<open>a \\<open>b\\<close>\\<close>\nby auto -- \"why\""),
+            ["by simp", "by auto"]
+        );
+    }
+
+    const HEADER: &str = "theory Foo imports Main begin\n\n";
+
+    fn annotate(body: &str) -> AnnotatedTheory {
+        annotate_theory(&format!("{}{}\n\nend", HEADER, body))
+    }
+
+    #[test]
+    fn lines_in_a_cartouche_are_text() {
+        let annotated = annotate("text \\<open>We now\nlemma this\\<close>");
+        assert!(annotated.text.contains("DefineSnippet text:"));
+        assert!(!annotated.text.contains("DefineSnippet lemma:"));
+    }
+
+    #[test]
+    fn commands_after_comments_and_on_one_line() {
+        let annotated = annotate(
+            "(* note *) lemma foo: \"True\" by simp\ndefinition \"a = 1\" definition \"b = 2\"",
+        );
+        assert!(annotated.text.contains("DefineSnippet lemma:foo lemma 3 3"));
+        assert!(annotated
+            .text
+            .contains("DefineSnippet definition:a definition 4 4"));
+        assert!(annotated
+            .text
+            .contains("DefineSnippet definition:b definition 4 4"));
+    }
+
+    #[test]
+    fn unclosed_cartouche_gives_a_hash() {
+        let annotated = annotate("definition \\<open>f x = x");
+        assert!(annotated.text.contains("DefineSnippet definition:"));
+        assert!(!annotated.text.contains("definition:f"));
+        assert_eq!(annotated.unnamed, [(3, "definition".to_string())]);
+    }
+
+    #[test]
+    fn sanitized_names_are_made_unique() {
+        let annotated = annotate("lemma f_x: \"True\" by simp\n\nlemma f-x: \"True\" by simp");
+        assert!(annotated.text.contains("DefineSnippet lemma:f-x lemma 3 3"));
+        assert!(annotated
+            .text
+            .contains("DefineSnippet lemma:f-x-2 lemma 5 5"));
+        assert_eq!(
+            annotated.renamed,
+            [(5, "lemma:f-x".to_string(), "lemma:f-x-2".to_string())]
+        );
+    }
+
+    #[test]
+    fn lines_without_a_command_are_kept() {
+        let thy = format!(
+            "{}lemma foo: \"True\" by simp\n(* trailing *)\n(* comments *)\n\nend\n(* after the end *)",
+            HEADER
+        );
+        let annotated = annotate_theory(&thy);
+        assert!(annotated.text.contains("(* trailing *)\n(* comments *)"));
+        assert!(annotated.text.contains("(* after the end *)"));
+        assert_eq!(
+            annotate_theory("(* no command *)\n").text,
+            "(* no command *)"
+        );
+    }
+
+    #[test]
+    fn identical_unnamed_chunks_are_numbered() {
+        let thy = "lemma \"True\" by simp\n\nlemma \"True\" by simp\n\nlemma \"True\" by simp";
+        let annotated = annotate(thy);
+        let names: Vec<_> = (annotated.text.lines())
+            .filter(|line| line.contains("DefineSnippet lemma:"))
+            .map(|line| line.split_whitespace().nth(2).unwrap())
+            .collect();
+        let hash = names[0];
+        assert_eq!(
+            names,
+            [
+                hash.to_string(),
+                format!("{}-1", hash),
+                format!("{}-2", hash)
+            ]
+        );
+        assert_eq!(annotate(thy), annotated);
+    }
+}
@@ -0,0 +1,338 @@
+use crate::symbols::get_name;
+
+/// The delimiter reported for the start of a cartouche or a double-quoted string.
+pub const OPEN: &str = "\\<open>";
+/// The delimiter reported for the end of a cartouche or a double-quoted string.
+pub const CLOSE: &str = "\\<close>";
+
+/// Splits Isabelle source into words.
+///
+/// The tokenizer is ad hoc and works on the ASCII encoding of symbols:
+///
+/// - Words are separated by whitespace, and each of `[`, `]`, `(`, `)`, `:` and `=`
+///   is a word on its own.
+/// - Double quotes and the cartouche symbols `\<open>` and `\<close>` all become
+///   [`OPEN`] and [`CLOSE`], so strings and cartouches look the same.
+///   Their contents are split into words like everything else.
+/// - Any other symbol `\<name>` starts a new word with its name, which the following
+///   characters continue, so `\<pi>` becomes `pi` and `x\<^sub>1` becomes `x` and `^sub1`.
+/// - Symbol names consist of letters, digits, `^`, `_` and `'`. A symbol that is not
+///   closed by `>` before any other character, such as a line break or the end of the
+///   input, ends there and is read as its name, since Isabelle symbols never span lines.
+///   A backslash that does not start a symbol only separates words.
+/// - Unicode symbols from [`crate::symbols::SYMBOLS`] are read as their ASCII name.
+///   Letters like `π` and control symbols like `⇩` behave like `\<pi>` and `\<^sub>`,
+///   `‹` and `›` become [`OPEN`] and [`CLOSE`], and operators like `⟹` are words on their
+///   own, so they never merge with neighbouring identifiers.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::{make_words, CLOSE, OPEN};
+///
+/// let words = make_words("lemma foo [simp]: \"\\<pi> = x\"");
+/// assert_eq!(
+///     words,
+///     vec!["lemma", "foo", "[", "simp", "]", ":", OPEN, "pi", "=", "x", CLOSE]
+/// );
+/// ```
+pub fn make_words(s: &str) -> Vec<String> {
+    make_words_with(s, &[])
+}
+
+/// Like [`make_words`], but every character in `breakers` is also a word on its own.
+///
+/// This covers symbols that are missing from the symbol table.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::{make_words, make_words_with};
+///
+/// assert_eq!(make_words("a⊎b"), vec!["a⊎b"]);
+/// assert_eq!(make_words_with("a⊎b", &['⊎']), vec!["a", "⊎", "b"]);
+/// ```
+pub fn make_words_with(s: &str, breakers: &[char]) -> Vec<String> {
+    let mut words: Vec<String> = vec![];
+    let mut current_word = String::new();
+
+    // After a backslash, and after \< of a symbol whose name is being read.
+    let mut pushing_symbol = false;
+    let mut symbol_started = false;
+    let mut current_symbol = String::new();
+
+    let mut inside_dquote = false;
+
+    fn breaker(c: char) -> bool {
+        ['[', ']', '(', ')', ':', '=', '\\'].contains(&c)
+    }
+
+    fn symbol_char(c: char) -> bool {
+        c.is_alphanumeric() || c == '^' || c == '_' || c == '\''
+    }
+
+    fn end_symbol(symbol: &str, current_word: &mut String, words: &mut Vec<String>) {
+        if (symbol == "open" || symbol == "close") && !current_word.is_empty() {
+            words.push(current_word.clone());
+            current_word.clear();
+        }
+
+        if symbol == "open" {
+            words.push(OPEN.to_owned());
+        } else if symbol == "close" {
+            words.push(CLOSE.to_owned());
+        } else {
+            current_word.push_str(symbol);
+        }
+    }
+
+    for c in s.chars() {
+        if pushing_symbol {
+            if !symbol_started && c == '<' {
+                symbol_started = true;
+                continue;
+            }
+            if symbol_started && symbol_char(c) {
+                current_symbol.push(c);
+                continue;
+            }
+
+            // The symbol ends here, and anything but its closing > is read as usual.
+            end_symbol(&current_symbol, &mut current_word, &mut words);
+            current_symbol.clear();
+            pushing_symbol = false;
+            let closed = symbol_started && c == '>';
+            symbol_started = false;
+            if closed {
+                continue;
+            }
+        }
+
+        if c == '"' {
+            if !current_word.is_empty() {
+                words.push(current_word.clone());
+                current_word.clear();
+            }
+
+            if inside_dquote {
+                words.push(CLOSE.to_owned());
+            } else {
+                words.push(OPEN.to_owned());
+            }
+
+            inside_dquote = !inside_dquote;
+            continue;
+        }
+
+        if let Some(name) = get_name(c).or_else(|| breakers.contains(&c).then_some("")) {
+            if !current_word.is_empty() {
+                words.push(current_word.clone());
+                current_word.clear();
+            }
+
+            if name == "open" {
+                words.push(OPEN.to_owned());
+            } else if name == "close" {
+                words.push(CLOSE.to_owned());
+            } else if name.is_empty() {
+                words.push(c.to_string());
+            } else if c.is_alphabetic() || name.starts_with('^') {
+                current_word.push_str(name);
+            } else {
+                words.push(name.to_owned());
+            }
+            continue;
+        }
+
+        if (c.is_whitespace() || breaker(c)) && !current_word.is_empty() {
+            words.push(current_word.clone());
+            current_word.clear();
+        }
+
+        if breaker(c) {
+            if c == '\\' {
+                pushing_symbol = true;
+            } else {
+                words.push(c.to_string());
+            }
+        } else if !c.is_whitespace() {
+            current_word.push(c);
+        }
+    }
+
+    if pushing_symbol {
+        end_symbol(&current_symbol, &mut current_word, &mut words);
+    }
+
+    if !current_word.is_empty() {
+        words.push(current_word);
+    }
+
+    words
+}
+
+/// Lines shorter than this are never wrapped, see [`wrap_line`].
+pub const MIN_LINE_LENGTH: usize = 20;
+
+/// Breaks a line of source into lines of at most `max` characters where it can.
+///
+/// Lines are only broken at whitespace outside of cartouches, so a symbol like `\<forall>`
+/// and a cartouche are never split, and a line may stay longer than `max` if there is
+/// no such place. Continuation lines keep the indentation of the line, plus two spaces,
+/// and elsewhere the whitespace between words is kept as it is.
+/// With `max` below [`MIN_LINE_LENGTH`] the line is kept as it is.
+///
+/// # Examples
+///
+/// ```
+/// use isasnips::wrap_line;
+///
+/// let line = "  shows \"\\<forall>x\\<in>A. \\<exists>y\\<in>B. f x \\<le> g y \\<and> g y \\<noteq> \\<bottom>\" using \\<open>A \\<subseteq> B\\<close>";
+/// let lines = wrap_line(line, 30);
+/// assert_eq!(
+///     lines,
+///     [
+///         "  shows \"\\<forall>x\\<in>A.",
+///         "    \\<exists>y\\<in>B. f x",
+///         "    \\<le> g y \\<and> g y",
+///         "    \\<noteq> \\<bottom>\" using",
+///         "    \\<open>A \\<subseteq> B\\<close>",
+///     ]
+/// );
+/// // No symbol is split, so the symbols of the lines are those of the line.
+/// let symbols = |s: &str| s.matches("\\<").count();
+/// assert_eq!(lines.iter().map(|l| symbols(l)).sum::<usize>(), symbols(line));
+/// assert!(lines.iter().all(|l| l.matches("\\<").count() == l.matches('>').count()));
+///
+/// assert_eq!(wrap_line(line, 10), [line]);
+/// ```
+pub fn wrap_line(line: &str, max: usize) -> Vec<String> {
+    if max < MIN_LINE_LENGTH || line.chars().count() <= max {
+        return vec![line.to_string()];
+    }
+
+    let content = line.trim_start();
+    let indent = &line[..line.len() - content.len()];
+
+    // The units between places where the line may be broken, with the whitespace before them,
+    // which is kept where the line is not broken, e.g. to align the parts of a definition.
+    let mut units: Vec<(String, String)> = vec![];
+    let mut space = String::new();
+    let mut unit = String::new();
+    let mut cartouches = 0usize;
+    let mut i = 0;
+    while i < content.len() {
+        let rest = &content[i..];
+        let delimiter = ["\\<open>", "‹", "\\<close>", "›"]
+            .iter()
+            .position(|d| rest.starts_with(d));
+        let len = match delimiter {
+            Some(d) => {
+                if d < 2 {
+                    cartouches += 1;
+                } else {
+                    cartouches = cartouches.saturating_sub(1);
+                }
+                ["\\<open>", "‹", "\\<close>", "›"][d].len()
+            }
+            None => rest.chars().next().map_or(1, char::len_utf8),
+        };
+
+        if rest.starts_with(char::is_whitespace) && cartouches == 0 {
+            if !unit.is_empty() {
+                units.push((std::mem::take(&mut space), std::mem::take(&mut unit)));
+            }
+            space.push_str(&rest[..len]);
+        } else {
+            unit.push_str(&rest[..len]);
+        }
+        i += len;
+    }
+    if !unit.is_empty() {
+        units.push((space, unit));
+    }
+
+    let continuation = format!("{}  ", indent);
+    let mut lines = vec![];
+    let mut current = indent.to_string();
+    for (space, unit) in units {
+        let start = if lines.is_empty() {
+            indent.len()
+        } else {
+            continuation.len()
+        };
+        if current.len() > start {
+            if current.chars().count() + space.chars().count() + unit.chars().count() > max {
+                lines.push(std::mem::replace(&mut current, continuation.clone()));
+            } else {
+                current.push_str(&space);
+            }
+        }
+        current.push_str(&unit);
+    }
+    lines.push(current);
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_line_keeps_aligned_whitespace() {
+        let line = "  \"f x    = g   x\"  |  \"f (Suc n) = h n\"  |  \"f 0 = 0\"";
+        assert_eq!(
+            wrap_line(line, 40),
+            [
+                "  \"f x    = g   x\"  |  \"f (Suc n) = h n\"",
+                "    |  \"f 0 = 0\""
+            ]
+        );
+    }
+
+    #[test]
+    fn wrap_line_keeps_short_lines() {
+        let line = "lemma   a:  \"True\"";
+        assert_eq!(wrap_line(line, 30), [line]);
+        assert_eq!(wrap_line(line, MIN_LINE_LENGTH - 1), [line]);
+    }
+
+    #[test]
+    fn control_symbols_continue_the_word() {
+        let words = make_words("definition \\<open>x\\<^sub>1 \\<equiv> 3\\<close>");
+        assert_eq!(
+            words,
+            ["definition", OPEN, "x", "^sub1", "equiv", "3", CLOSE]
+        );
+    }
+
+    #[test]
+    fn unicode_symbols_are_read_as_their_names() {
+        let words = make_words("lemma ‹∀x. P x⟹Q x›");
+        assert_eq!(
+            words,
+            [
+                "lemma",
+                OPEN,
+                "forall",
+                "x.",
+                "P",
+                "x",
+                "Longrightarrow",
+                "Q",
+                "x",
+                CLOSE
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_symbols_end_at_the_line() {
+        let words = make_words("lemma x\\<al\nshows \"a > b\"");
+        assert_eq!(
+            words,
+            ["lemma", "x", "al", "shows", OPEN, "a", ">", "b", CLOSE]
+        );
+        assert_eq!(make_words("x \\<alpha"), ["x", "alpha"]);
+    }
+}